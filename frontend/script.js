@@ -59,6 +59,14 @@ document.addEventListener('DOMContentLoaded', () => {
         }
     }
 
+    // URL of the image currently shown, optionally a server-side rendition of it
+    function currentImageUrl(variant) {
+        const path = state.lastImprovedImageId
+            ? `${API_BASE_URL}/improved/${state.lastImprovedImageId}`
+            : `${API_BASE_URL}/regenerated/${state.regeneratedImageId}`;
+        return variant ? `${path}?variant=${variant}` : path;
+    }
+
     function restoreUIFromState() {
         if (state.uploadedFilesInfo.length > 0) {
             analysisSection.style.display = 'block';
@@ -75,7 +83,7 @@ document.addEventListener('DOMContentLoaded', () => {
         }
         if (state.regeneratedImageId) {
             improvementSection.style.display = 'block';
-            regeneratedImage.src = state.regeneratedImageData;
+            regeneratedImage.src = currentImageUrl('preview');
             regeneratedImage.style.display = 'block';
             improveButton.disabled = false;
             saveImageButton.disabled = false;
@@ -214,7 +222,7 @@ document.addEventListener('DOMContentLoaded', () => {
             state.lastImprovedImageId = null;
             saveState();
 
-            regeneratedImage.src = state.regeneratedImageData;
+            regeneratedImage.src = currentImageUrl('preview');
             regeneratedImage.style.display = 'block';
             regenerationStatus.textContent = `Regeneration complete. ID: ${state.regeneratedImageId}`;
             improvementSection.style.display = 'block';
@@ -255,7 +263,7 @@ document.addEventListener('DOMContentLoaded', () => {
             state.regeneratedImageData = `data:image/png;base64,${result.data}`;
             saveState();
 
-            regeneratedImage.src = state.regeneratedImageData;
+            regeneratedImage.src = currentImageUrl('preview');
             improvementStatus.textContent = `Improvement complete. New ID: ${state.lastImprovedImageId}`;
             improvementPrompt.value = '';
         } catch (error) {
@@ -277,7 +285,7 @@ document.addEventListener('DOMContentLoaded', () => {
     });
 
     enlargeImageButton.addEventListener('click', () => {
-        enlargedImage.src = state.regeneratedImageData;
+        enlargedImage.src = currentImageUrl();
         imageModal.style.display = 'block';
     });
 
@@ -3,10 +3,17 @@ use actix_web::{HttpResponse, ResponseError};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum SketchyError {
     #[error("Redis error: {0}")]
     Redis(String),
 
+    #[error("Storage error: {0}")]
+    Storage(String),
+
+    #[error("Not found: {0}")]
+    NotFound(String),
+
     #[error("LLM service error: {0}")]
     LLM(String),
 
@@ -21,6 +28,9 @@ pub enum SketchyError {
 
     #[error("Invalid provider: {0}")]
     InvalidProvider(String),
+
+    #[error("Unsupported capability: {0}")]
+    Capability(String),
 }
 
 impl ResponseError for SketchyError {
@@ -30,6 +40,16 @@ impl ResponseError for SketchyError {
                 "error": "Database error",
                 "message": self.to_string()
             })),
+            SketchyError::Storage(_) => {
+                HttpResponse::InternalServerError().json(serde_json::json!({
+                    "error": "Storage error",
+                    "message": self.to_string()
+                }))
+            }
+            SketchyError::NotFound(_) => HttpResponse::NotFound().json(serde_json::json!({
+                "error": "Not found",
+                "message": self.to_string()
+            })),
             SketchyError::LLM(_) => HttpResponse::ServiceUnavailable().json(serde_json::json!({
                 "error": "AI service error",
                 "message": self.to_string()
@@ -56,6 +76,10 @@ impl ResponseError for SketchyError {
                     "message": self.to_string()
                 }))
             }
+            SketchyError::Capability(_) => HttpResponse::BadRequest().json(serde_json::json!({
+                "error": "Unsupported capability",
+                "message": self.to_string()
+            })),
         }
     }
 }
@@ -1,43 +1,383 @@
 // src/handlers.rs
-use crate::{AppState, errors::SketchyError, mcp::ImageGenerationProvider, models::*};
+use crate::mcp::{Capability, ImageGenerationProvider};
+use crate::services::prompt_template::{self, PromptTemplate};
+use crate::services::{
+    analysis_diff, archive,
+    image_processor::MaskSource,
+    storage::{self, Entity},
+};
+use crate::{AppState, errors::SketchyError, models::*};
 use actix_multipart::Multipart;
-use actix_web::{Error, HttpResponse, web};
-use futures_util::TryStreamExt;
+use actix_web::http::header::{
+    self, CacheControl, CacheDirective, ContentDisposition, ContentRange, ContentRangeSpec,
+    DispositionParam, DispositionType, ETag, EntityTag, Header, IfNoneMatch,
+};
+use actix_web::{Error, HttpRequest, HttpResponse, web};
+use futures_util::{StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use uuid::Uuid;
 use base64::{Engine as _, engine::general_purpose};
 
+/// Longest prompt accepted for review or for the library.
+const MAX_PROMPT_CHARS: usize = 10000;
+
 #[derive(Deserialize)]
 pub struct RegenerateImageBody {
     prompt: Option<String>,
     provider: Option<ImageGenerationProvider>,
     format: Option<String>,
     style_preset: Option<String>,
+    /// Build the prompt from these sections of the analysis instead.
+    compose: Option<PromptSections>,
+    /// Use this prompt from the library instead, and keep the result with it.
+    /// Prompts saved as templates are filled like `template`.
+    prompt_id: Option<Uuid>,
+    /// A prompt with `{placeholders}` filled from the analysis and
+    /// `variables`.
+    template: Option<String>,
+    /// Values for placeholders, over those taken from the analysis.
+    #[serde(default)]
+    variables: BTreeMap<String, String>,
 }
 
 #[derive(Serialize)]
 pub struct RegenerateImageResponse {
     pub id: Uuid,
     pub data: String, // Base64 encoded image data
+    pub similarity: Option<SimilarityReport>,
+    /// The prompt built from the analysis, if one was requested.
+    pub composed_prompt: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct BatchRegenerateBody {
+    template: Option<String>,
+    /// Use this prompt from the library as the template instead. It must
+    /// have been saved as one.
+    prompt_id: Option<Uuid>,
+    /// Values shared by every combination.
+    #[serde(default)]
+    variables: BTreeMap<String, String>,
+    /// Values to try for each variable; every combination is generated.
+    #[serde(default)]
+    matrix: BTreeMap<String, Vec<String>>,
+    provider: Option<ImageGenerationProvider>,
+    format: Option<String>,
+    style_preset: Option<String>,
+    /// Only render the prompts.
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Serialize)]
+pub struct BatchRegenerateResponse {
+    pub template: String,
+    pub results: Vec<BatchResult>,
+}
+
+/// One combination of a batch, in matrix order.
+#[derive(Serialize)]
+pub struct BatchResult {
+    /// The matrix values of this combination.
+    pub variables: BTreeMap<String, String>,
+    pub prompt: String,
+    /// The regenerated image, unless this was a dry run or it failed.
+    pub id: Option<Uuid>,
+    pub similarity: Option<SimilarityReport>,
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ComposePromptBody {
+    provider: Option<ImageGenerationProvider>,
+    #[serde(default)]
+    compose: PromptSections,
+}
+
+#[derive(Serialize)]
+pub struct ComposePromptResponse {
+    pub provider: ImageGenerationProvider,
+    pub prompt: String,
+}
+
+#[derive(Deserialize)]
+pub struct CritiquePromptBody {
+    prompt: String,
+    /// Analysis of the image the prompt is meant to recreate.
+    analysis_id: Option<Uuid>,
+    /// The generator the suggested rewrite is for.
+    target: Option<ImageGenerationProvider>,
+    /// Analysis provider giving the feedback. Defaults to `openai`.
+    provider: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RefineBody {
+    /// Prompt of the first iteration. Defaults to the analysis' prompt.
+    prompt: Option<String>,
+    provider: Option<ImageGenerationProvider>,
+    style_preset: Option<String>,
+    /// Analysis provider re-analysing the images and revising the prompt.
+    /// Defaults to `openai`.
+    llm_provider: Option<String>,
+    max_iterations: Option<u32>,
+    /// Stop once an iteration's combined score reaches this, from 0 to 1.
+    target_score: Option<f32>,
+}
+
+#[derive(Deserialize)]
+pub struct SavePromptBody {
+    /// Defaults to the prompt of `regenerated_image_id`, or else the prompt
+    /// written by the analysis.
+    prompt: Option<String>,
+    /// Keep the prompt as a template, with placeholders filled when used.
+    #[serde(default)]
+    is_template: bool,
+    title: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    analysis_id: Option<Uuid>,
+    /// A regenerated image to keep as the prompt's first result.
+    regenerated_image_id: Option<Uuid>,
+}
+
+#[derive(Deserialize)]
+pub struct UpdatePromptBody {
+    /// An empty title removes it.
+    title: Option<String>,
+    /// Replaces every tag.
+    tags: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+pub struct PromptQuery {
+    /// Comma-separated tags a prompt must all carry.
+    tags: Option<String>,
+    /// Text to look for in the prompt, title and tags.
+    q: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct AddPromptResultBody {
+    regenerated_image_id: Uuid,
 }
 
 #[derive(Deserialize)]
 pub struct ImproveImageBody {
     prompt: String,
-    // Add other parameters for image improvement if needed, e.g., strength
+    /// Defaults to Stability AI if it is configured, otherwise OpenAI.
+    provider: Option<ImageGenerationProvider>,
+    #[serde(flatten)]
+    params: ImproveParams,
+}
+
+#[derive(Deserialize)]
+pub struct InpaintBody {
+    prompt: String,
+    provider: Option<ImageGenerationProvider>,
+    /// Base64 encoded mask image.
+    mask: Option<String>,
+    /// Region of the analysis behind the image to use as the mask instead.
+    region_id: Option<String>,
+    /// Analysis to take the region from. Defaults to the one the image was
+    /// regenerated from; needed for chains started from an upload.
+    analysis_id: Option<Uuid>,
+    #[serde(flatten)]
+    params: ImproveParams,
+}
+
+#[derive(Deserialize)]
+pub struct OutpaintBody {
+    prompt: Option<String>,
+    provider: Option<ImageGenerationProvider>,
+    #[serde(flatten)]
+    extension: CanvasExtension,
+    #[serde(flatten)]
+    params: ImproveParams,
+}
+
+/// Kinds of stored image an improvement can be made from.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceKind {
+    Upload,
+    Regenerated,
+    Improved,
 }
 
 #[derive(Serialize)]
 pub struct ImproveImageResponse {
     pub id: Uuid,
     pub data: String, // Base64 encoded image data
+    pub provider: ImageGenerationProvider,
+    /// The settings used, including the seed the provider picked.
+    pub params: ImproveParams,
+}
+
+#[derive(Deserialize)]
+pub struct ImageVariantQuery {
+    variant: Option<ImageVariant>,
+}
+
+#[derive(Deserialize)]
+pub struct OverlayQuery {
+    /// Draw region outlines (default `true`).
+    regions: Option<bool>,
+    /// Draw focal point markers (default `true`).
+    focal_points: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteQuery {
+    /// Why the data is being removed, kept in the audit record.
+    reason: Option<String>,
 }
 
 pub async fn upload_images(
-    mut payload: Multipart,
+    payload: Multipart,
     data: web::Data<AppState>,
 ) -> Result<HttpResponse, Error> {
     let session_id = Uuid::new_v4();
+    let uploaded_images = store_uploaded_images(payload, session_id, &data).await?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "session_id": session_id,
+        "uploaded_images": uploaded_images,
+        "count": uploaded_images.len()
+    })))
+}
+
+pub async fn add_session_images(
+    path: web::Path<Uuid>,
+    payload: Multipart,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let session_id = path.into_inner();
+    ensure_session_exists(&session_id, &data).await?;
+
+    let uploaded_images = store_uploaded_images(payload, session_id, &data).await?;
+
+    // Keep the whole session alive alongside the new uploads
+    data.storage
+        .refresh_session_ttl(&session_id)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "session_id": session_id,
+        "uploaded_images": uploaded_images,
+        "count": uploaded_images.len()
+    })))
+}
+
+pub async fn pin_session(
+    path: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let session_id = path.into_inner();
+    ensure_session_exists(&session_id, &data).await?;
+
+    data.storage
+        .pin_session(&session_id)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "session_id": session_id,
+        "pinned": true
+    })))
+}
+
+pub async fn unpin_session(
+    path: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let session_id = path.into_inner();
+    ensure_session_exists(&session_id, &data).await?;
+
+    data.storage
+        .unpin_session(&session_id)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "session_id": session_id,
+        "pinned": false
+    })))
+}
+
+pub async fn export_session(
+    path: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let session_id = path.into_inner();
+    ensure_session_exists(&session_id, &data).await?;
+
+    let archive = archive::export_session(data.storage.as_ref(), &session_id).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/zip")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(format!(
+                "sketchy-session-{}.zip",
+                session_id
+            ))],
+        })
+        .body(archive))
+}
+
+/// Restores a session from the archive in the first file of a multipart upload.
+pub async fn import_session(
+    mut payload: Multipart,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let mut field = payload
+        .try_next()
+        .await?
+        .ok_or_else(|| SketchyError::Validation("No archive provided".to_string()))?;
+
+    let mut archive_data = Vec::new();
+    while let Some(chunk) = field.try_next().await? {
+        if (archive_data.len() + chunk.len()) as u64 > archive::MAX_IMPORT_BYTES {
+            return Err(SketchyError::Validation(format!(
+                "Archive exceeds the {} byte import limit",
+                archive::MAX_IMPORT_BYTES
+            ))
+            .into());
+        }
+        archive_data.extend_from_slice(&chunk);
+    }
+
+    let import =
+        archive::import_session(data.storage.as_ref(), &data.image_processor, archive_data).await?;
+
+    Ok(HttpResponse::Ok().json(&import))
+}
+
+async fn ensure_session_exists(session_id: &Uuid, data: &AppState) -> Result<(), Error> {
+    let exists = data
+        .storage
+        .session_exists(session_id)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    if !exists {
+        return Err(actix_web::error::ErrorNotFound(format!(
+            "Session with id '{}' not found.",
+            session_id
+        )));
+    }
+    Ok(())
+}
+
+/// Validates, resizes and stores every file in a multipart upload under the
+/// given session, returning the ids of the stored images.
+async fn store_uploaded_images(
+    mut payload: Multipart,
+    session_id: Uuid,
+    data: &AppState,
+) -> Result<Vec<Uuid>, Error> {
     let mut uploaded_images = Vec::new();
 
     while let Some(mut field) = payload.try_next().await? {
@@ -61,13 +401,13 @@ pub async fn upload_images(
         // Validate image
         data.image_processor
             .validate_image(&image_data)
-            .map_err(|e| actix_web::error::ErrorBadRequest(e))?;
+            .map_err(actix_web::error::ErrorBadRequest)?;
 
         // Resize if needed
         let processed_data = data
             .image_processor
             .resize_if_needed(&image_data, 2048)
-            .map_err(|e| actix_web::error::ErrorInternalServerError(e))?;
+            .map_err(actix_web::error::ErrorInternalServerError)?;
 
         let image_upload = ImageUpload {
             id: Uuid::new_v4(),
@@ -79,20 +419,17 @@ pub async fn upload_images(
             uploaded_at: chrono::Utc::now(),
         };
 
-        // Store in Redis
-        data.redis_service
+        // Persist the upload
+        data.storage
             .store_image(&image_upload)
             .await
-            .map_err(|e| actix_web::error::ErrorInternalServerError(e))?;
+            .map_err(actix_web::error::ErrorInternalServerError)?;
 
+        spawn_eager_variants(data, Entity::Image, image_upload.id, image_upload.data);
         uploaded_images.push(image_upload.id);
     }
 
-    Ok(HttpResponse::Ok().json(serde_json::json!({
-        "session_id": session_id,
-        "uploaded_images": uploaded_images,
-        "count": uploaded_images.len()
-    })))
+    Ok(uploaded_images)
 }
 
 pub async fn analyze_image(
@@ -106,18 +443,18 @@ pub async fn analyze_image(
         .map(|s| s.as_str())
         .unwrap_or("openai");
 
-    // Retrieve image from Redis
+    // Retrieve image from storage
     let image = data
-        .redis_service
+        .storage
         .get_image(&image_id)
         .await
-        .map_err(|e| actix_web::error::ErrorNotFound(e))?;
+        .map_err(actix_web::error::ErrorNotFound)?;
 
     // Resize image for Anthropic if needed (5MB limit)
     let image_data = if provider == "anthropic" {
         data.image_processor
             .resize_for_anthropic(&image.data)
-            .map_err(|e| actix_web::error::ErrorInternalServerError(e))?
+            .map_err(actix_web::error::ErrorInternalServerError)?
     } else {
         image.data.clone()
     };
@@ -127,15 +464,24 @@ pub async fn analyze_image(
         .llm_service
         .analyze_image(&image_data, provider)
         .await
-        .map_err(|e| actix_web::error::ErrorInternalServerError(e))?;
+        .map_err(actix_web::error::ErrorInternalServerError)?;
 
     analysis.image_id = image_id;
 
+    // Replace the LLM's colour guesses with colours measured from the upload
+    let image_processor = data.image_processor.clone();
+    let raw_analysis = analysis.raw_analysis.clone();
+    match web::block(move || image_processor.ground_colors(&image.data, raw_analysis)).await {
+        Ok(Ok(raw_analysis)) => analysis.raw_analysis = raw_analysis,
+        Ok(Err(e)) => log::warn!("Failed to measure colours of image {}: {}", image_id, e),
+        Err(e) => log::warn!("Failed to measure colours of image {}: {}", image_id, e),
+    }
+
     // Store analysis
-    data.redis_service
+    data.storage
         .store_analysis(&analysis)
         .await
-        .map_err(|e| actix_web::error::ErrorInternalServerError(e))?;
+        .map_err(actix_web::error::ErrorInternalServerError)?;
 
     Ok(HttpResponse::Ok().json(&analysis))
 }
@@ -147,144 +493,2177 @@ pub async fn get_analysis(
     let analysis_id = path.into_inner();
 
     let analysis = data
-        .redis_service
+        .storage
         .get_analysis(&analysis_id)
         .await
-        .map_err(|e| actix_web::error::ErrorNotFound(e))?;
+        .map_err(actix_web::error::ErrorNotFound)?;
 
     Ok(HttpResponse::Ok().json(&analysis))
 }
 
-pub async fn regenerate_image(
+/// Lists the regions of an analysis with their position in pixels on the
+/// analysed upload.
+pub async fn get_region_layout(
     path: web::Path<Uuid>,
     data: web::Data<AppState>,
-    body: web::Json<RegenerateImageBody>,
 ) -> Result<HttpResponse, Error> {
     let analysis_id = path.into_inner();
 
-    // Get analysis
-    let analysis = data
-        .redis_service
-        .get_analysis(&analysis_id)
-        .await
-        .map_err(|e| actix_web::error::ErrorNotFound(e))?;
+    let (analysis, image) = analysis_with_image(&data, &analysis_id).await?;
+    let layout = region_layout(&data, analysis, &image)?;
 
-    // Use custom prompt if provided, otherwise use the generated one
-    let prompt = body
-        .prompt
-        .as_deref()
-        .unwrap_or(&analysis.prompt_description);
+    Ok(HttpResponse::Ok().json(&layout))
+}
 
-    let provider = body.provider.clone().unwrap_or_default();
-    let format = body.format.as_deref().unwrap_or("raster");
-    let style_preset = body.style_preset.as_deref();
+/// Renders the upload an analysis was made from with its regions outlined
+/// and its focal points marked.
+pub async fn get_analysis_overlay(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    query: web::Query<OverlayQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let analysis_id = path.into_inner();
+    let draw_regions = query.regions.unwrap_or(true);
+    let draw_focal_points = query.focal_points.unwrap_or(true);
 
-    // Generate image
-    let mut regenerated = data
-        .llm_service
-        .generate_image(prompt, provider, format, style_preset)
-        .await
-        .map_err(|e| actix_web::error::ErrorInternalServerError(e))?;
+    let (analysis, image) = analysis_with_image(&data, &analysis_id).await?;
+    let layout = region_layout(&data, analysis, &image)?;
+    let regions: Vec<Option<PixelRect>> = if draw_regions {
+        layout.regions.iter().map(|region| region.pixels).collect()
+    } else {
+        Vec::new()
+    };
+    let focal_points = if draw_focal_points {
+        layout.focal_points
+    } else {
+        Vec::new()
+    };
 
-    regenerated.analysis_id = analysis_id;
+    let image_processor = data.image_processor.clone();
+    let overlay =
+        web::block(move || image_processor.render_overlay(&image.data, &regions, &focal_points))
+            .await??;
 
-    // Store regenerated image
-    data.redis_service
-        .store_regenerated(&regenerated)
-        .await
-        .map_err(|e| actix_web::error::ErrorInternalServerError(e))?;
+    let etag = format!(
+        "{}-overlay-{}{}",
+        analysis_id, draw_regions as u8, draw_focal_points as u8
+    );
+    Ok(image_bytes_response(&req, &etag, overlay))
+}
 
-    // Return image data
-    Ok(HttpResponse::Ok().json(RegenerateImageResponse {
-        id: regenerated.id,
-        data: general_purpose::STANDARD.encode(&regenerated.data),
-    }))
+/// Returns one region of the analysed upload as an image of its own.
+/// Regions nested in region details can be cropped too.
+pub async fn get_region_crop(
+    req: HttpRequest,
+    path: web::Path<(Uuid, String)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let (analysis_id, region_id) = path.into_inner();
+
+    let (analysis, image) = analysis_with_image(&data, &analysis_id).await?;
+    let region = find_region(&analysis, &region_id)?;
+    let crop = crop_region(&data, image, region).await?;
+
+    // Region ids come from the model, so escape them for the header
+    let etag = format!(
+        "{}-{}-crop",
+        analysis_id,
+        storage::encode_segment(&region_id)
+    );
+    Ok(image_bytes_response(&req, &etag, crop))
 }
 
-pub async fn improve_image(
-    path: web::Path<Uuid>,
+/// Analyses a crop of one region on its own and stores the result as the
+/// region's `detail`, replacing any earlier one. Works for nested regions
+/// too, so learners can keep drilling down.
+pub async fn analyze_region(
+    path: web::Path<(Uuid, String)>,
     data: web::Data<AppState>,
-    body: web::Json<ImproveImageBody>,
+    query: web::Query<std::collections::HashMap<String, String>>,
 ) -> Result<HttpResponse, Error> {
-    let regenerated_image_id = path.into_inner();
+    let (analysis_id, region_id) = path.into_inner();
+    let provider = query
+        .get("provider")
+        .map(|s| s.as_str())
+        .unwrap_or("openai");
 
-    // Retrieve the original regenerated image from Redis
-    let original_image = data
-        .redis_service
-        .get_regenerated(&regenerated_image_id)
-        .await
-        .map_err(|e| actix_web::error::ErrorNotFound(e))?;
+    let (analysis, image) = analysis_with_image(&data, &analysis_id).await?;
+    let region = find_region(&analysis, &region_id)?.clone();
+    let crop = crop_region(&data, image, &region).await?;
 
-    // Use the custom prompt for improvement
-    let prompt = body.prompt.as_str();
+    // Resize crop for Anthropic if needed (5MB limit)
+    let crop_data = if provider == "anthropic" {
+        data.image_processor
+            .resize_for_anthropic(&crop)
+            .map_err(actix_web::error::ErrorInternalServerError)?
+    } else {
+        crop.clone()
+    };
 
-    // Call the LLM service to improve the image
-    let mut improved_image = data
+    let mut sub_analysis = data
         .llm_service
-        .improve_image(&original_image.data, prompt)
+        .analyze_region(&crop_data, provider, &region)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    // Measure colours from the crop, as for whole-image analyses
+    let image_processor = data.image_processor.clone();
+    let raw_analysis = sub_analysis.raw_analysis.clone();
+    match web::block(move || image_processor.ground_colors(&crop, raw_analysis)).await {
+        Ok(Ok(raw_analysis)) => sub_analysis.raw_analysis = raw_analysis,
+        Ok(Err(e)) => log::warn!("Failed to measure colours of region {}: {}", region_id, e),
+        Err(e) => log::warn!("Failed to measure colours of region {}: {}", region_id, e),
+    }
+
+    let detail = RegionDetail {
+        llm_provider: sub_analysis.llm_provider,
+        model_used: sub_analysis.metadata.model_used,
+        prompt_description: sub_analysis.prompt_description,
+        attributes: sub_analysis.raw_analysis.global_attributes,
+        // The model saw only the crop, so its coordinates are relative to it
+        regions: sub_analysis
+            .raw_analysis
+            .regions
+            .into_iter()
+            .map(|mut part| {
+                part.coordinates = part.coordinates.within(&region.coordinates);
+                part
+            })
+            .collect(),
+        processing_time_ms: sub_analysis.metadata.processing_time_ms,
+        analyzed_at: sub_analysis.created_at,
+    };
+
+    // Re-read the analysis so details stored while the model was working
+    // aren't lost
+    let mut analysis = data
+        .storage
+        .get_analysis(&analysis_id)
         .await
-        .map_err(|e| actix_web::error::ErrorInternalServerError(e))?;
+        .map_err(actix_web::error::ErrorNotFound)?;
+    let region = analysis
+        .raw_analysis
+        .find_region_mut(&region_id)
+        .ok_or_else(|| region_not_found(&analysis_id, &region_id))?;
+    region.detail = Some(detail);
+    let region = region.clone();
 
-    improved_image.regenerated_image_id = regenerated_image_id;
+    data.storage
+        .store_analysis(&analysis)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(&region))
+}
 
-    // Store the improved image
-    data.redis_service
-        .store_improved(&improved_image)
+/// Loads an analysis and the upload it was made from.
+async fn analysis_with_image(
+    data: &AppState,
+    analysis_id: &Uuid,
+) -> Result<(ImageAnalysis, ImageUpload), Error> {
+    let analysis = data
+        .storage
+        .get_analysis(analysis_id)
+        .await
+        .map_err(actix_web::error::ErrorNotFound)?;
+    let image = data
+        .storage
+        .get_image(&analysis.image_id)
         .await
-        .map_err(|e| actix_web::error::ErrorInternalServerError(e))?;
+        .map_err(actix_web::error::ErrorNotFound)?;
+    Ok((analysis, image))
+}
 
-    // Return the improved image data
-    Ok(HttpResponse::Ok().json(ImproveImageResponse {
-        id: improved_image.id,
-        data: general_purpose::STANDARD.encode(&improved_image.data),
-    }))
+fn find_region<'a>(
+    analysis: &'a ImageAnalysis,
+    region_id: &str,
+) -> Result<&'a ImageRegion, SketchyError> {
+    analysis
+        .raw_analysis
+        .find_region(region_id)
+        .ok_or_else(|| region_not_found(&analysis.id, region_id))
 }
 
-pub async fn improve_from_improved(
+fn region_not_found(analysis_id: &Uuid, region_id: &str) -> SketchyError {
+    SketchyError::NotFound(format!(
+        "Region {} not found in analysis {}",
+        region_id, analysis_id
+    ))
+}
+
+async fn crop_region(
+    data: &AppState,
+    image: ImageUpload,
+    region: &ImageRegion,
+) -> Result<Vec<u8>, Error> {
+    let (width, height) = data.image_processor.dimensions(&image.data)?;
+    let rect = region.coordinates.to_pixels(width, height).ok_or_else(|| {
+        SketchyError::Validation(format!("Region {} covers less than a pixel", region.id))
+    })?;
+
+    let image_processor = data.image_processor.clone();
+    let crop = web::block(move || image_processor.crop_region(&image.data, &rect)).await??;
+    Ok(crop)
+}
+
+/// Converts the regions and focal points of an analysis to pixels on the
+/// upload it was made from.
+fn region_layout(
+    data: &AppState,
+    analysis: ImageAnalysis,
+    image: &ImageUpload,
+) -> Result<RegionLayout, Error> {
+    let (width, height) = data.image_processor.dimensions(&image.data)?;
+
+    let regions = analysis
+        .raw_analysis
+        .regions
+        .into_iter()
+        .enumerate()
+        .map(|(index, region)| {
+            let (r, g, b) = data.image_processor.overlay_color(index);
+            RegionPlacement {
+                pixels: region.coordinates.to_pixels(width, height),
+                region_id: region.id,
+                object_description: region.object_description,
+                importance_score: region.importance_score,
+                coordinates: region.coordinates,
+                overlay_color: format!("#{:02x}{:02x}{:02x}", r, g, b),
+            }
+        })
+        .collect();
+    let to_px = |percent: f32, size: u32| {
+        ((percent.clamp(0.0, 100.0) / 100.0 * size as f32) as u32).min(size.saturating_sub(1))
+    };
+    let focal_points = analysis
+        .raw_analysis
+        .composition
+        .focal_points
+        .iter()
+        .map(|&(x, y)| (to_px(x, width), to_px(y, height)))
+        .collect();
+
+    Ok(RegionLayout {
+        analysis_id: analysis.id,
+        image_id: image.id,
+        image_width: width,
+        image_height: height,
+        regions,
+        focal_points,
+    })
+}
+
+pub async fn regenerate_image(
     path: web::Path<Uuid>,
     data: web::Data<AppState>,
-    body: web::Json<ImproveImageBody>,
+    body: web::Json<RegenerateImageBody>,
 ) -> Result<HttpResponse, Error> {
-    let improved_image_id = path.into_inner();
+    let analysis_id = path.into_inner();
 
-    // Retrieve the previous improved image from Redis
-    let previous_image = data
-        .redis_service
-        .get_improved(&improved_image_id)
+    // Get analysis
+    let analysis = data
+        .storage
+        .get_analysis(&analysis_id)
         .await
-        .map_err(|e| actix_web::error::ErrorNotFound(e))?;
+        .map_err(actix_web::error::ErrorNotFound)?;
 
-    // Use the custom prompt for improvement
-    let prompt = body.prompt.as_str();
+    let provider = body.provider.clone().unwrap_or_default();
+    data.llm_service
+        .check_capability(&provider, Capability::TextToImage)?;
 
-    // Call the LLM service to improve the image
-    let mut new_improved_image = data
-        .llm_service
-        .improve_image(&previous_image.data, prompt)
-        .await
-        .map_err(|e| actix_web::error::ErrorInternalServerError(e))?;
+    let sources = [
+        body.prompt.is_some(),
+        body.compose.is_some(),
+        body.prompt_id.is_some(),
+        body.template.is_some(),
+    ];
+    if sources.into_iter().filter(|given| *given).count() > 1 {
+        return Err(SketchyError::Validation(
+            "Give only one of a prompt, compose options, a prompt_id or a template".to_string(),
+        )
+        .into());
+    }
+    let composed_prompt = match &body.compose {
+        Some(sections) => Some(data.llm_service.compose_prompt(
+            &analysis.raw_analysis,
+            &provider,
+            sections,
+        )?),
+        None => None,
+    };
+    let saved_prompt = match &body.prompt_id {
+        Some(prompt_id) => Some(data.storage.get_prompt(prompt_id).await?),
+        None => None,
+    };
+    let templated = templated_prompt(
+        &analysis,
+        body.template.as_deref(),
+        saved_prompt.as_ref(),
+        &body.variables,
+    )?;
 
-    // The new image still points back to the original regenerated image
-    new_improved_image.regenerated_image_id = previous_image.regenerated_image_id;
+    // Use a custom, composed, templated or saved prompt if provided,
+    // otherwise use the generated one
+    let prompt = composed_prompt
+        .as_deref()
+        .or(body.prompt.as_deref())
+        .or(templated.as_ref().map(|(prompt, _)| prompt.as_str()))
+        .or(saved_prompt.as_ref().map(|saved| saved.prompt.as_str()))
+        .unwrap_or(&analysis.prompt_description);
 
-    // Store the new improved image
-    data.redis_service
-        .store_improved(&new_improved_image)
-        .await
-        .map_err(|e| actix_web::error::ErrorInternalServerError(e))?;
+    let settings = GenerationSettings {
+        provider,
+        format: body.format.clone().unwrap_or_else(|| "raster".to_string()),
+        style_preset: body.style_preset.clone(),
+    };
+    let regenerated = regenerate(
+        &data,
+        &analysis,
+        prompt,
+        &settings,
+        templated.as_ref().map(|(_, used)| used.clone()),
+        saved_prompt.as_ref().map(|saved| &saved.id),
+    )
+    .await?;
 
-    // Return the new improved image's ID and data
-    Ok(HttpResponse::Ok().json(ImproveImageResponse {
-        id: new_improved_image.id,
-        data: general_purpose::STANDARD.encode(&new_improved_image.data),
+    // Return image data
+    Ok(HttpResponse::Ok().json(RegenerateImageResponse {
+        id: regenerated.id,
+        data: general_purpose::STANDARD.encode(&regenerated.data),
+        similarity: regenerated.similarity,
+        composed_prompt,
     }))
 }
 
-pub async fn list_sessions(_data: web::Data<AppState>) -> Result<HttpResponse, Error> {
-    // This would implement listing recent sessions
-    // For now, return a placeholder
-    Ok(HttpResponse::Ok().json(serde_json::json!({
-        "sessions": [],
-        "message": "Session listing not yet implemented"
-    })))
+/// Regenerates an image once for every combination of the matrix values,
+/// filling the rest of the template from the analysis and `variables`.
+pub async fn batch_regenerate(
+    path: web::Path<Uuid>,
+    data: web::Data<AppState>,
+    body: web::Json<BatchRegenerateBody>,
+) -> Result<HttpResponse, Error> {
+    const MAX_COMBINATIONS: usize = 16;
+    /// Generations running at once, to stay within provider rate limits.
+    const CONCURRENCY: usize = 3;
+
+    let analysis_id = path.into_inner();
+    let body = body.into_inner();
+
+    let analysis = data.storage.get_analysis(&analysis_id).await?;
+
+    let provider = body.provider.unwrap_or_default();
+    data.llm_service
+        .check_capability(&provider, Capability::TextToImage)?;
+
+    let saved_prompt = match (&body.template, &body.prompt_id) {
+        (Some(_), None) => None,
+        (None, Some(prompt_id)) => {
+            let saved = data.storage.get_prompt(prompt_id).await?;
+            if !saved.is_template {
+                return Err(SketchyError::Validation(format!(
+                    "Prompt {} wasn't saved as a template",
+                    prompt_id
+                ))
+                .into());
+            }
+            Some(saved)
+        }
+        _ => {
+            return Err(SketchyError::Validation(
+                "Give either a template or a prompt_id".to_string(),
+            )
+            .into());
+        }
+    };
+    let source = body
+        .template
+        .as_deref()
+        .or(saved_prompt.as_ref().map(|saved| saved.prompt.as_str()))
+        .unwrap_or_default();
+    let template = PromptTemplate::parse(source)?;
+
+    if let Some(name) = body
+        .matrix
+        .keys()
+        .find(|name| body.variables.contains_key(*name))
+    {
+        return Err(SketchyError::Validation(format!(
+            "'{}' is given both as a variable and in the matrix",
+            name
+        ))
+        .into());
+    }
+    check_template_variables(&template, body.variables.keys().chain(body.matrix.keys()))?;
+
+    // Render everything first, so a bad combination fails before any
+    // generation starts
+    let mut renders = Vec::new();
+    for combination in prompt_template::expand(&body.matrix, MAX_COMBINATIONS)? {
+        let mut values = body.variables.clone();
+        values.extend(combination.clone());
+        let (prompt, used) = fill_template(&analysis, &template, &values)?;
+        renders.push((combination, prompt, used));
+    }
+
+    if body.dry_run {
+        let results = renders
+            .into_iter()
+            .map(|(variables, prompt, _)| BatchResult {
+                variables,
+                prompt,
+                id: None,
+                similarity: None,
+                error: None,
+            })
+            .collect();
+        return Ok(HttpResponse::Ok().json(BatchRegenerateResponse {
+            template: source.to_string(),
+            results,
+        }));
+    }
+
+    let settings = GenerationSettings {
+        provider,
+        format: body.format.unwrap_or_else(|| "raster".to_string()),
+        style_preset: body.style_preset,
+    };
+    let saved_prompt_id = saved_prompt.as_ref().map(|saved| saved.id);
+    let results: Vec<BatchResult> = futures_util::stream::iter(renders)
+        .map(|(variables, prompt, used)| {
+            let (data, analysis, settings) = (&data, &analysis, &settings);
+            async move {
+                // One failed generation shouldn't lose the others
+                match regenerate(
+                    data,
+                    analysis,
+                    &prompt,
+                    settings,
+                    Some(used),
+                    saved_prompt_id.as_ref(),
+                )
+                .await
+                {
+                    Ok(regenerated) => BatchResult {
+                        variables,
+                        prompt,
+                        id: Some(regenerated.id),
+                        similarity: regenerated.similarity,
+                        error: None,
+                    },
+                    Err(e) => BatchResult {
+                        variables,
+                        prompt,
+                        id: None,
+                        similarity: None,
+                        error: Some(e.to_string()),
+                    },
+                }
+            }
+        })
+        .buffered(CONCURRENCY)
+        .collect()
+        .await;
+
+    Ok(HttpResponse::Ok().json(BatchRegenerateResponse {
+        template: source.to_string(),
+        results,
+    }))
+}
+
+/// How to generate an image, shared by single and batch regeneration.
+struct GenerationSettings {
+    provider: ImageGenerationProvider,
+    format: String,
+    style_preset: Option<String>,
+}
+
+/// Generates an image from `prompt`, scores it against the original upload
+/// and stores it under the analysis. A regeneration from a saved prompt is
+/// kept as one of its results.
+async fn regenerate(
+    data: &AppState,
+    analysis: &ImageAnalysis,
+    prompt: &str,
+    settings: &GenerationSettings,
+    template: Option<TemplatedPrompt>,
+    saved_prompt_id: Option<&Uuid>,
+) -> Result<RegeneratedImage, Error> {
+    // Generate image
+    let mut regenerated = data
+        .llm_service
+        .generate_image(
+            prompt,
+            settings.provider.clone(),
+            &settings.format,
+            settings.style_preset.as_deref(),
+        )
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    regenerated.analysis_id = analysis.id;
+    regenerated.template = template;
+
+    // Score against the original; a failure here shouldn't lose the image
+    if let ImageFormat::Raster { .. } = regenerated.format {
+        match similarity_report(data, analysis, &regenerated.data).await {
+            Ok(report) => regenerated.similarity = Some(report),
+            Err(e) => log::warn!(
+                "Failed to score regenerated image {}: {}",
+                regenerated.id,
+                e
+            ),
+        }
+    }
+
+    // Store regenerated image
+    data.storage
+        .store_regenerated(&regenerated)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    // SVG output can't be rasterised into variants
+    if let ImageFormat::Raster { .. } = regenerated.format {
+        spawn_eager_variants(
+            data,
+            Entity::Regenerated,
+            regenerated.id,
+            regenerated.data.clone(),
+        );
+    }
+
+    if let Some(prompt_id) = saved_prompt_id
+        && let Err(e) = keep_prompt_result(data, prompt_id, &regenerated).await
+    {
+        log::warn!(
+            "Failed to add regenerated image {} to prompt {}: {}",
+            regenerated.id,
+            prompt_id,
+            e
+        );
+    }
+
+    Ok(regenerated)
+}
+
+/// Fills `template`, or else the saved prompt if it was saved as a
+/// template, returning the prompt and how it was filled. Other saved
+/// prompts are used as written, so they can't take variables.
+fn templated_prompt(
+    analysis: &ImageAnalysis,
+    template: Option<&str>,
+    saved_prompt: Option<&SavedPrompt>,
+    variables: &BTreeMap<String, String>,
+) -> Result<Option<(String, TemplatedPrompt)>, SketchyError> {
+    let template = match (template, saved_prompt) {
+        (Some(template), _) => template,
+        (None, Some(saved)) if saved.is_template => saved.prompt.as_str(),
+        (None, saved) if !variables.is_empty() => {
+            return Err(SketchyError::Validation(match saved {
+                Some(saved) => format!(
+                    "Prompt {} wasn't saved as a template, so it takes no variables",
+                    saved.id
+                ),
+                None => "Variables need a template or a prompt_id".to_string(),
+            }));
+        }
+        (None, _) => return Ok(None),
+    };
+    let template = PromptTemplate::parse(template)?;
+    check_template_variables(&template, variables.keys())?;
+    fill_template(analysis, &template, variables).map(Some)
+}
+
+/// Rejects values for placeholders the template doesn't have, which are
+/// most likely typos.
+fn check_template_variables<'a>(
+    template: &PromptTemplate,
+    names: impl Iterator<Item = &'a String>,
+) -> Result<(), SketchyError> {
+    let placeholders = template.variables();
+    for name in names {
+        if !placeholders.contains(&name.as_str()) {
+            return Err(SketchyError::Validation(format!(
+                "The template has no {{{}}} placeholder",
+                name
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Renders a template with values from the analysis, overridden by
+/// `values`, and checks the result like any other prompt.
+fn fill_template(
+    analysis: &ImageAnalysis,
+    template: &PromptTemplate,
+    values: &BTreeMap<String, String>,
+) -> Result<(String, TemplatedPrompt), SketchyError> {
+    let mut all = prompt_template::analysis_variables(analysis);
+    all.extend(values.clone());
+
+    let prompt = validated_prompt(&template.render(&all)?)?.to_string();
+    let variables = template
+        .variables()
+        .into_iter()
+        .map(|name| (name.to_string(), all[name].clone()))
+        .collect();
+    Ok((
+        prompt,
+        TemplatedPrompt {
+            template: template.source().to_string(),
+            variables,
+        },
+    ))
+}
+
+/// Previews the prompt `regenerate_image` would compose from an analysis.
+pub async fn compose_prompt(
+    path: web::Path<Uuid>,
+    data: web::Data<AppState>,
+    body: web::Json<ComposePromptBody>,
+) -> Result<HttpResponse, Error> {
+    let analysis_id = path.into_inner();
+
+    let analysis = data
+        .storage
+        .get_analysis(&analysis_id)
+        .await
+        .map_err(actix_web::error::ErrorNotFound)?;
+
+    let body = body.into_inner();
+    let provider = body.provider.unwrap_or_default();
+    let prompt =
+        data.llm_service
+            .compose_prompt(&analysis.raw_analysis, &provider, &body.compose)?;
+
+    Ok(HttpResponse::Ok().json(ComposePromptResponse { provider, prompt }))
+}
+
+/// Asks a model for feedback on a hand-written prompt, checked against the
+/// analysis of the image it should recreate when one is given and tailored
+/// to the target image provider.
+pub async fn critique_prompt(
+    data: web::Data<AppState>,
+    body: web::Json<CritiquePromptBody>,
+) -> Result<HttpResponse, Error> {
+    let body = body.into_inner();
+    let prompt = validated_prompt(&body.prompt)?;
+
+    let analysis = match body.analysis_id {
+        Some(analysis_id) => Some(data.storage.get_analysis(&analysis_id).await?),
+        None => None,
+    };
+    let provider = body.provider.as_deref().unwrap_or("openai");
+
+    let critique = data
+        .llm_service
+        .critique_prompt(prompt, analysis.as_ref(), body.target.as_ref(), provider)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(critique))
+}
+
+/// Saves a prompt to the library, with links to where it came from.
+pub async fn save_prompt(
+    data: web::Data<AppState>,
+    body: web::Json<SavePromptBody>,
+) -> Result<HttpResponse, Error> {
+    let body = body.into_inner();
+    let tags = SavedPrompt::normalize_tags(body.tags)?;
+    let title = validated_title(body.title)?;
+
+    let regenerated = match &body.regenerated_image_id {
+        Some(regenerated_id) => Some(data.storage.get_regenerated(regenerated_id).await?),
+        None => None,
+    };
+    let analysis_id = match (body.analysis_id, &regenerated) {
+        (Some(analysis_id), Some(regenerated)) if analysis_id != regenerated.analysis_id => {
+            return Err(SketchyError::Validation(format!(
+                "Regenerated image {} was not made from analysis {}",
+                regenerated.id, analysis_id
+            ))
+            .into());
+        }
+        (Some(analysis_id), _) => Some(analysis_id),
+        (None, regenerated) => regenerated.as_ref().map(|r| r.analysis_id),
+    };
+    let analysis = match &analysis_id {
+        Some(analysis_id) => Some(data.storage.get_analysis(analysis_id).await?),
+        None => None,
+    };
+
+    let prompt = match (&body.prompt, &regenerated, &analysis) {
+        (Some(prompt), _, _) => prompt.as_str(),
+        (None, Some(regenerated), _) => regenerated.prompt_used.as_str(),
+        (None, None, Some(analysis)) => analysis.prompt_description.as_str(),
+        (None, None, None) => {
+            return Err(SketchyError::Validation(
+                "Give a prompt, an analysis_id or a regenerated_image_id".to_string(),
+            )
+            .into());
+        }
+    };
+    let prompt = validated_prompt(prompt)?;
+    if body.is_template {
+        PromptTemplate::parse(prompt)?;
+    }
+    let thumbnail = match &regenerated {
+        Some(regenerated) => Some(prompt_thumbnail(&data, regenerated).await?),
+        None => None,
+    };
+
+    let now = chrono::Utc::now();
+    let saved = SavedPrompt {
+        id: Uuid::new_v4(),
+        prompt: prompt.to_string(),
+        is_template: body.is_template,
+        title,
+        tags,
+        analysis_id,
+        image_id: analysis.as_ref().map(|analysis| analysis.image_id),
+        results: Vec::new(),
+        created_at: now,
+        updated_at: now,
+    };
+    data.storage.store_prompt(&saved).await?;
+    if let (Some(regenerated), Some(thumbnail)) = (&regenerated, &thumbnail) {
+        data.storage
+            .add_prompt_result(&saved.id, &regenerated.id, thumbnail)
+            .await?;
+    }
+
+    let saved = data.storage.get_prompt(&saved.id).await?;
+    Ok(HttpResponse::Created().json(&saved))
+}
+
+/// Lists saved prompts, newest first, optionally only those with all of
+/// the given tags and containing the given text.
+pub async fn list_prompts(
+    data: web::Data<AppState>,
+    query: web::Query<PromptQuery>,
+) -> Result<HttpResponse, Error> {
+    let query = query.into_inner();
+    let tags = match query.tags.as_deref() {
+        Some(tags) if !tags.trim().is_empty() => {
+            SavedPrompt::normalize_tags(tags.split(',').map(str::to_string).collect())?
+        }
+        _ => Vec::new(),
+    };
+
+    let mut prompts = data.storage.prompts_tagged(&tags).await?;
+    if let Some(text) = query.q.as_deref().map(str::trim)
+        && !text.is_empty()
+    {
+        prompts.retain(|prompt| prompt.matches(text));
+    }
+    prompts.sort_by_key(|prompt| std::cmp::Reverse(prompt.updated_at));
+
+    Ok(HttpResponse::Ok().json(&prompts))
+}
+
+pub async fn get_prompt(
+    path: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let prompt_id = path.into_inner();
+
+    let prompt = data.storage.get_prompt(&prompt_id).await?;
+
+    Ok(HttpResponse::Ok().json(&prompt))
+}
+
+/// Changes the title or tags of a saved prompt. The prompt itself can't be
+/// edited, so its results stay true to it; save a new one instead.
+pub async fn update_prompt(
+    path: web::Path<Uuid>,
+    data: web::Data<AppState>,
+    body: web::Json<UpdatePromptBody>,
+) -> Result<HttpResponse, Error> {
+    let prompt_id = path.into_inner();
+    let body = body.into_inner();
+
+    let mut prompt = data.storage.get_prompt(&prompt_id).await?;
+    if let Some(tags) = body.tags {
+        prompt.tags = SavedPrompt::normalize_tags(tags)?;
+    }
+    if body.title.is_some() {
+        prompt.title = validated_title(body.title)?;
+    }
+    prompt.updated_at = chrono::Utc::now();
+    data.storage.store_prompt(&prompt).await?;
+
+    Ok(HttpResponse::Ok().json(&prompt))
+}
+
+pub async fn delete_prompt(
+    path: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let prompt_id = path.into_inner();
+
+    data.storage.delete_prompt(&prompt_id).await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Keeps a regenerated image as a result of a saved prompt.
+pub async fn add_prompt_result(
+    path: web::Path<Uuid>,
+    data: web::Data<AppState>,
+    body: web::Json<AddPromptResultBody>,
+) -> Result<HttpResponse, Error> {
+    let prompt_id = path.into_inner();
+
+    let prompt = data.storage.get_prompt(&prompt_id).await?;
+    let regenerated = data
+        .storage
+        .get_regenerated(&body.regenerated_image_id)
+        .await?;
+    keep_prompt_result(&data, &prompt.id, &regenerated).await?;
+
+    let prompt = data.storage.get_prompt(&prompt_id).await?;
+    Ok(HttpResponse::Ok().json(&prompt))
+}
+
+pub async fn get_prompt_thumbnail(
+    req: HttpRequest,
+    path: web::Path<(Uuid, Uuid)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let (prompt_id, regenerated_image_id) = path.into_inner();
+
+    let thumbnail = data
+        .storage
+        .get_prompt_thumbnail(&prompt_id, &regenerated_image_id)
+        .await?;
+
+    Ok(image_bytes_response(
+        &req,
+        &variant_etag(&regenerated_image_id, ImageVariant::Thumb),
+        thumbnail,
+    ))
+}
+
+/// Stores a thumbnail of `regenerated` with a saved prompt.
+async fn keep_prompt_result(
+    data: &AppState,
+    prompt_id: &Uuid,
+    regenerated: &RegeneratedImage,
+) -> Result<(), Error> {
+    let thumbnail = prompt_thumbnail(data, regenerated).await?;
+    data.storage
+        .add_prompt_result(prompt_id, &regenerated.id, &thumbnail)
+        .await?;
+    Ok(())
+}
+
+async fn prompt_thumbnail(
+    data: &AppState,
+    regenerated: &RegeneratedImage,
+) -> Result<Vec<u8>, Error> {
+    if let ImageFormat::Vector { .. } = regenerated.format {
+        return Err(SketchyError::Validation(
+            "Vector images have no thumbnail to keep".to_string(),
+        )
+        .into());
+    }
+
+    let image_processor = data.image_processor.clone();
+    let original = regenerated.data.clone();
+    let thumbnail =
+        web::block(move || image_processor.render_variant(&original, ImageVariant::Thumb))
+            .await??;
+    Ok(thumbnail)
+}
+
+/// Trims a prompt, rejecting it if it is empty or too long.
+fn validated_prompt(prompt: &str) -> Result<&str, SketchyError> {
+    let prompt = prompt.trim();
+    if prompt.is_empty() {
+        return Err(SketchyError::Validation("The prompt is empty".to_string()));
+    }
+    if prompt.chars().count() > MAX_PROMPT_CHARS {
+        return Err(SketchyError::Validation(format!(
+            "The prompt is longer than {} characters",
+            MAX_PROMPT_CHARS
+        )));
+    }
+    Ok(prompt)
+}
+
+/// Trims a prompt title. An empty title is no title.
+fn validated_title(title: Option<String>) -> Result<Option<String>, SketchyError> {
+    const MAX_TITLE_CHARS: usize = 200;
+
+    let Some(title) = title.map(|title| title.trim().to_string()) else {
+        return Ok(None);
+    };
+    if title.chars().count() > MAX_TITLE_CHARS {
+        return Err(SketchyError::Validation(format!(
+            "The title is longer than {} characters",
+            MAX_TITLE_CHARS
+        )));
+    }
+    Ok((!title.is_empty()).then_some(title))
+}
+
+/// Returns the similarity report of a regenerated image, computing and
+/// storing it first for images regenerated before reports existed.
+pub async fn get_regenerated_similarity(
+    path: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let regenerated_image_id = path.into_inner();
+
+    let mut regenerated = data
+        .storage
+        .get_regenerated(&regenerated_image_id)
+        .await
+        .map_err(actix_web::error::ErrorNotFound)?;
+
+    if let Some(report) = &regenerated.similarity {
+        return Ok(HttpResponse::Ok().json(report));
+    }
+
+    let analysis = data
+        .storage
+        .get_analysis(&regenerated.analysis_id)
+        .await
+        .map_err(actix_web::error::ErrorNotFound)?;
+    let report = similarity_report(&data, &analysis, &regenerated.data).await?;
+
+    regenerated.similarity = Some(report.clone());
+    data.storage
+        .store_regenerated(&regenerated)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(&report))
+}
+
+/// Analyses a regenerated image again and compares the result with the
+/// analysis it was regenerated from, to show what the prompt lost.
+pub async fn round_trip_regenerated(
+    path: web::Path<Uuid>,
+    data: web::Data<AppState>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+) -> Result<HttpResponse, Error> {
+    let regenerated_image_id = path.into_inner();
+    let provider = query
+        .get("provider")
+        .map(|s| s.as_str())
+        .unwrap_or("openai");
+
+    let mut regenerated = data.storage.get_regenerated(&regenerated_image_id).await?;
+    if let ImageFormat::Vector { .. } = regenerated.format {
+        return Err(SketchyError::Validation(
+            "Vector images can't be analysed; regenerate as raster".to_string(),
+        )
+        .into());
+    }
+    let original = data.storage.get_analysis(&regenerated.analysis_id).await?;
+
+    let round_trip = round_trip(&data, &original, &regenerated, provider).await?;
+
+    regenerated.round_trip = Some(round_trip.clone());
+    data.storage.store_regenerated(&regenerated).await?;
+
+    Ok(HttpResponse::Ok().json(&round_trip))
+}
+
+/// Analyses a regenerated image with `provider` and diffs the result against
+/// `original`.
+async fn round_trip(
+    data: &AppState,
+    original: &ImageAnalysis,
+    regenerated: &RegeneratedImage,
+    provider: &str,
+) -> Result<RoundTrip, Error> {
+    let image_data = if provider == "anthropic" {
+        data.image_processor
+            .resize_for_anthropic(&regenerated.data)?
+    } else {
+        regenerated.data.clone()
+    };
+    let mut reanalysis = data
+        .llm_service
+        .analyze_image(&image_data, provider)
+        .await?;
+
+    // Measure colours the same way as for the original so the palettes compare
+    let image_processor = data.image_processor.clone();
+    let bytes = regenerated.data.clone();
+    let raw_analysis = reanalysis.raw_analysis.clone();
+    match web::block(move || image_processor.ground_colors(&bytes, raw_analysis)).await {
+        Ok(Ok(raw_analysis)) => reanalysis.raw_analysis = raw_analysis,
+        Ok(Err(e)) => log::warn!(
+            "Failed to measure colours of regenerated image {}: {}",
+            regenerated.id,
+            e
+        ),
+        Err(e) => log::warn!(
+            "Failed to measure colours of regenerated image {}: {}",
+            regenerated.id,
+            e
+        ),
+    }
+
+    Ok(RoundTrip {
+        analysis_id: original.id,
+        llm_provider: reanalysis.llm_provider,
+        model_used: reanalysis.metadata.model_used,
+        diff: analysis_diff::diff(&original.raw_analysis, &reanalysis.raw_analysis),
+        reanalysis: reanalysis.raw_analysis,
+        prompt_description: reanalysis.prompt_description,
+        created_at: chrono::Utc::now(),
+    })
+}
+
+/// Starts a refinement job on an analysis and returns it while the first
+/// iteration runs. Poll [`get_refinement`] for progress.
+pub async fn start_refinement(
+    path: web::Path<Uuid>,
+    data: web::Data<AppState>,
+    body: web::Json<RefineBody>,
+) -> Result<HttpResponse, Error> {
+    const DEFAULT_ITERATIONS: u32 = 3;
+    const MAX_ITERATIONS: u32 = 10;
+    const DEFAULT_TARGET_SCORE: f32 = 0.85;
+
+    let analysis_id = path.into_inner();
+    let body = body.into_inner();
+
+    let analysis = data.storage.get_analysis(&analysis_id).await?;
+
+    let provider = body.provider.unwrap_or_default();
+    data.llm_service
+        .check_capability(&provider, Capability::TextToImage)?;
+    let llm_provider = body.llm_provider.unwrap_or_else(|| "openai".to_string());
+    data.llm_service.check_analysis_provider(&llm_provider)?;
+
+    let max_iterations = body.max_iterations.unwrap_or(DEFAULT_ITERATIONS);
+    if !(1..=MAX_ITERATIONS).contains(&max_iterations) {
+        return Err(SketchyError::Validation(format!(
+            "max_iterations must be between 1 and {}",
+            MAX_ITERATIONS
+        ))
+        .into());
+    }
+    let target_score = body.target_score.unwrap_or(DEFAULT_TARGET_SCORE);
+    if !(target_score > 0.0 && target_score <= 1.0) {
+        return Err(SketchyError::Validation(
+            "target_score must be above 0 and at most 1".to_string(),
+        )
+        .into());
+    }
+    let prompt = match body.prompt.as_deref().map(str::trim) {
+        Some("") => {
+            return Err(SketchyError::Validation("The prompt is empty".to_string()).into());
+        }
+        Some(prompt) => prompt.to_string(),
+        None => analysis.prompt_description.clone(),
+    };
+
+    let now = chrono::Utc::now();
+    let refinement = Refinement {
+        id: Uuid::new_v4(),
+        analysis_id,
+        status: RefinementStatus::Running,
+        provider,
+        llm_provider,
+        style_preset: body.style_preset,
+        max_iterations,
+        target_score,
+        iterations: Vec::new(),
+        best_regenerated_image_id: None,
+        error: None,
+        created_at: now,
+        updated_at: now,
+    };
+    data.storage.store_refinement(&refinement).await?;
+
+    let state = data.get_ref().clone();
+    let job = refinement.clone();
+    actix_web::rt::spawn(async move {
+        let refinement_id = job.id;
+        if let Err(e) = run_refinement(&state, job, analysis, prompt).await {
+            log::warn!("Refinement {} stopped: {}", refinement_id, e);
+        }
+    });
+
+    Ok(HttpResponse::Accepted().json(&refinement))
+}
+
+pub async fn get_refinement(
+    path: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let refinement_id = path.into_inner();
+
+    let refinement = data.storage.get_refinement(&refinement_id).await?;
+
+    Ok(HttpResponse::Ok().json(&refinement))
+}
+
+/// Runs the iterations of a refinement, saving the job after each one. A
+/// failed iteration fails the job, keeping the iterations before it.
+async fn run_refinement(
+    data: &AppState,
+    mut refinement: Refinement,
+    analysis: ImageAnalysis,
+    prompt: String,
+) -> Result<(), Error> {
+    let result = refine(data, &mut refinement, &analysis, prompt).await;
+    if let Err(e) = &result {
+        refinement.status = RefinementStatus::Failed;
+        refinement.error = Some(e.to_string());
+    }
+    refinement.updated_at = chrono::Utc::now();
+    data.storage.store_refinement(&refinement).await?;
+    result
+}
+
+async fn refine(
+    data: &AppState,
+    refinement: &mut Refinement,
+    analysis: &ImageAnalysis,
+    mut prompt: String,
+) -> Result<(), Error> {
+    let mut rationale = None;
+
+    for iteration in 1..=refinement.max_iterations {
+        let mut regenerated = data
+            .llm_service
+            .generate_image(
+                &prompt,
+                refinement.provider.clone(),
+                "raster",
+                refinement.style_preset.as_deref(),
+            )
+            .await?;
+        regenerated.analysis_id = analysis.id;
+
+        let similarity = similarity_report(data, analysis, &regenerated.data).await?;
+        let round_trip = round_trip(data, analysis, &regenerated, &refinement.llm_provider).await?;
+        let score = RefinementScore::new(&similarity, &round_trip);
+
+        regenerated.refinement = Some(RefinementStep {
+            refinement_id: refinement.id,
+            iteration,
+            score: score.clone(),
+        });
+        regenerated.similarity = Some(similarity.clone());
+        regenerated.round_trip = Some(round_trip.clone());
+        data.storage.store_regenerated(&regenerated).await?;
+        spawn_eager_variants(
+            data,
+            Entity::Regenerated,
+            regenerated.id,
+            regenerated.data.clone(),
+        );
+
+        let ended = refinement.record(RefinementIteration {
+            iteration,
+            regenerated_image_id: regenerated.id,
+            prompt: prompt.clone(),
+            rationale: rationale.take(),
+            score,
+        });
+        if ended {
+            return Ok(());
+        }
+
+        refinement.updated_at = chrono::Utc::now();
+        data.storage.store_refinement(refinement).await?;
+
+        let revision = data
+            .llm_service
+            .revise_prompt(
+                &prompt,
+                analysis,
+                &round_trip,
+                &similarity,
+                &refinement.provider,
+                &refinement.llm_provider,
+            )
+            .await?;
+        prompt = revision.prompt;
+        rationale = Some(revision.rationale);
+    }
+
+    refinement.status = RefinementStatus::Exhausted;
+    Ok(())
+}
+
+/// Compares a regenerated image with the upload its analysis was made from.
+async fn similarity_report(
+    data: &AppState,
+    analysis: &ImageAnalysis,
+    regenerated: &[u8],
+) -> Result<SimilarityReport, Error> {
+    let original = data
+        .storage
+        .get_image(&analysis.image_id)
+        .await
+        .map_err(actix_web::error::ErrorNotFound)?;
+
+    let image_processor = data.image_processor.clone();
+    let colors = analysis
+        .raw_analysis
+        .global_attributes
+        .dominant_colors
+        .clone();
+    let regenerated = regenerated.to_vec();
+    let report = web::block(move || image_processor.compare(&original.data, &regenerated, &colors))
+        .await??;
+    Ok(report)
+}
+
+pub async fn improve_image(
+    path: web::Path<Uuid>,
+    data: web::Data<AppState>,
+    body: web::Json<ImproveImageBody>,
+) -> Result<HttpResponse, Error> {
+    let source = ImproveSource::Regenerated(path.into_inner());
+    improve(&data, source, body.into_inner()).await
+}
+
+pub async fn improve_from_improved(
+    path: web::Path<Uuid>,
+    data: web::Data<AppState>,
+    body: web::Json<ImproveImageBody>,
+) -> Result<HttpResponse, Error> {
+    let source = ImproveSource::Improved(path.into_inner());
+    improve(&data, source, body.into_inner()).await
+}
+
+pub async fn improve_source(
+    path: web::Path<(SourceKind, Uuid)>,
+    data: web::Data<AppState>,
+    body: web::Json<ImproveImageBody>,
+) -> Result<HttpResponse, Error> {
+    let (kind, id) = path.into_inner();
+    improve(&data, ImproveSource::new(kind, id), body.into_inner()).await
+}
+
+pub async fn inpaint_source(
+    path: web::Path<(SourceKind, Uuid)>,
+    data: web::Data<AppState>,
+    body: web::Json<InpaintBody>,
+) -> Result<HttpResponse, Error> {
+    let (kind, id) = path.into_inner();
+    inpaint(&data, ImproveSource::new(kind, id), body.into_inner()).await
+}
+
+pub async fn outpaint_source(
+    path: web::Path<(SourceKind, Uuid)>,
+    data: web::Data<AppState>,
+    body: web::Json<OutpaintBody>,
+) -> Result<HttpResponse, Error> {
+    let (kind, id) = path.into_inner();
+    outpaint(&data, ImproveSource::new(kind, id), body.into_inner()).await
+}
+
+/// Changes a whole image with a prompt, as the next step of its chain.
+async fn improve(
+    data: &AppState,
+    source: ImproveSource,
+    body: ImproveImageBody,
+) -> Result<HttpResponse, Error> {
+    let provider = body
+        .provider
+        .unwrap_or_else(|| data.llm_service.default_improve_provider());
+    data.llm_service
+        .check_capability(&provider, Capability::ImageToImage)?;
+    let params = body.params.validated()?;
+    check_edit_params(&params, &provider, "improve")?;
+    let prompt = validated_prompt(&body.prompt)?;
+    let source = load_improve_source(data, source).await?;
+
+    let improved = data
+        .llm_service
+        .improve_image(&provider, &source.data, prompt, &params)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    store_improvement(data, improved, &source).await
+}
+
+/// Repaints part of an image, masked by an uploaded image or by a region
+/// of the analysis it was generated from, as the next step of its chain.
+async fn inpaint(
+    data: &AppState,
+    source: ImproveSource,
+    body: InpaintBody,
+) -> Result<HttpResponse, Error> {
+    let provider = body
+        .provider
+        .unwrap_or_else(|| data.llm_service.default_improve_provider());
+    data.llm_service
+        .check_capability(&provider, Capability::Inpaint)?;
+    let params = body.params.validated()?;
+    check_edit_params(&params, &provider, "inpaint")?;
+    let prompt = validated_prompt(&body.prompt)?;
+
+    let source = load_improve_source(data, source).await?;
+    let mask = match (body.mask, &body.region_id) {
+        (Some(mask), None) => MaskSource::Upload(
+            general_purpose::STANDARD
+                .decode(mask.trim())
+                .map_err(|e| SketchyError::Validation(format!("Invalid mask encoding: {}", e)))?,
+        ),
+        (None, Some(region_id)) => {
+            // Regions are in percent, so they carry over to generated images
+            // of any size, though only roughly where the layout differs
+            let analysis = mask_analysis(data, &source, body.analysis_id).await?;
+            MaskSource::Region(find_region(&analysis, region_id)?.coordinates.clone())
+        }
+        _ => {
+            return Err(
+                SketchyError::Validation("Give either a mask or a region_id".to_string()).into(),
+            );
+        }
+    };
+
+    let image_processor = data.image_processor.clone();
+    let image = source.data.clone();
+    let edit_provider = provider.clone();
+    let (image, mask) =
+        web::block(move || image_processor.inpaint_inputs(&image, &mask, &edit_provider)).await??;
+
+    let mut improved = data
+        .llm_service
+        .inpaint(&provider, &image, &mask, prompt, &params)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    improved.edit = Some(ImageEdit::Inpaint {
+        provider,
+        region_id: body.region_id,
+    });
+
+    store_improvement(data, improved, &source).await
+}
+
+/// The analysis to take a region mask from: the one asked for, which must
+/// be of the upload a chain starts from, or else the one the image was
+/// regenerated from.
+async fn mask_analysis(
+    data: &AppState,
+    source: &SourceImage,
+    analysis_id: Option<Uuid>,
+) -> Result<ImageAnalysis, Error> {
+    let analysis_id = match (analysis_id, source.regenerated_image_id) {
+        (Some(analysis_id), _) => analysis_id,
+        (None, Some(regenerated_id)) => {
+            data.storage
+                .get_regenerated(&regenerated_id)
+                .await
+                .map_err(actix_web::error::ErrorNotFound)?
+                .analysis_id
+        }
+        (None, None) => {
+            return Err(SketchyError::Validation(
+                "Give the analysis_id to take the region from".to_string(),
+            )
+            .into());
+        }
+    };
+    let analysis = data
+        .storage
+        .get_analysis(&analysis_id)
+        .await
+        .map_err(actix_web::error::ErrorNotFound)?;
+
+    if let Some(image_id) = source.image_id
+        && analysis.image_id != image_id
+    {
+        return Err(SketchyError::Validation(format!(
+            "Analysis '{}' is not of image '{}'",
+            analysis_id, image_id
+        ))
+        .into());
+    }
+    Ok(analysis)
+}
+
+/// Extends the canvas of an image and paints the new area, as the next
+/// step of its chain.
+async fn outpaint(
+    data: &AppState,
+    source: ImproveSource,
+    body: OutpaintBody,
+) -> Result<HttpResponse, Error> {
+    const DEFAULT_PROMPT: &str = "Extend the image naturally, matching its content and style.";
+
+    let provider = body
+        .provider
+        .unwrap_or_else(|| data.llm_service.default_improve_provider());
+    data.llm_service
+        .check_capability(&provider, Capability::Outpaint)?;
+    let extension = body.extension.validated()?;
+    let params = body.params.validated()?;
+    check_edit_params(&params, &provider, "outpaint")?;
+    let prompt = match body.prompt.as_deref().map(str::trim) {
+        Some(prompt) if !prompt.is_empty() => validated_prompt(prompt)?,
+        _ => DEFAULT_PROMPT,
+    };
+
+    let source = load_improve_source(data, source).await?;
+
+    let image_processor = data.image_processor.clone();
+    let image = source.data.clone();
+    let edit_provider = provider.clone();
+    let image =
+        web::block(move || image_processor.outpaint_input(&image, &extension, &edit_provider))
+            .await??;
+
+    let mut improved = data
+        .llm_service
+        .outpaint(&provider, &image, &extension, prompt, &params)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    improved.edit = Some(ImageEdit::Outpaint {
+        provider,
+        extension,
+    });
+
+    store_improvement(data, improved, &source).await
+}
+
+/// Rejects settings the provider's edit endpoint would silently ignore.
+fn check_edit_params(
+    params: &ImproveParams,
+    provider: &ImageGenerationProvider,
+    operation: &str,
+) -> Result<(), SketchyError> {
+    let openai = *provider == ImageGenerationProvider::OpenAI;
+    let mut unsupported = Vec::new();
+    if params.init_image_mode != InitImageMode::ImageStrength && openai {
+        unsupported.push("init_image_mode");
+    }
+    if params.negative_prompt.is_some() && (openai || operation == "outpaint") {
+        unsupported.push("negative_prompt");
+    }
+    if params.seed.is_some() && openai {
+        unsupported.push("seed");
+    }
+    if params.style_preset.is_some() && openai {
+        unsupported.push("style_preset");
+    }
+
+    if unsupported.is_empty() {
+        Ok(())
+    } else {
+        Err(SketchyError::Validation(format!(
+            "{} can't be used to {} with {}",
+            unsupported.join(", "),
+            operation,
+            provider.as_str()
+        )))
+    }
+}
+
+/// The image an improvement is made from.
+#[derive(Clone, Copy)]
+enum ImproveSource {
+    Upload(Uuid),
+    Regenerated(Uuid),
+    Improved(Uuid),
+}
+
+impl ImproveSource {
+    fn new(kind: SourceKind, id: Uuid) -> Self {
+        match kind {
+            SourceKind::Upload => Self::Upload(id),
+            SourceKind::Regenerated => Self::Regenerated(id),
+            SourceKind::Improved => Self::Improved(id),
+        }
+    }
+}
+
+/// The bytes of an improvement's input and its place in the chain.
+struct SourceImage {
+    data: Vec<u8>,
+    /// The upload the chain starts from, if it doesn't start from a
+    /// regenerated image.
+    image_id: Option<Uuid>,
+    regenerated_image_id: Option<Uuid>,
+    /// The improvement being improved on, with its step.
+    parent: Option<(Uuid, u32)>,
+}
+
+async fn load_improve_source(data: &AppState, source: ImproveSource) -> Result<SourceImage, Error> {
+    match source {
+        ImproveSource::Upload(id) => {
+            let image = data
+                .storage
+                .get_image(&id)
+                .await
+                .map_err(actix_web::error::ErrorNotFound)?;
+            Ok(SourceImage {
+                data: image.data,
+                image_id: Some(image.id),
+                regenerated_image_id: None,
+                parent: None,
+            })
+        }
+        ImproveSource::Regenerated(id) => {
+            let regenerated = data
+                .storage
+                .get_regenerated(&id)
+                .await
+                .map_err(actix_web::error::ErrorNotFound)?;
+            Ok(SourceImage {
+                data: regenerated.data,
+                image_id: None,
+                regenerated_image_id: Some(regenerated.id),
+                parent: None,
+            })
+        }
+        ImproveSource::Improved(id) => {
+            let improved = data
+                .storage
+                .get_improved(&id)
+                .await
+                .map_err(actix_web::error::ErrorNotFound)?;
+            Ok(SourceImage {
+                data: improved.data,
+                image_id: improved.image_id,
+                regenerated_image_id: improved.regenerated_image_id,
+                parent: Some((improved.id, improved.step)),
+            })
+        }
+    }
+}
+
+/// Stores a new improvement as the step after its source.
+async fn store_improvement(
+    data: &AppState,
+    mut improved: ImprovedImage,
+    source: &SourceImage,
+) -> Result<HttpResponse, Error> {
+    improved.image_id = source.image_id;
+    improved.regenerated_image_id = source.regenerated_image_id;
+    improved.parent_improved_id = source.parent.map(|(id, _)| id);
+    improved.step = source.parent.map_or(1, |(_, step)| step + 1);
+
+    data.storage
+        .store_improved(&improved)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    spawn_eager_variants(data, Entity::Improved, improved.id, improved.data.clone());
+
+    Ok(HttpResponse::Ok().json(ImproveImageResponse {
+        id: improved.id,
+        data: general_purpose::STANDARD.encode(&improved.data),
+        provider: improved.provider,
+        params: improved.params,
+    }))
+}
+
+pub async fn get_image(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    query: web::Query<ImageVariantQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let image_id = path.into_inner();
+
+    let variant = query.variant;
+    if let Some(response) =
+        cached_variant_response(&req, &data, Entity::Image, &image_id, variant).await?
+    {
+        return Ok(response);
+    }
+
+    let image = data
+        .storage
+        .get_image(&image_id)
+        .await
+        .map_err(actix_web::error::ErrorNotFound)?;
+
+    serve_image(&req, &data, Entity::Image, &image.id, image.data, variant).await
+}
+
+pub async fn get_image_meta(
+    path: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let image_id = path.into_inner();
+
+    let image = data
+        .storage
+        .get_image(&image_id)
+        .await
+        .map_err(actix_web::error::ErrorNotFound)?;
+
+    Ok(HttpResponse::Ok().json(&image))
+}
+
+pub async fn get_regenerated(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    query: web::Query<ImageVariantQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let regenerated_image_id = path.into_inner();
+
+    let variant = query.variant;
+    if let Some(response) = cached_variant_response(
+        &req,
+        &data,
+        Entity::Regenerated,
+        &regenerated_image_id,
+        variant,
+    )
+    .await?
+    {
+        return Ok(response);
+    }
+
+    let regenerated = data
+        .storage
+        .get_regenerated(&regenerated_image_id)
+        .await
+        .map_err(actix_web::error::ErrorNotFound)?;
+
+    serve_image(
+        &req,
+        &data,
+        Entity::Regenerated,
+        &regenerated.id,
+        regenerated.data,
+        variant,
+    )
+    .await
+}
+
+pub async fn get_regenerated_meta(
+    path: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let regenerated_image_id = path.into_inner();
+
+    let regenerated = data
+        .storage
+        .get_regenerated(&regenerated_image_id)
+        .await
+        .map_err(actix_web::error::ErrorNotFound)?;
+
+    Ok(HttpResponse::Ok().json(&regenerated))
+}
+
+pub async fn get_improved(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    query: web::Query<ImageVariantQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let improved_image_id = path.into_inner();
+
+    let variant = query.variant;
+    if let Some(response) =
+        cached_variant_response(&req, &data, Entity::Improved, &improved_image_id, variant).await?
+    {
+        return Ok(response);
+    }
+
+    let improved = data
+        .storage
+        .get_improved(&improved_image_id)
+        .await
+        .map_err(actix_web::error::ErrorNotFound)?;
+
+    serve_image(
+        &req,
+        &data,
+        Entity::Improved,
+        &improved.id,
+        improved.data,
+        variant,
+    )
+    .await
+}
+
+pub async fn get_improved_meta(
+    path: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let improved_image_id = path.into_inner();
+
+    let improved = data
+        .storage
+        .get_improved(&improved_image_id)
+        .await
+        .map_err(actix_web::error::ErrorNotFound)?;
+
+    Ok(HttpResponse::Ok().json(&improved))
+}
+
+/// Serves a rendition that was generated earlier, if one was requested and exists.
+async fn cached_variant_response(
+    req: &HttpRequest,
+    data: &AppState,
+    entity: Entity,
+    id: &Uuid,
+    variant: Option<ImageVariant>,
+) -> Result<Option<HttpResponse>, Error> {
+    let Some(variant) = variant else {
+        return Ok(None);
+    };
+
+    let cached = data
+        .storage
+        .get_variant(entity, id, variant)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(cached.map(|bytes| image_bytes_response(req, &variant_etag(id, variant), bytes)))
+}
+
+/// Serves an original image, or the requested rendition of it, generating
+/// and caching the rendition first.
+async fn serve_image(
+    req: &HttpRequest,
+    data: &AppState,
+    entity: Entity,
+    id: &Uuid,
+    original: Vec<u8>,
+    variant: Option<ImageVariant>,
+) -> Result<HttpResponse, Error> {
+    match variant {
+        Some(variant) => {
+            let bytes = generate_variant(data, entity, id, original, variant).await?;
+            Ok(image_bytes_response(req, &variant_etag(id, variant), bytes))
+        }
+        None => Ok(image_bytes_response(req, &id.to_string(), original)),
+    }
+}
+
+async fn generate_variant(
+    data: &AppState,
+    entity: Entity,
+    id: &Uuid,
+    original: Vec<u8>,
+    variant: ImageVariant,
+) -> Result<Vec<u8>, Error> {
+    // Decoding and encoding are CPU-bound, so keep them off the async workers
+    let image_processor = data.image_processor.clone();
+    let bytes = web::block(move || image_processor.render_variant(&original, variant)).await??;
+
+    data.storage
+        .store_variant(entity, id, variant, &bytes)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(bytes)
+}
+
+/// Generates the thumbnail and preview of a newly stored image in the
+/// background, so the request that stored it doesn't wait for them.
+fn spawn_eager_variants(data: &AppState, entity: Entity, id: Uuid, original: Vec<u8>) {
+    let data = data.clone();
+    actix_web::rt::spawn(async move {
+        for variant in ImageVariant::EAGER {
+            if let Err(e) = generate_variant(&data, entity, &id, original.clone(), variant).await {
+                log::warn!(
+                    "Failed to generate {} variant of {}:{}: {}",
+                    variant.as_str(),
+                    entity.key_prefix(),
+                    id,
+                    e
+                );
+            }
+        }
+    });
+}
+
+fn variant_etag(id: &Uuid, variant: ImageVariant) -> String {
+    format!("{}-{}", id, variant.as_str())
+}
+
+/// Serves stored image bytes with conditional and range request support.
+/// Stored images and their renditions never change, so `etag` is derived
+/// from the id alone.
+fn image_bytes_response(req: &HttpRequest, etag: &str, data: Vec<u8>) -> HttpResponse {
+    let etag = EntityTag::new_strong(etag.to_string());
+    let content_type = image::guess_format(&data)
+        .map(|format| format.to_mime_type())
+        .unwrap_or("application/octet-stream");
+    let cache_control = CacheControl(vec![
+        CacheDirective::Private,
+        CacheDirective::MaxAge(86400),
+        CacheDirective::Extension("immutable".to_string(), None),
+    ]);
+
+    let not_modified = match IfNoneMatch::parse(req) {
+        Ok(IfNoneMatch::Any) => true,
+        Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        Err(_) => false,
+    };
+    if not_modified {
+        return HttpResponse::NotModified()
+            .insert_header(ETag(etag))
+            .insert_header(cache_control)
+            .finish();
+    }
+
+    let full_length = data.len() as u64;
+    let (mut response, body) = match header::Range::parse(req) {
+        // Only single byte ranges are served; anything else gets the full image
+        Ok(header::Range::Bytes(ranges)) if ranges.len() == 1 => {
+            let Some((from, to)) = ranges[0].to_satisfiable_range(full_length) else {
+                return HttpResponse::RangeNotSatisfiable()
+                    .insert_header(ContentRange(ContentRangeSpec::Bytes {
+                        range: None,
+                        instance_length: Some(full_length),
+                    }))
+                    .finish();
+            };
+
+            let mut response = HttpResponse::PartialContent();
+            response.insert_header(ContentRange(ContentRangeSpec::Bytes {
+                range: Some((from, to)),
+                instance_length: Some(full_length),
+            }));
+            (response, data[from as usize..=to as usize].to_vec())
+        }
+        _ => (HttpResponse::Ok(), data),
+    };
+
+    response
+        .content_type(content_type)
+        .insert_header(ETag(etag))
+        .insert_header(cache_control)
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .body(body)
+}
+
+pub async fn get_image_lineage(
+    path: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let image_id = path.into_inner();
+
+    let lineage = data
+        .storage
+        .image_lineage(&image_id)
+        .await
+        .map_err(actix_web::error::ErrorNotFound)?;
+
+    Ok(HttpResponse::Ok().json(&lineage))
+}
+
+pub async fn get_improvement_lineage(
+    path: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let improved_image_id = path.into_inner();
+
+    let ancestry = data
+        .storage
+        .improvement_ancestry(&improved_image_id)
+        .await
+        .map_err(actix_web::error::ErrorNotFound)?;
+
+    Ok(HttpResponse::Ok().json(&ancestry))
+}
+
+pub async fn get_image_branches(
+    path: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let root = ChainRoot::Upload(path.into_inner());
+    let branches = data.storage.improvement_branches(&root).await?;
+    Ok(HttpResponse::Ok().json(&branches))
+}
+
+pub async fn get_regenerated_branches(
+    path: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let root = ChainRoot::Regenerated(path.into_inner());
+    let branches = data.storage.improvement_branches(&root).await?;
+    Ok(HttpResponse::Ok().json(&branches))
+}
+
+/// Lists the branches of the chain an improvement belongs to.
+pub async fn get_improvement_branches(
+    path: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let improved = data.storage.get_improved(&path.into_inner()).await?;
+    let root = chain_root(&improved)?;
+    let branches = data.storage.improvement_branches(&root).await?;
+    Ok(HttpResponse::Ok().json(&branches))
+}
+
+/// Moves the head of an improvement's chain back (or over) to it. Nothing
+/// is deleted, and improving the head again starts a new branch.
+pub async fn revert_improvement(
+    path: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let improved = data.storage.get_improved(&path.into_inner()).await?;
+    let root = chain_root(&improved)?;
+    data.storage.set_improvement_head(&improved).await?;
+    let branches = data.storage.improvement_branches(&root).await?;
+    Ok(HttpResponse::Ok().json(&branches))
+}
+
+/// Compares the prompts of two steps of the same chain.
+pub async fn diff_improvements(
+    path: web::Path<(Uuid, Uuid)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let (from_id, to_id) = path.into_inner();
+    let from = data.storage.improvement_ancestry(&from_id).await?;
+    let to = data.storage.improvement_ancestry(&to_id).await?;
+
+    if from.steps[0].root() != to.steps[0].root() {
+        return Err(SketchyError::Validation(format!(
+            "Improved images '{}' and '{}' are not in the same chain",
+            from_id, to_id
+        ))
+        .into());
+    }
+
+    // Both ancestries run from the first step, so they agree up to the fork
+    let shared = from
+        .steps
+        .iter()
+        .zip(&to.steps)
+        .take_while(|(a, b)| a.id == b.id)
+        .count();
+    let from_prompt = from
+        .steps
+        .last()
+        .map_or("", |step| step.prompt_used.as_str());
+    let to_prompt = to.steps.last().map_or("", |step| step.prompt_used.as_str());
+
+    Ok(HttpResponse::Ok().json(PromptDiff {
+        from: from_id,
+        to: to_id,
+        common_ancestor: shared.checked_sub(1).map(|i| from.steps[i].id),
+        from_steps: from.steps[shared..].iter().map(StepPrompt::from).collect(),
+        to_steps: to.steps[shared..].iter().map(StepPrompt::from).collect(),
+        changes: TextChange::diff(from_prompt, to_prompt),
+    }))
+}
+
+fn chain_root(improved: &ImprovedImage) -> Result<ChainRoot, SketchyError> {
+    improved.root().ok_or_else(|| {
+        SketchyError::NotFound(format!(
+            "Improved image '{}' has no chain to follow",
+            improved.id
+        ))
+    })
+}
+
+pub async fn delete_session(
+    path: web::Path<Uuid>,
+    query: web::Query<DeleteQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let session_id = path.into_inner();
+
+    let record = data
+        .storage
+        .delete_session(&session_id, query.into_inner().reason)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(&record))
+}
+
+pub async fn delete_image(
+    path: web::Path<Uuid>,
+    query: web::Query<DeleteQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let image_id = path.into_inner();
+
+    let record = data
+        .storage
+        .delete_image(&image_id, query.into_inner().reason)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(&record))
+}
+
+pub async fn delete_analysis(
+    path: web::Path<Uuid>,
+    query: web::Query<DeleteQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let analysis_id = path.into_inner();
+
+    let record = data
+        .storage
+        .delete_analysis(&analysis_id, query.into_inner().reason)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(&record))
+}
+
+pub async fn delete_regenerated(
+    path: web::Path<Uuid>,
+    query: web::Query<DeleteQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let regenerated_image_id = path.into_inner();
+
+    let record = data
+        .storage
+        .delete_regenerated(&regenerated_image_id, query.into_inner().reason)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(&record))
+}
+
+pub async fn delete_improved(
+    path: web::Path<Uuid>,
+    query: web::Query<DeleteQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let improved_image_id = path.into_inner();
+
+    let record = data
+        .storage
+        .delete_improved(&improved_image_id, query.into_inner().reason)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(&record))
+}
+
+pub async fn get_deletion(
+    path: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let deletion_id = path.into_inner();
+
+    let record = data.storage.get_deletion(&deletion_id).await?;
+
+    Ok(HttpResponse::Ok().json(&record))
+}
+
+pub async fn list_providers(data: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(data.llm_service.providers())
+}
+
+pub async fn list_sessions(_data: web::Data<AppState>) -> Result<HttpResponse, Error> {
+    // This would implement listing recent sessions
+    // For now, return a placeholder
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "sessions": [],
+        "message": "Session listing not yet implemented"
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn analysis() -> ImageAnalysis {
+        serde_json::from_value(serde_json::json!({
+            "id": Uuid::new_v4(),
+            "image_id": Uuid::new_v4(),
+            "llm_provider": "openai",
+            "raw_analysis": {
+                "regions": [],
+                "global_attributes": {
+                    "style": "watercolor",
+                    "mood": "",
+                    "lighting": "",
+                    "perspective": "",
+                    "dominant_colors": [],
+                },
+                "composition": {
+                    "layout": "",
+                    "focal_points": [],
+                    "balance": "",
+                    "depth_layers": [],
+                },
+            },
+            "prompt_description": "A watercolor barn",
+            "metadata": {
+                "processing_time_ms": 1,
+                "model_used": "gpt-4o",
+                "confidence_score": 0.9,
+            },
+            "created_at": "2026-01-01T00:00:00Z",
+        }))
+        .unwrap()
+    }
+
+    /// A library prompt as stored. Without `is_template` it is stored as it
+    /// was before prompts could be templates.
+    fn saved(prompt: &str, is_template: Option<bool>) -> SavedPrompt {
+        let mut saved = serde_json::json!({
+            "id": Uuid::new_v4(),
+            "prompt": prompt,
+            "title": null,
+            "tags": [],
+            "analysis_id": null,
+            "image_id": null,
+            "created_at": "2026-01-01T00:00:00Z",
+            "updated_at": "2026-01-01T00:00:00Z",
+        });
+        if let Some(is_template) = is_template {
+            saved["is_template"] = is_template.into();
+        }
+        serde_json::from_value(saved).unwrap()
+    }
+
+    #[test]
+    fn saved_prompts_are_used_as_written() {
+        let analysis = analysis();
+        let none = BTreeMap::new();
+        for is_template in [None, Some(false)] {
+            // Unbalanced braces and a placeholder the analysis could fill
+            let saved = saved("A barn { in {style} }}", is_template);
+            assert!(!saved.is_template);
+            let templated = templated_prompt(&analysis, None, Some(&saved), &none).unwrap();
+            assert!(templated.is_none());
+        }
+
+        let saved = saved("A barn in {style}", None);
+        let variables = BTreeMap::from([("style".to_string(), "ink".to_string())]);
+        assert!(matches!(
+            templated_prompt(&analysis, None, Some(&saved), &variables),
+            Err(SketchyError::Validation(_))
+        ));
+    }
+
+    #[test]
+    fn saved_templates_are_filled() {
+        let analysis = analysis();
+        let saved = saved("A barn in {style}, {{literally}}", Some(true));
+
+        let (prompt, used) = templated_prompt(&analysis, None, Some(&saved), &BTreeMap::new())
+            .unwrap()
+            .unwrap();
+        assert_eq!(prompt, "A barn in watercolor, {literally}");
+        assert_eq!(used.template, saved.prompt);
+        assert_eq!(used.variables["style"], "watercolor");
+
+        let variables = BTreeMap::from([("style".to_string(), "ink".to_string())]);
+        let (prompt, _) = templated_prompt(&analysis, None, Some(&saved), &variables)
+            .unwrap()
+            .unwrap();
+        assert_eq!(prompt, "A barn in ink, {literally}");
+    }
+
+    #[test]
+    fn variables_need_a_template() {
+        let variables = BTreeMap::from([("style".to_string(), "ink".to_string())]);
+        assert!(matches!(
+            templated_prompt(&analysis(), None, None, &variables),
+            Err(SketchyError::Validation(_))
+        ));
+        assert!(
+            templated_prompt(&analysis(), None, None, &BTreeMap::new())
+                .unwrap()
+                .is_none()
+        );
+    }
 }
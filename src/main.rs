@@ -12,14 +12,23 @@ mod services;
 
 
 use crate::handlers::{
-    analyze_image, get_analysis, improve_from_improved, improve_image, list_sessions,
-    regenerate_image, upload_images,
+    add_prompt_result, add_session_images, analyze_image, analyze_region, batch_regenerate,
+    compose_prompt, critique_prompt, delete_analysis, delete_image, delete_improved, delete_prompt,
+    delete_regenerated, delete_session, diff_improvements, export_session, get_analysis,
+    get_analysis_overlay, get_deletion, get_image, get_image_branches, get_image_lineage,
+    get_image_meta, get_improved, get_improved_meta, get_improvement_branches,
+    get_improvement_lineage, get_prompt, get_prompt_thumbnail, get_refinement, get_regenerated,
+    get_regenerated_branches, get_regenerated_meta, get_regenerated_similarity, get_region_crop,
+    get_region_layout, import_session, improve_from_improved, improve_image, improve_source,
+    inpaint_source, list_prompts, list_providers, list_sessions, outpaint_source, pin_session,
+    regenerate_image, revert_improvement, round_trip_regenerated, save_prompt, start_refinement,
+    unpin_session, update_prompt, upload_images,
 };
-use crate::services::{ImageProcessor, LLMService, RedisService};
+use crate::services::{ImageProcessor, LLMService, Storage, storage};
 
 #[derive(Clone)]
 pub struct AppState {
-    redis_service: Arc<RedisService>,
+    storage: Arc<dyn Storage>,
     llm_service: Arc<LLMService>,
     image_processor: Arc<ImageProcessor>,
 }
@@ -31,7 +40,9 @@ async fn main() -> std::io::Result<()> {
     info!("Starting Sketchy service...");
 
     // Initialize services
-    let redis_service = Arc::new(RedisService::new("redis://127.0.0.1:6379").await.unwrap());
+    let storage = storage::from_env()
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
     let llm_service = Arc::new(LLMService::new(
         std::env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY must be set"),
         std::env::var("ANTHROPIC_API_KEY").ok(),
@@ -40,7 +51,7 @@ async fn main() -> std::io::Result<()> {
     let image_processor = Arc::new(ImageProcessor::new());
 
     let app_state = AppState {
-        redis_service,
+        storage,
         llm_service,
         image_processor,
     };
@@ -56,7 +67,53 @@ async fn main() -> std::io::Result<()> {
                     .route("/upload", web::post().to(upload_images))
                     .route("/analyze/{image_id}", web::post().to(analyze_image))
                     .route("/analysis/{analysis_id}", web::get().to(get_analysis))
-                    .route("/regenerate/{analysis_id}", web::post().to(regenerate_image))
+                    .route("/analysis/{analysis_id}", web::delete().to(delete_analysis))
+                    .route(
+                        "/analysis/{analysis_id}/prompt",
+                        web::post().to(compose_prompt),
+                    )
+                    .route(
+                        "/analysis/{analysis_id}/overlay",
+                        web::get().to(get_analysis_overlay),
+                    )
+                    .route(
+                        "/analysis/{analysis_id}/regions",
+                        web::get().to(get_region_layout),
+                    )
+                    .route(
+                        "/analysis/{analysis_id}/regions/{region_id}/crop",
+                        web::get().to(get_region_crop),
+                    )
+                    .route(
+                        "/analysis/{analysis_id}/regions/{region_id}/analyze",
+                        web::post().to(analyze_region),
+                    )
+                    .route(
+                        "/analysis/{analysis_id}/refine",
+                        web::post().to(start_refinement),
+                    )
+                    .route("/prompts/critique", web::post().to(critique_prompt))
+                    .route("/prompts", web::post().to(save_prompt))
+                    .route("/prompts", web::get().to(list_prompts))
+                    .route("/prompts/{prompt_id}", web::get().to(get_prompt))
+                    .route("/prompts/{prompt_id}", web::patch().to(update_prompt))
+                    .route("/prompts/{prompt_id}", web::delete().to(delete_prompt))
+                    .route(
+                        "/prompts/{prompt_id}/results",
+                        web::post().to(add_prompt_result),
+                    )
+                    .route(
+                        "/prompts/{prompt_id}/results/{regenerated_image_id}/thumb",
+                        web::get().to(get_prompt_thumbnail),
+                    )
+                    .route(
+                        "/regenerate/{analysis_id}",
+                        web::post().to(regenerate_image),
+                    )
+                    .route(
+                        "/regenerate/{analysis_id}/batch",
+                        web::post().to(batch_regenerate),
+                    )
                     .route(
                         "/improve/from_original/{regenerated_image_id}",
                         web::post().to(improve_image),
@@ -65,7 +122,97 @@ async fn main() -> std::io::Result<()> {
                         "/improve/from_improved/{improved_image_id}",
                         web::post().to(improve_from_improved),
                     )
-                    .route("/sessions", web::get().to(list_sessions)),
+                    .route("/improve/{kind}/{id}", web::post().to(improve_source))
+                    .route(
+                        "/improve/{kind}/{id}/inpaint",
+                        web::post().to(inpaint_source),
+                    )
+                    .route(
+                        "/improve/{kind}/{id}/outpaint",
+                        web::post().to(outpaint_source),
+                    )
+                    .route("/images/{image_id}", web::get().to(get_image))
+                    .route("/images/{image_id}", web::delete().to(delete_image))
+                    .route("/images/{image_id}/meta", web::get().to(get_image_meta))
+                    .route(
+                        "/images/{image_id}/lineage",
+                        web::get().to(get_image_lineage),
+                    )
+                    .route(
+                        "/images/{image_id}/branches",
+                        web::get().to(get_image_branches),
+                    )
+                    .route(
+                        "/regenerated/{regenerated_image_id}",
+                        web::get().to(get_regenerated),
+                    )
+                    .route(
+                        "/regenerated/{regenerated_image_id}",
+                        web::delete().to(delete_regenerated),
+                    )
+                    .route(
+                        "/regenerated/{regenerated_image_id}/meta",
+                        web::get().to(get_regenerated_meta),
+                    )
+                    .route(
+                        "/regenerated/{regenerated_image_id}/similarity",
+                        web::get().to(get_regenerated_similarity),
+                    )
+                    .route(
+                        "/regenerated/{regenerated_image_id}/roundtrip",
+                        web::post().to(round_trip_regenerated),
+                    )
+                    .route(
+                        "/regenerated/{regenerated_image_id}/branches",
+                        web::get().to(get_regenerated_branches),
+                    )
+                    .route("/improved/{improved_image_id}", web::get().to(get_improved))
+                    .route(
+                        "/improved/{improved_image_id}",
+                        web::delete().to(delete_improved),
+                    )
+                    .route(
+                        "/improved/{improved_image_id}/meta",
+                        web::get().to(get_improved_meta),
+                    )
+                    .route(
+                        "/improved/{improved_image_id}/lineage",
+                        web::get().to(get_improvement_lineage),
+                    )
+                    .route(
+                        "/improved/{improved_image_id}/branches",
+                        web::get().to(get_improvement_branches),
+                    )
+                    .route(
+                        "/improved/{improved_image_id}/revert",
+                        web::post().to(revert_improvement),
+                    )
+                    .route(
+                        "/improved/{improved_image_id}/diff/{other_improved_id}",
+                        web::get().to(diff_improvements),
+                    )
+                    .route(
+                        "/refinements/{refinement_id}",
+                        web::get().to(get_refinement),
+                    )
+                    .route("/providers", web::get().to(list_providers))
+                    .route("/sessions", web::get().to(list_sessions))
+                    .route("/sessions/import", web::post().to(import_session))
+                    .route("/sessions/{session_id}", web::delete().to(delete_session))
+                    .route(
+                        "/sessions/{session_id}/images",
+                        web::post().to(add_session_images),
+                    )
+                    .route(
+                        "/sessions/{session_id}/export",
+                        web::get().to(export_session),
+                    )
+                    .route("/sessions/{session_id}/pin", web::post().to(pin_session))
+                    .route(
+                        "/sessions/{session_id}/pin",
+                        web::delete().to(unpin_session),
+                    )
+                    .route("/deletions/{deletion_id}", web::get().to(get_deletion)),
             )
             .route("/health", web::get().to(health_check))
             .service(fs::Files::new("/", "./frontend/").index_file("index.html"))
@@ -2,15 +2,73 @@
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum ImageGenerationProvider {
+    #[default]
     OpenAI,
     StabilityAI,
 }
 
-impl Default for ImageGenerationProvider {
-    fn default() -> Self {
-        ImageGenerationProvider::OpenAI
+/// Something a provider's API can do with images.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// Generate an image from a prompt.
+    TextToImage,
+    /// Change a whole image guided by a prompt.
+    ImageToImage,
+    /// Repaint a masked area of an image.
+    Inpaint,
+    /// Extend the canvas of an image.
+    Outpaint,
+}
+
+impl Capability {
+    /// What the capability does, to complete "can't ...".
+    pub fn action(&self) -> &'static str {
+        match self {
+            Capability::TextToImage => "generate images",
+            Capability::ImageToImage => "improve images",
+            Capability::Inpaint => "inpaint",
+            Capability::Outpaint => "outpaint",
+        }
+    }
+}
+
+impl ImageGenerationProvider {
+    /// Every provider, in the order they are listed.
+    pub const ALL: [ImageGenerationProvider; 2] = [
+        ImageGenerationProvider::OpenAI,
+        ImageGenerationProvider::StabilityAI,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImageGenerationProvider::OpenAI => "openai",
+            ImageGenerationProvider::StabilityAI => "stabilityai",
+        }
     }
-}
\ No newline at end of file
+
+    /// What the provider's API supports, whether or not it is configured.
+    pub fn capabilities(&self) -> &'static [Capability] {
+        match self {
+            ImageGenerationProvider::OpenAI => &[
+                Capability::TextToImage,
+                Capability::ImageToImage,
+                Capability::Inpaint,
+                Capability::Outpaint,
+            ],
+            ImageGenerationProvider::StabilityAI => &[
+                Capability::TextToImage,
+                Capability::ImageToImage,
+                Capability::Inpaint,
+                Capability::Outpaint,
+            ],
+        }
+    }
+
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities().contains(&capability)
+    }
+}
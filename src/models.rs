@@ -1,6 +1,9 @@
 // src/models.rs
+use crate::errors::SketchyError;
+use crate::mcp::{Capability, ImageGenerationProvider};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use uuid::Uuid;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -10,6 +13,8 @@ pub struct ImageUpload {
     pub filename: String,
     pub content_type: String,
     pub size: usize,
+    /// Raw image bytes. Stored separately from the JSON metadata, so never serialized.
+    #[serde(default, skip_serializing)]
     pub data: Vec<u8>,
     pub uploaded_at: DateTime<Utc>,
 }
@@ -32,6 +37,41 @@ pub struct RawAnalysis {
     pub composition: CompositionAnalysis,
 }
 
+impl RawAnalysis {
+    /// Finds a region by id, including regions nested in region details.
+    pub fn find_region(&self, id: &str) -> Option<&ImageRegion> {
+        let mut pending: Vec<&ImageRegion> = self.regions.iter().collect();
+        while let Some(region) = pending.pop() {
+            if region.id == id {
+                return Some(region);
+            }
+            if let Some(detail) = &region.detail {
+                pending.extend(detail.regions.iter());
+            }
+        }
+        None
+    }
+
+    pub fn find_region_mut(&mut self, id: &str) -> Option<&mut ImageRegion> {
+        fn find<'a>(regions: &'a mut [ImageRegion], id: &str) -> Option<&'a mut ImageRegion> {
+            for region in regions {
+                if region.id == id {
+                    return Some(region);
+                }
+                if let Some(found) = region
+                    .detail
+                    .as_mut()
+                    .and_then(|detail| find(&mut detail.regions, id))
+                {
+                    return Some(found);
+                }
+            }
+            None
+        }
+        find(&mut self.regions, id)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ImageRegion {
     pub id: String,
@@ -40,10 +80,107 @@ pub struct ImageRegion {
     pub object_description: String,
     pub texture_description: String,
     pub importance_score: f32,
+    /// A closer look at the region, made by analysing a crop of it.
+    #[serde(default)]
+    pub detail: Option<RegionDetail>,
 }
 
+/// The result of analysing a crop of one region on its own.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegionDetail {
+    pub llm_provider: String,
+    pub model_used: String,
+    /// A prompt describing only this region, more precisely than the prompt
+    /// for the whole image does.
+    pub prompt_description: String,
+    pub attributes: GlobalAttributes,
+    /// Parts of the region. Their coordinates are relative to the whole
+    /// image, like those of top-level regions.
+    pub regions: Vec<ImageRegion>,
+    pub processing_time_ms: u64,
+    pub analyzed_at: DateTime<Utc>,
+}
+
+/// A region of an image in percent of its size, from 0 to 100, with `x` and
+/// `y` at the top-left corner. Use [`BoundingBox::to_pixels`] for a given
+/// image size.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BoundingBox {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl BoundingBox {
+    /// Builds a box from coordinates reported by an LLM, clamped to the
+    /// image. Models sometimes answer in fractions despite being asked for
+    /// percentages, so a box with every value at most 1 is scaled up.
+    pub fn from_reported(x: f32, y: f32, width: f32, height: f32) -> Self {
+        let scale = if [x, y, width, height].iter().all(|v| *v <= 1.0) {
+            100.0
+        } else {
+            1.0
+        };
+        let x = (x * scale).clamp(0.0, 100.0);
+        let y = (y * scale).clamp(0.0, 100.0);
+        Self {
+            x,
+            y,
+            width: (width * scale).clamp(0.0, 100.0 - x),
+            height: (height * scale).clamp(0.0, 100.0 - y),
+        }
+    }
+
+    /// The box in an image of `width` x `height` pixels, clamped to the
+    /// image. `None` if less than a pixel is left.
+    pub fn to_pixels(&self, width: u32, height: u32) -> Option<PixelRect> {
+        let to_px = |percent: f32, size: u32| {
+            ((percent.clamp(0.0, 100.0) as f64 / 100.0 * size as f64).round() as u32).min(size)
+        };
+        let x = to_px(self.x, width);
+        let y = to_px(self.y, height);
+        let rect = PixelRect {
+            x,
+            y,
+            width: to_px(self.x + self.width, width).saturating_sub(x),
+            height: to_px(self.y + self.height, height).saturating_sub(y),
+        };
+        (rect.width > 0 && rect.height > 0).then_some(rect)
+    }
+
+    /// Converts a box given relative to `parent` into one relative to the
+    /// image `parent` is in.
+    pub fn within(&self, parent: &BoundingBox) -> Self {
+        Self {
+            x: parent.x + self.x * parent.width / 100.0,
+            y: parent.y + self.y * parent.height / 100.0,
+            width: self.width * parent.width / 100.0,
+            height: self.height * parent.height / 100.0,
+        }
+    }
+
+    /// Intersection over union with `other`: 0 if the boxes don't touch, 1
+    /// if they are the same.
+    pub fn overlap(&self, other: &BoundingBox) -> f32 {
+        let width = (self.x + self.width).min(other.x + other.width) - self.x.max(other.x);
+        let height = (self.y + self.height).min(other.y + other.height) - self.y.max(other.y);
+        if width <= 0.0 || height <= 0.0 {
+            return 0.0;
+        }
+        let intersection = width * height;
+        let union = self.width * self.height + other.width * other.height - intersection;
+        if union > 0.0 {
+            intersection / union
+        } else {
+            0.0
+        }
+    }
+}
+
+/// A rectangle in pixels, with `x` and `y` at the top-left corner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PixelRect {
     pub x: u32,
     pub y: u32,
     pub width: u32,
@@ -55,6 +192,134 @@ pub struct Color {
     pub hex: String,
     pub rgb: (u8, u8, u8),
     pub percentage: f32,
+    #[serde(default)]
+    pub source: ColorSource,
+}
+
+/// Where a colour in an analysis came from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorSource {
+    /// Claimed by the LLM and not checked against the image.
+    #[default]
+    Reported,
+    /// Measured from the image; the LLM reported nothing close to it.
+    Measured,
+    /// Measured from the image and close to a colour the LLM reported.
+    Confirmed,
+}
+
+impl Color {
+    pub fn from_rgb(rgb: (u8, u8, u8), percentage: f32, source: ColorSource) -> Self {
+        Self {
+            hex: format!("#{:02x}{:02x}{:02x}", rgb.0, rgb.1, rgb.2),
+            rgb,
+            percentage,
+            source,
+        }
+    }
+
+    /// Parses `#rrggbb` or `#rgb`, with or without the leading `#`.
+    pub fn parse_hex(hex: &str) -> Option<(u8, u8, u8)> {
+        let digits = hex.trim().trim_start_matches('#');
+        if !digits.is_ascii() {
+            return None;
+        }
+        let channel = |s: &str| u8::from_str_radix(s, 16).ok();
+        match digits.len() {
+            6 => Some((
+                channel(&digits[0..2])?,
+                channel(&digits[2..4])?,
+                channel(&digits[4..6])?,
+            )),
+            3 => Some((
+                channel(&digits[0..1])? * 17,
+                channel(&digits[1..2])? * 17,
+                channel(&digits[2..3])? * 17,
+            )),
+            _ => None,
+        }
+    }
+
+    /// A rough English name for the colour, such as "dark green" or "light
+    /// grey", for prompts where hex codes mean little.
+    pub fn name(&self) -> String {
+        let [r, g, b] = [self.rgb.0, self.rgb.1, self.rgb.2].map(|c| c as f32 / 255.0);
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        let lightness = (max + min) / 2.0;
+        let chroma = max - min;
+
+        if chroma < 0.08 {
+            return match lightness {
+                l if l < 0.15 => "black",
+                l if l < 0.4 => "dark grey",
+                l if l < 0.75 => "grey",
+                l if l < 0.93 => "light grey",
+                _ => "white",
+            }
+            .to_string();
+        }
+
+        let hue = if max == r {
+            60.0 * ((g - b) / chroma).rem_euclid(6.0)
+        } else if max == g {
+            60.0 * ((b - r) / chroma + 2.0)
+        } else {
+            60.0 * ((r - g) / chroma + 4.0)
+        };
+        let saturation = chroma / (1.0 - (2.0 * lightness - 1.0).abs());
+
+        let hue_name = match hue {
+            h if !(15.0..345.0).contains(&h) => "red",
+            h if h < 40.0 => "orange",
+            h if h < 65.0 => "yellow",
+            h if h < 160.0 => "green",
+            h if h < 195.0 => "cyan",
+            h if h < 255.0 => "blue",
+            h if h < 290.0 => "purple",
+            _ => "pink",
+        };
+        if hue_name == "orange" && lightness < 0.4 {
+            return "brown".to_string();
+        }
+
+        let shade = if lightness < 0.3 {
+            "dark "
+        } else if lightness > 0.7 {
+            "light "
+        } else if saturation < 0.35 {
+            "muted "
+        } else {
+            ""
+        };
+        format!("{}{}", shade, hue_name)
+    }
+}
+
+/// Where the regions and focal points of an analysis lie on the upload it
+/// was made from.
+#[derive(Debug, Clone, Serialize)]
+pub struct RegionLayout {
+    pub analysis_id: Uuid,
+    pub image_id: Uuid,
+    pub image_width: u32,
+    pub image_height: u32,
+    pub regions: Vec<RegionPlacement>,
+    /// Focal points in pixels.
+    pub focal_points: Vec<(u32, u32)>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RegionPlacement {
+    pub region_id: String,
+    pub object_description: String,
+    pub importance_score: f32,
+    pub coordinates: BoundingBox,
+    /// The box in pixels, or `None` if it covers less than a pixel.
+    pub pixels: Option<PixelRect>,
+    /// Colour of the region's outline in the overlay, as `#rrggbb`.
+    pub overlay_color: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -69,11 +334,45 @@ pub struct GlobalAttributes {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CompositionAnalysis {
     pub layout: String,
+    /// Points of interest as (x, y) in percent of the image size, like
+    /// [`BoundingBox`].
     pub focal_points: Vec<(f32, f32)>,
     pub balance: String,
     pub depth_layers: Vec<String>,
 }
 
+/// Which parts of an analysis go into a prompt composed from it. Every
+/// section is on by default.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PromptSections {
+    /// The most important regions, described by their detail prompt when
+    /// they have been analysed on their own.
+    pub regions: bool,
+    /// How many regions to include, most important first.
+    pub max_regions: usize,
+    pub style: bool,
+    pub perspective: bool,
+    pub mood: bool,
+    pub lighting: bool,
+    /// The dominant colours of the image, by name.
+    pub palette: bool,
+}
+
+impl Default for PromptSections {
+    fn default() -> Self {
+        Self {
+            regions: true,
+            max_regions: 5,
+            style: true,
+            perspective: true,
+            mood: true,
+            lighting: true,
+            palette: true,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AnalysisMetadata {
     pub processing_time_ms: u64,
@@ -86,12 +385,341 @@ pub struct RegeneratedImage {
     pub id: Uuid,
     pub analysis_id: Uuid,
     pub format: ImageFormat,
+    #[serde(default, skip_serializing)]
     pub data: Vec<u8>,
     pub prompt_used: String,
     pub generation_params: GenerationParams,
+    /// How closely the image matches the upload it was regenerated from.
+    #[serde(default)]
+    pub similarity: Option<SimilarityReport>,
+    /// The latest re-analysis of the image, compared with the analysis it
+    /// was regenerated from.
+    #[serde(default)]
+    pub round_trip: Option<RoundTrip>,
+    /// Set if the image is an iteration of a refinement.
+    #[serde(default)]
+    pub refinement: Option<RefinementStep>,
+    /// Set if `prompt_used` was rendered from a template.
+    #[serde(default)]
+    pub template: Option<TemplatedPrompt>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Local, model-free measures of how close a regenerated image is to the
+/// original upload.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SimilarityReport {
+    /// Structural similarity of the greyscale images, from -1 to 1 where 1
+    /// means identical.
+    pub ssim: f32,
+    /// Hamming distance between 64-bit perceptual hashes, from 0 (same) to 64.
+    pub phash_distance: u32,
+    /// One minus the intersection of the RGB colour histograms, from 0
+    /// (same colour distribution) to 1 (no colours in common).
+    pub histogram_distance: f32,
+    /// Mean CIE76 ΔE between the analysis' dominant colours and the closest
+    /// colours in the regenerated image, weighted by their share. Below
+    /// about 2.3 the difference is imperceptible. `None` if the analysis
+    /// reported no colours.
+    pub dominant_color_delta: Option<f32>,
+    pub computed_at: DateTime<Utc>,
+}
+
+impl SimilarityReport {
+    /// The measures folded into one number from 0 (unrelated) to 1 (the
+    /// same image): the mean of SSIM, hash similarity, histogram overlap
+    /// and, if measured, colour closeness.
+    pub fn score(&self) -> f32 {
+        const MAX_COLOR_DELTA: f32 = 50.0;
+
+        let mut parts = vec![
+            self.ssim.clamp(0.0, 1.0),
+            1.0 - self.phash_distance.min(64) as f32 / 64.0,
+            1.0 - self.histogram_distance.clamp(0.0, 1.0),
+        ];
+        if let Some(delta) = self.dominant_color_delta {
+            parts.push(1.0 - (delta / MAX_COLOR_DELTA).min(1.0));
+        }
+        parts.iter().sum::<f32>() / parts.len() as f32
+    }
+}
+
+/// A regenerated image analysed again, to show what its prompt failed to
+/// convey about the original.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoundTrip {
+    /// The analysis the image was regenerated from.
+    pub analysis_id: Uuid,
+    pub llm_provider: String,
+    pub model_used: String,
+    /// What the analysis of the regenerated image found.
+    pub reanalysis: RawAnalysis,
+    pub prompt_description: String,
+    pub diff: AnalysisDiff,
     pub created_at: DateTime<Utc>,
 }
 
+/// How an analysis of a regenerated image differs from the analysis of the
+/// original.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnalysisDiff {
+    /// Overall agreement, from 0 (nothing in common) to 1 (the same).
+    /// Attributes, regions and palette count for 40%, 40% and 20%.
+    pub score: f32,
+    pub style: AttributeDrift,
+    pub mood: AttributeDrift,
+    pub lighting: AttributeDrift,
+    pub perspective: AttributeDrift,
+    pub layout: AttributeDrift,
+    /// Original regions and the regenerated regions they were matched with.
+    pub matched_regions: Vec<RegionMatch>,
+    /// Original regions with no counterpart in the regenerated image.
+    pub missing_regions: Vec<RegionSummary>,
+    /// Regenerated regions with no counterpart in the original.
+    pub added_regions: Vec<RegionSummary>,
+    /// Each dominant colour of the original and the closest colour of the
+    /// regenerated image.
+    pub palette: Vec<ColorDrift>,
+    /// Mean CIE76 ΔE of `palette`, weighted by each colour's share. `None`
+    /// if either analysis has no colours.
+    pub palette_delta: Option<f32>,
+}
+
+/// One attribute as described in both analyses.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AttributeDrift {
+    pub original: String,
+    pub regenerated: String,
+    /// Share of words the two descriptions have in common, from 0 to 1.
+    pub similarity: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegionMatch {
+    pub original: RegionSummary,
+    pub regenerated: RegionSummary,
+    /// Share of words the two descriptions have in common, from 0 to 1.
+    pub similarity: f32,
+    /// Intersection over union of the two boxes, from 0 (apart) to 1 (same
+    /// place and size).
+    pub overlap: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegionSummary {
+    pub id: String,
+    pub object_description: String,
+    pub importance_score: f32,
+    pub coordinates: BoundingBox,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ColorDrift {
+    pub original: String,
+    /// Hex code of the closest regenerated colour.
+    pub closest: String,
+    pub delta_e: f32,
+    /// Share of the original image the colour covers, in percent.
+    pub percentage: f32,
+}
+
+/// A job that regenerates an image from an analysis again and again,
+/// revising the prompt after each attempt, until one scores high enough or
+/// it runs out of iterations.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Refinement {
+    pub id: Uuid,
+    pub analysis_id: Uuid,
+    pub status: RefinementStatus,
+    pub provider: ImageGenerationProvider,
+    /// The analysis provider that re-analyses the images and revises the
+    /// prompt.
+    pub llm_provider: String,
+    pub style_preset: Option<String>,
+    pub max_iterations: u32,
+    pub target_score: f32,
+    pub iterations: Vec<RefinementIteration>,
+    /// The regenerated image with the highest score so far.
+    pub best_regenerated_image_id: Option<Uuid>,
+    /// Why the job failed, if it did.
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Refinement {
+    /// Adds a finished iteration, keeping track of the best image so far.
+    /// The job converges once an iteration reaches the target score, and is
+    /// exhausted after the last iteration otherwise. Returns whether it
+    /// ended.
+    pub fn record(&mut self, iteration: RefinementIteration) -> bool {
+        let best = self
+            .iterations
+            .iter()
+            .map(|previous| previous.score.combined)
+            .fold(f32::NEG_INFINITY, f32::max);
+        if self.best_regenerated_image_id.is_none() || iteration.score.combined > best {
+            self.best_regenerated_image_id = Some(iteration.regenerated_image_id);
+        }
+
+        let status = if iteration.score.combined >= self.target_score {
+            Some(RefinementStatus::Converged)
+        } else if iteration.iteration >= self.max_iterations {
+            Some(RefinementStatus::Exhausted)
+        } else {
+            None
+        };
+        self.iterations.push(iteration);
+        if let Some(status) = status {
+            self.status = status;
+        }
+        status.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RefinementStatus {
+    Running,
+    /// Reached the target score.
+    Converged,
+    /// Used up its iterations without reaching the target score.
+    Exhausted,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefinementIteration {
+    /// Counted from 1.
+    pub iteration: u32,
+    pub regenerated_image_id: Uuid,
+    pub prompt: String,
+    /// Why the prompt was changed from the previous iteration's. `None` for
+    /// the first iteration.
+    pub rationale: Option<String>,
+    pub score: RefinementScore,
+}
+
+/// How close one iteration of a refinement came to the original.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefinementScore {
+    /// [`SimilarityReport::score`] of the image.
+    pub pixel: f32,
+    /// [`AnalysisDiff::score`] of its round trip.
+    pub analysis: f32,
+    /// The mean of `pixel` and `analysis`, compared with the target score.
+    pub combined: f32,
+}
+
+impl RefinementScore {
+    pub fn new(similarity: &SimilarityReport, round_trip: &RoundTrip) -> Self {
+        let pixel = similarity.score();
+        let analysis = round_trip.diff.score;
+        Self {
+            pixel,
+            analysis,
+            combined: (pixel + analysis) / 2.0,
+        }
+    }
+}
+
+/// Where a regenerated image made by a refinement sits in it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefinementStep {
+    pub refinement_id: Uuid,
+    pub iteration: u32,
+    pub score: RefinementScore,
+}
+
+/// A prompt rewritten to fix what the image made from the previous one got
+/// wrong.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PromptRevision {
+    pub prompt: String,
+    pub rationale: String,
+}
+
+/// The template a prompt was rendered from and the value each of its
+/// placeholders was given.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TemplatedPrompt {
+    pub template: String,
+    pub variables: BTreeMap<String, String>,
+}
+
+/// A prompt kept in the library. Unlike the images and analyses it links
+/// to, it never expires.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedPrompt {
+    pub id: Uuid,
+    pub prompt: String,
+    /// Whether `prompt` has template placeholders to fill when it is used.
+    /// Other prompts are used exactly as written.
+    #[serde(default)]
+    pub is_template: bool,
+    pub title: Option<String>,
+    /// Lowercase, sorted and without duplicates.
+    pub tags: Vec<String>,
+    /// The analysis the prompt came from, which may have expired since.
+    pub analysis_id: Option<Uuid>,
+    /// The upload that analysis was made from.
+    pub image_id: Option<Uuid>,
+    /// Regenerated images made from the prompt. Their thumbnails are kept
+    /// in the library after the images expire.
+    #[serde(default)]
+    pub results: Vec<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl SavedPrompt {
+    pub const MAX_TAGS: usize = 20;
+    pub const MAX_TAG_CHARS: usize = 50;
+
+    /// Trims and lowercases tags, dropping duplicates. Tags can't be empty
+    /// or contain commas, which separate them in searches.
+    pub fn normalize_tags(tags: Vec<String>) -> Result<Vec<String>, SketchyError> {
+        let mut normalized = Vec::new();
+        for tag in tags {
+            let tag = tag.trim().to_lowercase();
+            if tag.is_empty() || tag.contains(',') {
+                return Err(SketchyError::Validation(format!(
+                    "Invalid tag '{}': tags can't be empty or contain commas",
+                    tag
+                )));
+            }
+            if tag.chars().count() > Self::MAX_TAG_CHARS {
+                return Err(SketchyError::Validation(format!(
+                    "Tag '{}' is longer than {} characters",
+                    tag,
+                    Self::MAX_TAG_CHARS
+                )));
+            }
+            normalized.push(tag);
+        }
+        normalized.sort();
+        normalized.dedup();
+        if normalized.len() > Self::MAX_TAGS {
+            return Err(SketchyError::Validation(format!(
+                "A prompt can have at most {} tags",
+                Self::MAX_TAGS
+            )));
+        }
+        Ok(normalized)
+    }
+
+    /// Whether the prompt, title or a tag contains `text`, ignoring case.
+    pub fn matches(&self, text: &str) -> bool {
+        let text = text.to_lowercase();
+        self.prompt.to_lowercase().contains(&text)
+            || self
+                .title
+                .as_ref()
+                .is_some_and(|title| title.to_lowercase().contains(&text))
+            || self.tags.iter().any(|tag| tag.contains(&text))
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ImageFormat {
     Raster {
@@ -103,6 +731,42 @@ pub enum ImageFormat {
     },
 }
 
+/// Derived renditions of a stored image, cached next to the original.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageVariant {
+    /// WebP scaled to fit 256x256.
+    Thumb,
+    /// WebP scaled to fit 768x768.
+    Preview,
+    /// Full-size WebP.
+    Webp,
+    /// Full-size AVIF, only available when built with the `avif` feature.
+    Avif,
+}
+
+impl ImageVariant {
+    pub const ALL: [ImageVariant; 4] = [
+        ImageVariant::Thumb,
+        ImageVariant::Preview,
+        ImageVariant::Webp,
+        ImageVariant::Avif,
+    ];
+
+    /// Variants generated as soon as an image is stored; the rest are
+    /// generated the first time they are requested.
+    pub const EAGER: [ImageVariant; 2] = [ImageVariant::Thumb, ImageVariant::Preview];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImageVariant::Thumb => "thumb",
+            ImageVariant::Preview => "preview",
+            ImageVariant::Webp => "webp",
+            ImageVariant::Avif => "avif",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GenerationParams {
     pub model: String,
@@ -114,8 +778,786 @@ pub struct GenerationParams {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ImprovedImage {
     pub id: Uuid,
-    pub regenerated_image_id: Uuid,
+    /// The regenerated image at the root of the improvement chain, or
+    /// `None` if the chain started from an upload.
+    #[serde(default)]
+    pub regenerated_image_id: Option<Uuid>,
+    /// The upload at the root of the improvement chain, if it started from
+    /// one rather than from a regenerated image.
+    #[serde(default)]
+    pub image_id: Option<Uuid>,
+    /// The improvement this one was made from, or `None` for the first step.
+    #[serde(default)]
+    pub parent_improved_id: Option<Uuid>,
+    /// Position in the chain, starting at 1 for the first improvement.
+    #[serde(default)]
+    pub step: u32,
+    #[serde(default, skip_serializing)]
     pub data: Vec<u8>,
     pub prompt_used: String,
+    /// The provider that made the image. Improvements from before one could
+    /// be chosen were all made by Stability AI.
+    #[serde(default = "legacy_improve_provider")]
+    pub provider: ImageGenerationProvider,
+    /// Settings the improvement was made with. Improvements from before
+    /// these were configurable used the defaults. Edits only use the
+    /// negative prompt, seed, style preset and output format.
+    #[serde(default)]
+    pub params: ImproveParams,
+    /// The edit this improvement was made with, or `None` for an
+    /// image-to-image improvement of the whole image.
+    #[serde(default)]
+    pub edit: Option<ImageEdit>,
     pub created_at: DateTime<Utc>,
 }
+
+impl ImprovedImage {
+    /// The image the improvement's chain starts from.
+    pub fn root(&self) -> Option<ChainRoot> {
+        match (self.image_id, self.regenerated_image_id) {
+            (Some(image_id), _) => Some(ChainRoot::Upload(image_id)),
+            (None, Some(regenerated_id)) => Some(ChainRoot::Regenerated(regenerated_id)),
+            (None, None) => None,
+        }
+    }
+}
+
+/// The upload or regenerated image an improvement chain starts from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", content = "id", rename_all = "lowercase")]
+pub enum ChainRoot {
+    Upload(Uuid),
+    Regenerated(Uuid),
+}
+
+fn legacy_improve_provider() -> ImageGenerationProvider {
+    ImageGenerationProvider::StabilityAI
+}
+
+/// A change to part of an image, or to its canvas, rather than to the whole image.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "operation", rename_all = "lowercase")]
+pub enum ImageEdit {
+    /// Repainted the area covered by a mask.
+    Inpaint {
+        provider: ImageGenerationProvider,
+        /// The analysis region the mask was made from, or `None` for an
+        /// uploaded mask.
+        region_id: Option<String>,
+    },
+    /// Extended the canvas and painted the new area.
+    Outpaint {
+        provider: ImageGenerationProvider,
+        extension: CanvasExtension,
+    },
+}
+
+/// Pixels added to each side of an image by an outpaint.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CanvasExtension {
+    pub left: u32,
+    pub right: u32,
+    pub up: u32,
+    pub down: u32,
+}
+
+impl CanvasExtension {
+    /// Most pixels a side can be extended by in one outpaint.
+    pub const MAX_SIDE: u32 = 2000;
+
+    pub fn validated(self) -> Result<Self, SketchyError> {
+        let sides = [self.left, self.right, self.up, self.down];
+        if sides.iter().all(|side| *side == 0) {
+            return Err(SketchyError::Validation(
+                "Extend at least one of left, right, up or down".to_string(),
+            ));
+        }
+        if sides.iter().any(|side| *side > Self::MAX_SIDE) {
+            return Err(SketchyError::Validation(format!(
+                "A side can be extended by at most {} pixels",
+                Self::MAX_SIDE
+            )));
+        }
+        Ok(self)
+    }
+}
+
+/// Image-to-image settings for an improvement.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImproveParams {
+    pub init_image_mode: InitImageMode,
+    /// With `image_strength` mode, how closely the result follows the
+    /// input image, from 0 (ignore it) to 1 (keep it).
+    pub image_strength: f32,
+    /// With `step_schedule` mode, the fraction of diffusion steps skipped
+    /// at the start. Lower values keep more of the input image.
+    pub step_schedule_start: f32,
+    /// With `step_schedule` mode, the fraction of steps at which to stop.
+    pub step_schedule_end: Option<f32>,
+    pub negative_prompt: Option<String>,
+    /// The seed used. When none is requested, the seed the provider picked
+    /// is stored instead, if it reports one.
+    pub seed: Option<u32>,
+    pub style_preset: Option<String>,
+    pub output_format: OutputFormat,
+}
+
+impl Default for ImproveParams {
+    fn default() -> Self {
+        Self {
+            init_image_mode: InitImageMode::ImageStrength,
+            image_strength: 0.35,
+            step_schedule_start: 0.65,
+            step_schedule_end: None,
+            negative_prompt: None,
+            seed: None,
+            style_preset: None,
+            output_format: OutputFormat::Png,
+        }
+    }
+}
+
+impl ImproveParams {
+    /// Style presets Stability AI accepts.
+    pub const STYLE_PRESETS: [&'static str; 17] = [
+        "3d-model",
+        "analog-film",
+        "anime",
+        "cinematic",
+        "comic-book",
+        "digital-art",
+        "enhance",
+        "fantasy-art",
+        "isometric",
+        "line-art",
+        "low-poly",
+        "modeling-compound",
+        "neon-punk",
+        "origami",
+        "photographic",
+        "pixel-art",
+        "tile-texture",
+    ];
+
+    /// Checks every value is in the range the provider accepts, and drops
+    /// a blank negative prompt.
+    pub fn validated(mut self) -> Result<Self, SketchyError> {
+        let fraction = |name: &str, value: f32| {
+            if (0.0..=1.0).contains(&value) {
+                Ok(())
+            } else {
+                Err(SketchyError::Validation(format!(
+                    "{} must be between 0 and 1, got {}",
+                    name, value
+                )))
+            }
+        };
+        fraction("image_strength", self.image_strength)?;
+        fraction("step_schedule_start", self.step_schedule_start)?;
+        if let Some(end) = self.step_schedule_end {
+            fraction("step_schedule_end", end)?;
+            if end < self.step_schedule_start {
+                return Err(SketchyError::Validation(
+                    "step_schedule_end must not be before step_schedule_start".to_string(),
+                ));
+            }
+        }
+
+        self.negative_prompt = self
+            .negative_prompt
+            .map(|prompt| prompt.trim().to_string())
+            .filter(|prompt| !prompt.is_empty());
+        if let Some(prompt) = &self.negative_prompt
+            && prompt.chars().count() > 10000
+        {
+            return Err(SketchyError::Validation(
+                "negative_prompt must be at most 10000 characters".to_string(),
+            ));
+        }
+
+        // The top of the u32 range is reserved by Stability AI
+        if self.seed == Some(u32::MAX) {
+            return Err(SketchyError::Validation(format!(
+                "seed must be at most {}",
+                u32::MAX - 1
+            )));
+        }
+
+        if let Some(preset) = &self.style_preset
+            && !Self::STYLE_PRESETS.contains(&preset.as_str())
+        {
+            return Err(SketchyError::Validation(format!(
+                "Unknown style_preset '{}', expected one of: {}",
+                preset,
+                Self::STYLE_PRESETS.join(", ")
+            )));
+        }
+
+        Ok(self)
+    }
+}
+
+/// How the input image steers an image-to-image generation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InitImageMode {
+    ImageStrength,
+    StepSchedule,
+}
+
+impl InitImageMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InitImageMode::ImageStrength => "IMAGE_STRENGTH",
+            InitImageMode::StepSchedule => "STEP_SCHEDULE",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Png,
+    Jpeg,
+    Webp,
+}
+
+impl OutputFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpeg",
+            OutputFormat::Webp => "webp",
+        }
+    }
+}
+
+/// Everything derived from one upload: its analyses, the images regenerated
+/// from each analysis and the improvements made to those.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageLineage {
+    pub image: ImageUpload,
+    pub analyses: Vec<AnalysisLineage>,
+    /// First-step improvements made to the upload itself.
+    #[serde(default)]
+    pub improvements: Vec<ImprovementNode>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnalysisLineage {
+    pub analysis: ImageAnalysis,
+    pub regenerations: Vec<RegenerationLineage>,
+    /// Refinement jobs run on the analysis. Their iterations are in
+    /// `regenerations`.
+    #[serde(default)]
+    pub refinements: Vec<Refinement>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegenerationLineage {
+    pub regenerated: RegeneratedImage,
+    /// First-step improvements, each holding the steps made from it.
+    pub improvements: Vec<ImprovementNode>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImprovementNode {
+    pub improved: ImprovedImage,
+    pub children: Vec<ImprovementNode>,
+}
+
+/// The path from an upload down to one improvement.
+#[derive(Debug, Clone, Serialize)]
+pub struct ImprovementAncestry {
+    pub image_id: Option<Uuid>,
+    pub analysis_id: Option<Uuid>,
+    /// `None` for a chain started from the upload itself.
+    pub regenerated_image_id: Option<Uuid>,
+    /// Improvements from the first step down to the requested one.
+    pub steps: Vec<ImprovedImage>,
+}
+
+/// Every branch of an improvement chain. Improving any step again starts a
+/// new branch next to the ones already made from it.
+#[derive(Debug, Clone, Serialize)]
+pub struct ImprovementBranches {
+    pub root: ChainRoot,
+    /// The step to continue from: the latest improvement, unless the chain
+    /// was reverted to another one since.
+    pub head: Option<Uuid>,
+    /// One branch per step nothing has been made from yet, oldest first.
+    pub branches: Vec<ImprovementBranch>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImprovementBranch {
+    /// The last step of the branch.
+    pub tip: Uuid,
+    /// Ids from the first step down to the tip.
+    pub steps: Vec<Uuid>,
+    /// Whether the head is one of the steps.
+    pub contains_head: bool,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// How the prompts of two steps of the same chain differ.
+#[derive(Debug, Clone, Serialize)]
+pub struct PromptDiff {
+    pub from: Uuid,
+    pub to: Uuid,
+    /// The last step both were made from, or `None` if they only share the
+    /// root image.
+    pub common_ancestor: Option<Uuid>,
+    /// Steps after the common ancestor down to each of the two.
+    pub from_steps: Vec<StepPrompt>,
+    pub to_steps: Vec<StepPrompt>,
+    /// Word-level changes from the prompt of `from` to the prompt of `to`.
+    pub changes: Vec<TextChange>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StepPrompt {
+    pub id: Uuid,
+    pub step: u32,
+    pub prompt_used: String,
+}
+
+impl From<&ImprovedImage> for StepPrompt {
+    fn from(improved: &ImprovedImage) -> Self {
+        Self {
+            id: improved.id,
+            step: improved.step,
+            prompt_used: improved.prompt_used.clone(),
+        }
+    }
+}
+
+/// A run of words kept, removed or added between two texts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "op", content = "text", rename_all = "lowercase")]
+pub enum TextChange {
+    Equal(String),
+    Removed(String),
+    Added(String),
+}
+
+impl TextChange {
+    /// The changes turning `from` into `to`, word by word, from their
+    /// longest common subsequence. Runs of the same kind are merged and
+    /// whitespace is normalised to single spaces. Past a million word pairs
+    /// the differing middle of the texts is one removal and one addition.
+    pub fn diff(from: &str, to: &str) -> Vec<TextChange> {
+        const MAX_PAIRS: usize = 1_000_000;
+
+        let a: Vec<&str> = from.split_whitespace().collect();
+        let b: Vec<&str> = to.split_whitespace().collect();
+
+        // Words shared at either end are matched without the table
+        let prefix = a.iter().zip(&b).take_while(|(a, b)| a == b).count();
+        let suffix = a[prefix..]
+            .iter()
+            .rev()
+            .zip(b[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let (a_middle, b_middle) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+
+        let mut words: Vec<TextChange> = a[..prefix]
+            .iter()
+            .map(|word| TextChange::Equal(word.to_string()))
+            .collect();
+        if a_middle.len().saturating_mul(b_middle.len()) > MAX_PAIRS {
+            words.extend(
+                a_middle
+                    .iter()
+                    .map(|word| TextChange::Removed(word.to_string())),
+            );
+            words.extend(
+                b_middle
+                    .iter()
+                    .map(|word| TextChange::Added(word.to_string())),
+            );
+        } else {
+            words.extend(Self::subsequence_diff(a_middle, b_middle));
+        }
+        words.extend(
+            a[a.len() - suffix..]
+                .iter()
+                .map(|word| TextChange::Equal(word.to_string())),
+        );
+
+        let mut changes: Vec<TextChange> = Vec::new();
+        for word in words {
+            match (changes.last_mut(), word) {
+                (Some(TextChange::Equal(run)), TextChange::Equal(word))
+                | (Some(TextChange::Removed(run)), TextChange::Removed(word))
+                | (Some(TextChange::Added(run)), TextChange::Added(word)) => {
+                    run.push(' ');
+                    run.push_str(&word);
+                }
+                (_, word) => changes.push(word),
+            }
+        }
+        changes
+    }
+
+    /// Word changes from the longest common subsequence of `a` and `b`.
+    fn subsequence_diff(a: &[&str], b: &[&str]) -> Vec<TextChange> {
+        // lcs[i][j] is the length of the common subsequence of a[i..] and b[j..]
+        let mut lcs = vec![vec![0u32; b.len() + 1]; a.len() + 1];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                lcs[i][j] = if a[i] == b[j] {
+                    lcs[i + 1][j + 1] + 1
+                } else {
+                    lcs[i + 1][j].max(lcs[i][j + 1])
+                };
+            }
+        }
+
+        let mut words = Vec::new();
+        let (mut i, mut j) = (0, 0);
+        while i < a.len() || j < b.len() {
+            if i < a.len() && j < b.len() && a[i] == b[j] {
+                words.push(TextChange::Equal(a[i].to_string()));
+                i += 1;
+                j += 1;
+            } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+                words.push(TextChange::Removed(a[i].to_string()));
+                i += 1;
+            } else {
+                words.push(TextChange::Added(b[j].to_string()));
+                j += 1;
+            }
+        }
+        words
+    }
+}
+
+/// The `manifest.json` at the root of a session export.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionManifest {
+    pub format_version: u32,
+    pub session_id: Uuid,
+    pub exported_at: DateTime<Utc>,
+    /// Every upload in the session with everything derived from it,
+    /// including the prompts used at each step.
+    pub images: Vec<ImageLineage>,
+    /// Path inside the archive of the bytes of each image, keyed by its id.
+    pub files: BTreeMap<Uuid, String>,
+    /// The improvement each chain continues from, keyed by the id of the
+    /// upload or regenerated image the chain starts from.
+    #[serde(default)]
+    pub heads: BTreeMap<Uuid, Uuid>,
+}
+
+/// Result of importing a session export. Everything is stored under fresh
+/// ids so an archive can be imported more than once.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionImport {
+    pub session_id: Uuid,
+    pub images: usize,
+    pub analyses: usize,
+    pub regenerated: usize,
+    pub improved: usize,
+    /// The new id of every imported entity, keyed by its id in the archive.
+    pub ids: BTreeMap<Uuid, Uuid>,
+}
+
+/// What a deletion request was made for.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeletionTarget {
+    Session,
+    Image,
+    Analysis,
+    Regenerated,
+    Improved,
+}
+
+/// Audit trail of a deletion. Only ids are kept, never the deleted content.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeletionRecord {
+    pub id: Uuid,
+    pub target: DeletionTarget,
+    pub target_id: Uuid,
+    pub reason: Option<String>,
+    /// Every entity removed, including those removed by the cascade.
+    pub images: Vec<Uuid>,
+    pub analyses: Vec<Uuid>,
+    pub regenerated: Vec<Uuid>,
+    pub improved: Vec<Uuid>,
+    pub keys_deleted: usize,
+    pub deleted_at: DateTime<Utc>,
+}
+
+/// An image generation provider and what it can be used for.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProviderInfo {
+    pub provider: ImageGenerationProvider,
+    pub capabilities: Vec<Capability>,
+    /// Whether its API key is set. Providers without one can't be used.
+    pub configured: bool,
+}
+
+/// Feedback on a prompt written by a user.
+#[derive(Debug, Clone, Serialize)]
+pub struct PromptCritique {
+    pub prompt: String,
+    /// The analysis the prompt was checked against, if any.
+    pub analysis_id: Option<Uuid>,
+    pub llm_provider: String,
+    pub model_used: String,
+    /// A short overall assessment.
+    pub summary: String,
+    /// What the reference image has that the prompt leaves out. Always
+    /// empty without an analysis.
+    pub missing_attributes: Vec<MissingAttribute>,
+    /// Words a generator could read more than one way.
+    pub ambiguities: Vec<PromptIssue>,
+    /// Words that pull the image in opposite directions.
+    pub conflicts: Vec<PromptIssue>,
+    pub suggested_prompt: String,
+    pub processing_time_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MissingAttribute {
+    /// Which part of the image, e.g. `lighting` or a region.
+    pub attribute: String,
+    /// What the analysis found there.
+    pub expected: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PromptIssue {
+    /// The words of the prompt concerned.
+    pub terms: Vec<String>,
+    pub explanation: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn equal(text: &str) -> TextChange {
+        TextChange::Equal(text.to_string())
+    }
+
+    fn removed(text: &str) -> TextChange {
+        TextChange::Removed(text.to_string())
+    }
+
+    fn added(text: &str) -> TextChange {
+        TextChange::Added(text.to_string())
+    }
+
+    fn similarity(
+        ssim: f32,
+        phash_distance: u32,
+        dominant_color_delta: Option<f32>,
+    ) -> SimilarityReport {
+        SimilarityReport {
+            ssim,
+            phash_distance,
+            histogram_distance: 0.5,
+            dominant_color_delta,
+            computed_at: Utc::now(),
+        }
+    }
+
+    fn round_trip(score: f32) -> RoundTrip {
+        let raw: RawAnalysis = serde_json::from_value(serde_json::json!({
+            "regions": [],
+            "global_attributes": {
+                "style": "watercolor",
+                "mood": "calm",
+                "lighting": "soft",
+                "perspective": "eye level",
+                "dominant_colors": [],
+            },
+            "composition": {
+                "layout": "centered",
+                "focal_points": [],
+                "balance": "symmetric",
+                "depth_layers": [],
+            },
+        }))
+        .unwrap();
+        let mut diff = crate::services::analysis_diff::diff(&raw, &raw);
+        diff.score = score;
+        RoundTrip {
+            analysis_id: Uuid::new_v4(),
+            llm_provider: "openai".to_string(),
+            model_used: "gpt-4o".to_string(),
+            reanalysis: raw,
+            prompt_description: "A watercolor barn".to_string(),
+            diff,
+            created_at: Utc::now(),
+        }
+    }
+
+    fn job(max_iterations: u32, target_score: f32) -> Refinement {
+        Refinement {
+            id: Uuid::new_v4(),
+            analysis_id: Uuid::new_v4(),
+            status: RefinementStatus::Running,
+            provider: ImageGenerationProvider::OpenAI,
+            llm_provider: "openai".to_string(),
+            style_preset: None,
+            max_iterations,
+            target_score,
+            iterations: Vec::new(),
+            best_regenerated_image_id: None,
+            error: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        }
+    }
+
+    /// Records the next iteration with the given combined score, returning
+    /// its image id and whether the job ended.
+    fn record(refinement: &mut Refinement, combined: f32) -> (Uuid, bool) {
+        let id = Uuid::new_v4();
+        let ended = refinement.record(RefinementIteration {
+            iteration: refinement.iterations.len() as u32 + 1,
+            regenerated_image_id: id,
+            prompt: "A barn".to_string(),
+            rationale: None,
+            score: RefinementScore {
+                pixel: combined,
+                analysis: combined,
+                combined,
+            },
+        });
+        (id, ended)
+    }
+
+    #[test]
+    fn refinement_score_averages_pixel_and_analysis_scores() {
+        // SSIM 1, identical hashes and half the histogram: (1 + 1 + 0.5) / 3
+        let score = RefinementScore::new(&similarity(1.0, 0, None), &round_trip(0.5));
+        assert!((score.pixel - 2.5 / 3.0).abs() < 1e-6, "{}", score.pixel);
+        assert_eq!(score.analysis, 0.5);
+        assert!((score.combined - (2.5 / 3.0 + 0.5) / 2.0).abs() < 1e-6);
+
+        // Negative SSIM counts as 0, a colour delta of 25 as half
+        let score = RefinementScore::new(&similarity(-0.4, 32, Some(25.0)), &round_trip(1.0));
+        assert!((score.pixel - 1.5 / 4.0).abs() < 1e-6, "{}", score.pixel);
+        assert!((score.combined - (1.5 / 4.0 + 1.0) / 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn refinement_converges_at_the_target_score() {
+        let mut refinement = job(5, 0.8);
+        assert!(!record(&mut refinement, 0.5).1);
+        assert_eq!(refinement.status, RefinementStatus::Running);
+        // Reaching the target exactly is enough
+        let (best, ended) = record(&mut refinement, 0.8);
+        assert!(ended);
+        assert_eq!(refinement.status, RefinementStatus::Converged);
+        assert_eq!(refinement.best_regenerated_image_id, Some(best));
+        assert_eq!(refinement.iterations.len(), 2);
+    }
+
+    #[test]
+    fn refinement_is_exhausted_after_the_last_iteration() {
+        let mut refinement = job(3, 0.9);
+        let (best, _) = record(&mut refinement, 0.6);
+        assert!(!record(&mut refinement, 0.4).1);
+        // A later tie doesn't replace the best image
+        let (_, ended) = record(&mut refinement, 0.6);
+        assert!(ended);
+        assert_eq!(refinement.status, RefinementStatus::Exhausted);
+        assert_eq!(refinement.best_regenerated_image_id, Some(best));
+    }
+
+    #[test]
+    fn refinement_prefers_converging_on_its_last_iteration() {
+        let mut refinement = job(1, 0.5);
+        let (best, ended) = record(&mut refinement, 0.7);
+        assert!(ended);
+        assert_eq!(refinement.status, RefinementStatus::Converged);
+        assert_eq!(refinement.best_regenerated_image_id, Some(best));
+
+        // With a single iteration below the target, it is still the best
+        let mut refinement = job(1, 1.0);
+        let (best, ended) = record(&mut refinement, 0.2);
+        assert!(ended);
+        assert_eq!(refinement.status, RefinementStatus::Exhausted);
+        assert_eq!(refinement.best_regenerated_image_id, Some(best));
+    }
+
+    #[test]
+    fn diff_of_identical_texts_is_one_run() {
+        assert_eq!(
+            TextChange::diff("a red  barn\n", " a red barn"),
+            [equal("a red barn")]
+        );
+        assert_eq!(TextChange::diff("", "  "), []);
+    }
+
+    #[test]
+    fn diff_from_or_to_nothing() {
+        assert_eq!(TextChange::diff("", "a red barn"), [added("a red barn")]);
+        assert_eq!(TextChange::diff("a red barn", ""), [removed("a red barn")]);
+    }
+
+    #[test]
+    fn diff_merges_runs_around_common_words() {
+        assert_eq!(
+            TextChange::diff(
+                "a red barn at noon in watercolor",
+                "a big red barn at dusk in oils"
+            ),
+            [
+                equal("a"),
+                added("big"),
+                equal("red barn at"),
+                removed("noon"),
+                added("dusk"),
+                equal("in"),
+                removed("watercolor"),
+                added("oils"),
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_keeps_the_longest_common_subsequence() {
+        // "b c" is kept over "a", the longer of the two possible matches
+        assert_eq!(
+            TextChange::diff("a b c", "b c a"),
+            [removed("a"), equal("b c"), added("a")]
+        );
+        assert_eq!(
+            TextChange::diff("Barn barn", "barn"),
+            [removed("Barn"), equal("barn")]
+        );
+    }
+
+    #[test]
+    fn long_diffs_fall_back_to_replacing_the_middle() {
+        let words = |prefix: &str| {
+            (0..1500)
+                .map(|i| format!("{}{}", prefix, i))
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        let changes = TextChange::diff(
+            &format!("A barn {} at dusk", words("old")),
+            &format!("A barn {} at dusk", words("new")),
+        );
+        assert_eq!(
+            changes,
+            [
+                equal("A barn"),
+                removed(&words("old")),
+                added(&words("new")),
+                equal("at dusk")
+            ]
+        );
+    }
+}
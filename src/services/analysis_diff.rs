@@ -0,0 +1,413 @@
+// src/services/analysis_diff.rs
+use std::collections::HashSet;
+
+use crate::models::{
+    AnalysisDiff, AttributeDrift, Color, ColorDrift, ImageRegion, RawAnalysis, RegionMatch,
+    RegionSummary,
+};
+use crate::services::image_processor::{delta_e, to_lab};
+
+/// Regions pair up only when description and position together score at
+/// least this much.
+const MATCH_THRESHOLD: f32 = 0.25;
+/// A palette this far off (CIE76 ΔE) counts as no match at all.
+const MAX_PALETTE_DELTA: f32 = 50.0;
+
+const STOPWORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "by", "for", "from", "in", "is", "it", "of", "on", "or",
+    "the", "to", "with",
+];
+
+/// Compares the analysis of a regenerated image with the analysis of the
+/// original it was regenerated from.
+pub fn diff(original: &RawAnalysis, regenerated: &RawAnalysis) -> AnalysisDiff {
+    let (og, rg) = (&original.global_attributes, &regenerated.global_attributes);
+    let style = attribute(&og.style, &rg.style);
+    let mood = attribute(&og.mood, &rg.mood);
+    let lighting = attribute(&og.lighting, &rg.lighting);
+    let perspective = attribute(&og.perspective, &rg.perspective);
+    let layout = attribute(
+        &original.composition.layout,
+        &regenerated.composition.layout,
+    );
+
+    let (matched_regions, missing_regions, added_regions) =
+        match_regions(&original.regions, &regenerated.regions);
+    let palette = palette_drift(&og.dominant_colors, &rg.dominant_colors);
+    let palette_delta = weighted_delta(&palette);
+
+    let attributes = [&style, &mood, &lighting, &perspective, &layout]
+        .iter()
+        .map(|a| a.similarity)
+        .sum::<f32>()
+        / 5.0;
+    let regions = region_recall(&matched_regions, &missing_regions);
+    let colors = palette_delta.map_or(0.0, |d| 1.0 - (d / MAX_PALETTE_DELTA).min(1.0));
+
+    AnalysisDiff {
+        score: 0.4 * attributes + 0.4 * regions + 0.2 * colors,
+        style,
+        mood,
+        lighting,
+        perspective,
+        layout,
+        matched_regions,
+        missing_regions,
+        added_regions,
+        palette,
+        palette_delta,
+    }
+}
+
+fn attribute(original: &str, regenerated: &str) -> AttributeDrift {
+    AttributeDrift {
+        original: original.to_string(),
+        regenerated: regenerated.to_string(),
+        similarity: word_overlap(original, regenerated),
+    }
+}
+
+fn words(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .filter(|w| !STOPWORDS.contains(&w.as_str()))
+        .collect()
+}
+
+/// Jaccard similarity of the words in `a` and `b`, ignoring case and
+/// stopwords. Two empty descriptions agree.
+fn word_overlap(a: &str, b: &str) -> f32 {
+    let (a, b) = (words(a), words(b));
+    let union = a.union(&b).count();
+    if union == 0 {
+        return 1.0;
+    }
+    a.intersection(&b).count() as f32 / union as f32
+}
+
+fn summary(region: &ImageRegion) -> RegionSummary {
+    RegionSummary {
+        id: region.id.clone(),
+        object_description: region.object_description.clone(),
+        importance_score: region.importance_score,
+        coordinates: region.coordinates.clone(),
+    }
+}
+
+/// Pairs regions greedily, best combined score first. Descriptions weigh
+/// more than position, since the regenerated composition may shift.
+fn match_regions(
+    original: &[ImageRegion],
+    regenerated: &[ImageRegion],
+) -> (Vec<RegionMatch>, Vec<RegionSummary>, Vec<RegionSummary>) {
+    let mut candidates = Vec::new();
+    for (i, a) in original.iter().enumerate() {
+        for (j, b) in regenerated.iter().enumerate() {
+            let similarity = word_overlap(&a.object_description, &b.object_description);
+            let overlap = a.coordinates.overlap(&b.coordinates);
+            let score = 0.6 * similarity + 0.4 * overlap;
+            if score >= MATCH_THRESHOLD {
+                candidates.push((score, i, j, similarity, overlap));
+            }
+        }
+    }
+    candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    let mut original_used = vec![false; original.len()];
+    let mut regenerated_used = vec![false; regenerated.len()];
+    let mut matched = Vec::new();
+    for (_, i, j, similarity, overlap) in candidates {
+        if original_used[i] || regenerated_used[j] {
+            continue;
+        }
+        original_used[i] = true;
+        regenerated_used[j] = true;
+        matched.push(RegionMatch {
+            original: summary(&original[i]),
+            regenerated: summary(&regenerated[j]),
+            similarity,
+            overlap,
+        });
+    }
+
+    let unused = |regions: &[ImageRegion], used: &[bool]| {
+        regions
+            .iter()
+            .zip(used)
+            .filter(|(_, used)| !**used)
+            .map(|(region, _)| summary(region))
+            .collect()
+    };
+    (
+        matched,
+        unused(original, &original_used),
+        unused(regenerated, &regenerated_used),
+    )
+}
+
+/// Share of the original's importance carried by matched regions, with
+/// each match counted by how well it agrees.
+fn region_recall(matched: &[RegionMatch], missing: &[RegionSummary]) -> f32 {
+    let importance = |r: &RegionSummary| r.importance_score.max(0.0);
+    let total: f32 = matched.iter().map(|m| importance(&m.original)).sum::<f32>()
+        + missing.iter().map(importance).sum::<f32>();
+    if total <= 0.0 {
+        return if missing.is_empty() { 1.0 } else { 0.0 };
+    }
+    matched
+        .iter()
+        .map(|m| importance(&m.original) * (0.6 * m.similarity + 0.4 * m.overlap))
+        .sum::<f32>()
+        / total
+}
+
+fn palette_drift(original: &[Color], regenerated: &[Color]) -> Vec<ColorDrift> {
+    if regenerated.is_empty() {
+        return Vec::new();
+    }
+    let lab = |c: &Color| to_lab([c.rgb.0, c.rgb.1, c.rgb.2]);
+    let palette: Vec<_> = regenerated.iter().map(|c| (c, lab(c))).collect();
+    original
+        .iter()
+        .map(|color| {
+            let target = lab(color);
+            let (closest, delta) = palette
+                .iter()
+                .map(|(c, l)| (*c, delta_e(&target, l)))
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .expect("palette is not empty");
+            ColorDrift {
+                original: color.hex.clone(),
+                closest: closest.hex.clone(),
+                delta_e: delta as f32,
+                percentage: color.percentage,
+            }
+        })
+        .collect()
+}
+
+/// Mean ΔE weighted by share, falling back to equal weights when no shares
+/// were reported.
+fn weighted_delta(palette: &[ColorDrift]) -> Option<f32> {
+    if palette.is_empty() {
+        return None;
+    }
+    let total: f32 = palette.iter().map(|c| c.percentage.max(0.0)).sum();
+    Some(if total > 0.0 {
+        palette
+            .iter()
+            .map(|c| c.delta_e * c.percentage.max(0.0))
+            .sum::<f32>()
+            / total
+    } else {
+        palette.iter().map(|c| c.delta_e).sum::<f32>() / palette.len() as f32
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{BoundingBox, ColorSource, CompositionAnalysis, GlobalAttributes};
+
+    fn region(id: &str, description: &str, importance: f32, x: f32, y: f32) -> ImageRegion {
+        ImageRegion {
+            id: id.to_string(),
+            coordinates: BoundingBox {
+                x,
+                y,
+                width: 20.0,
+                height: 20.0,
+            },
+            dominant_colors: Vec::new(),
+            object_description: description.to_string(),
+            texture_description: String::new(),
+            importance_score: importance,
+            detail: None,
+        }
+    }
+
+    fn color(rgb: (u8, u8, u8), percentage: f32) -> Color {
+        Color::from_rgb(rgb, percentage, ColorSource::Reported)
+    }
+
+    fn analysis(regions: Vec<ImageRegion>, colors: Vec<Color>) -> RawAnalysis {
+        RawAnalysis {
+            regions,
+            global_attributes: GlobalAttributes {
+                style: "watercolor painting".to_string(),
+                mood: "calm".to_string(),
+                lighting: "golden hour".to_string(),
+                perspective: "eye level".to_string(),
+                dominant_colors: colors,
+            },
+            composition: CompositionAnalysis {
+                layout: "rule of thirds".to_string(),
+                focal_points: Vec::new(),
+                balance: "asymmetric".to_string(),
+                depth_layers: Vec::new(),
+            },
+        }
+    }
+
+    fn approx(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn word_overlap_ignores_case_and_stopwords() {
+        assert_eq!(word_overlap("A Red Barn", "the red barn"), 1.0);
+        assert!(approx(word_overlap("red barn", "red house"), 1.0 / 3.0));
+        assert_eq!(word_overlap("", "the"), 1.0);
+        assert_eq!(word_overlap("barn", ""), 0.0);
+    }
+
+    #[test]
+    fn identical_analyses_score_one() {
+        let original = analysis(
+            vec![
+                region("r1", "a red barn", 0.9, 10.0, 10.0),
+                region("r2", "a green field", 0.5, 50.0, 50.0),
+            ],
+            vec![color((170, 51, 34), 40.0), color((40, 140, 60), 60.0)],
+        );
+        let diff = diff(&original, &original.clone());
+        assert!(approx(diff.score, 1.0), "{}", diff.score);
+        assert_eq!(diff.matched_regions.len(), 2);
+        assert!(diff.missing_regions.is_empty() && diff.added_regions.is_empty());
+        assert_eq!(diff.palette_delta, Some(0.0));
+    }
+
+    #[test]
+    fn matches_regions_by_description_and_position() {
+        let original = vec![
+            region("r1", "a red barn", 0.9, 10.0, 10.0),
+            region("r2", "a green field", 0.5, 50.0, 50.0),
+            region("r3", "a flock of birds", 0.2, 80.0, 0.0),
+        ];
+        let regenerated = vec![
+            // Same object, moved
+            region("x", "red barn", 0.8, 15.0, 10.0),
+            // Same place, unrelated object
+            region("y", "a river", 0.4, 50.0, 50.0),
+        ];
+        let (matched, missing, added) = match_regions(&original, &regenerated);
+
+        let pairs: Vec<(&str, &str)> = matched
+            .iter()
+            .map(|m| (m.original.id.as_str(), m.regenerated.id.as_str()))
+            .collect();
+        assert_eq!(pairs, [("r1", "x"), ("r2", "y")]);
+        assert_eq!(matched[0].similarity, 1.0);
+        assert!(matched[0].overlap > 0.0 && matched[0].overlap < 1.0);
+        assert_eq!(matched[1].similarity, 0.0);
+        assert!(approx(matched[1].overlap, 1.0));
+        assert_eq!(
+            missing.iter().map(|r| r.id.as_str()).collect::<Vec<_>>(),
+            ["r3"]
+        );
+        assert!(added.is_empty());
+    }
+
+    #[test]
+    fn region_recall_weighs_matches_by_importance() {
+        let original = vec![
+            region("r1", "a red barn", 3.0, 10.0, 10.0),
+            region("r2", "a green field", 1.0, 50.0, 50.0),
+        ];
+        let regenerated = vec![region("x", "a red barn", 1.0, 10.0, 10.0)];
+        let (matched, missing, _) = match_regions(&original, &regenerated);
+        assert!(approx(region_recall(&matched, &missing), 0.75));
+    }
+
+    #[test]
+    fn region_recall_without_importance() {
+        let original = vec![region("r1", "a red barn", 0.0, 10.0, 10.0)];
+        let (matched, missing, _) = match_regions(&original, &original);
+        assert_eq!(region_recall(&matched, &missing), 1.0);
+        let (matched, missing, _) = match_regions(&original, &[]);
+        assert_eq!(region_recall(&matched, &missing), 0.0);
+        assert_eq!(region_recall(&[], &[]), 1.0);
+    }
+
+    #[test]
+    fn empty_regions_on_either_side() {
+        let regions = vec![region("r1", "a red barn", 0.9, 10.0, 10.0)];
+
+        let (matched, missing, added) = match_regions(&[], &regions);
+        assert!(matched.is_empty() && missing.is_empty());
+        assert_eq!(added.len(), 1);
+
+        let (matched, missing, added) = match_regions(&regions, &[]);
+        assert!(matched.is_empty() && added.is_empty());
+        assert_eq!(missing.len(), 1);
+    }
+
+    #[test]
+    fn palette_drift_finds_the_closest_colour() {
+        let original = [color((255, 0, 0), 70.0), color((0, 0, 255), 30.0)];
+        let regenerated = [color((250, 5, 5), 50.0), color((0, 0, 255), 50.0)];
+        let palette = palette_drift(&original, &regenerated);
+
+        assert_eq!(palette[0].closest, "#fa0505");
+        assert!(palette[0].delta_e > 0.0);
+        assert_eq!(palette[1].closest, "#0000ff");
+        assert_eq!(palette[1].delta_e, 0.0);
+        assert!(approx(
+            weighted_delta(&palette).unwrap(),
+            palette[0].delta_e * 0.7
+        ));
+    }
+
+    #[test]
+    fn palette_drift_with_empty_palettes() {
+        let colors = [color((255, 0, 0), 100.0)];
+        // Nothing to compare against must not reach the closest-colour lookup
+        assert!(palette_drift(&colors, &[]).is_empty());
+        assert!(palette_drift(&[], &colors).is_empty());
+        assert!(palette_drift(&[], &[]).is_empty());
+        assert_eq!(weighted_delta(&[]), None);
+    }
+
+    #[test]
+    fn weighted_delta_without_shares_weighs_equally() {
+        let original = [color((255, 0, 0), 0.0), color((0, 0, 255), 0.0)];
+        let regenerated = [color((0, 0, 255), 0.0)];
+        let palette = palette_drift(&original, &regenerated);
+        assert!(approx(
+            weighted_delta(&palette).unwrap(),
+            palette[0].delta_e / 2.0
+        ));
+    }
+
+    #[test]
+    fn score_with_nothing_regenerated() {
+        let original = analysis(
+            vec![region("r1", "a red barn", 0.9, 10.0, 10.0)],
+            vec![color((255, 0, 0), 100.0)],
+        );
+        let mut regenerated = analysis(Vec::new(), Vec::new());
+        regenerated.global_attributes.style = "photograph".to_string();
+
+        let diff = diff(&original, &regenerated);
+        assert_eq!(diff.missing_regions.len(), 1);
+        assert!(diff.palette.is_empty());
+        assert_eq!(diff.palette_delta, None);
+        // Four of five attributes agree; regions and colours add nothing
+        assert!(approx(diff.score, 0.4 * 0.8), "{}", diff.score);
+    }
+
+    #[test]
+    fn score_with_empty_original() {
+        let original = analysis(Vec::new(), Vec::new());
+        let regenerated = analysis(
+            vec![region("x", "a red barn", 0.9, 10.0, 10.0)],
+            vec![color((255, 0, 0), 100.0)],
+        );
+
+        let diff = diff(&original, &regenerated);
+        assert_eq!(diff.added_regions.len(), 1);
+        assert!(diff.palette.is_empty());
+        assert!(approx(diff.score, 0.8), "{}", diff.score);
+    }
+}
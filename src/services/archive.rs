@@ -0,0 +1,757 @@
+// src/services/archive.rs
+use crate::errors::SketchyError;
+use crate::models::*;
+use crate::services::{ImageProcessor, Storage};
+use std::collections::BTreeMap;
+use std::io::{Cursor, Read, Write};
+use uuid::Uuid;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// Version of the archive layout written by [`export_session`].
+const FORMAT_VERSION: u32 = 1;
+
+/// Upper bound on the size of an imported archive, both as uploaded and
+/// uncompressed.
+pub const MAX_IMPORT_BYTES: u64 = 512 * 1024 * 1024;
+
+/// Packs a session into a zip archive:
+///
+/// - `manifest.json` with the lineage of every upload and the prompts used
+/// - `images/`, `regenerated/` and `improved/` with the image bytes
+/// - `analyses/` with every analysis as JSON
+pub async fn export_session(
+    storage: &dyn Storage,
+    session_id: &Uuid,
+) -> Result<Vec<u8>, SketchyError> {
+    let mut image_ids = Vec::new();
+    for id in storage
+        .members(&format!("session:{}:images", session_id))
+        .await?
+    {
+        if let Ok(id) = Uuid::parse_str(&id) {
+            image_ids.push(id);
+        }
+    }
+
+    let mut images = Vec::new();
+    for image_id in image_ids {
+        match storage.image_lineage(&image_id).await {
+            Ok(lineage) => images.push(lineage),
+            // Expired since the index was read
+            Err(SketchyError::NotFound(_)) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    images.sort_by_key(|lineage| lineage.image.uploaded_at);
+
+    let mut archive = ArchiveWriter::new();
+    let mut files = BTreeMap::new();
+    let mut heads = BTreeMap::new();
+    for lineage in &images {
+        let image = storage.get_image(&lineage.image.id).await?;
+        let path = archive.add_image("images", &image.id, &image.data)?;
+        files.insert(image.id, path);
+
+        for analysis_lineage in &lineage.analyses {
+            let analysis = &analysis_lineage.analysis;
+            archive.add_file(
+                &format!("analyses/{}.json", analysis.id),
+                &to_json_pretty(analysis)?,
+                CompressionMethod::Deflated,
+            )?;
+
+            for regeneration in &analysis_lineage.regenerations {
+                let regenerated = storage
+                    .get_regenerated(&regeneration.regenerated.id)
+                    .await?;
+                let path = archive.add_image("regenerated", &regenerated.id, &regenerated.data)?;
+                files.insert(regenerated.id, path);
+
+                add_improvements(
+                    storage,
+                    &mut archive,
+                    &mut files,
+                    &regeneration.improvements,
+                )
+                .await?;
+                if !regeneration.improvements.is_empty() {
+                    add_head(storage, &mut heads, ChainRoot::Regenerated(regenerated.id)).await?;
+                }
+            }
+        }
+        add_improvements(storage, &mut archive, &mut files, &lineage.improvements).await?;
+        if !lineage.improvements.is_empty() {
+            add_head(storage, &mut heads, ChainRoot::Upload(image.id)).await?;
+        }
+    }
+
+    let manifest = SessionManifest {
+        format_version: FORMAT_VERSION,
+        session_id: *session_id,
+        exported_at: chrono::Utc::now(),
+        images,
+        files,
+        heads,
+    };
+    archive.add_file(
+        "manifest.json",
+        &to_json_pretty(&manifest)?,
+        CompressionMethod::Deflated,
+    )?;
+    archive.finish()
+}
+
+/// Adds the bytes of every improvement in the trees to the archive.
+async fn add_improvements(
+    storage: &dyn Storage,
+    archive: &mut ArchiveWriter,
+    files: &mut BTreeMap<Uuid, String>,
+    improvements: &[ImprovementNode],
+) -> Result<(), SketchyError> {
+    let mut pending: Vec<&ImprovementNode> = improvements.iter().collect();
+    while let Some(node) = pending.pop() {
+        let improved = storage.get_improved(&node.improved.id).await?;
+        let path = archive.add_image("improved", &improved.id, &improved.data)?;
+        files.insert(improved.id, path);
+        pending.extend(node.children.iter());
+    }
+    Ok(())
+}
+
+/// Records which improvement a chain continues from.
+async fn add_head(
+    storage: &dyn Storage,
+    heads: &mut BTreeMap<Uuid, Uuid>,
+    root: ChainRoot,
+) -> Result<(), SketchyError> {
+    let (ChainRoot::Upload(root_id) | ChainRoot::Regenerated(root_id)) = root;
+    if let Some(head) = storage.improvement_branches(&root).await?.head {
+        heads.insert(root_id, head);
+    }
+    Ok(())
+}
+
+/// Restores a session from an archive made by [`export_session`] into a new
+/// session, giving every entity a fresh id. Images are checked like fresh
+/// uploads rather than trusted as the manifest describes them.
+///
+/// Everything is read and checked before anything is stored, and a session
+/// that fails to store part way is deleted again, so a failed import leaves
+/// nothing behind.
+pub async fn import_session(
+    storage: &dyn Storage,
+    image_processor: &ImageProcessor,
+    archive: Vec<u8>,
+) -> Result<SessionImport, SketchyError> {
+    let mut archive = ArchiveReader::new(archive)?;
+    let manifest: SessionManifest = serde_json::from_slice(&archive.read("manifest.json")?)
+        .map_err(|e| SketchyError::Validation(format!("Invalid manifest: {}", e)))?;
+    if manifest.format_version > FORMAT_VERSION {
+        return Err(SketchyError::Validation(format!(
+            "Unsupported archive format version {}",
+            manifest.format_version
+        )));
+    }
+
+    let mut import = SessionImport {
+        session_id: Uuid::new_v4(),
+        images: 0,
+        analyses: 0,
+        regenerated: 0,
+        improved: 0,
+        ids: BTreeMap::new(),
+    };
+    let staged = import.stage(image_processor, &mut archive, manifest)?;
+    import.images = staged.images.len();
+    import.analyses = staged.analyses.len();
+    import.regenerated = staged.regenerated.len();
+    import.improved = staged.improved.len();
+
+    if let Err(e) = staged.store(storage).await {
+        // Nothing exists yet if the first image failed to store
+        match storage
+            .delete_session(&import.session_id, Some("Import failed".to_string()))
+            .await
+        {
+            Ok(_) | Err(SketchyError::NotFound(_)) => {}
+            Err(cleanup) => log::warn!(
+                "Failed to remove partly imported session {}: {}",
+                import.session_id,
+                cleanup
+            ),
+        }
+        return Err(e);
+    }
+
+    Ok(import)
+}
+
+/// Everything an import stores, read from the archive and checked, in the
+/// order it is stored.
+#[derive(Default)]
+struct StagedImport {
+    images: Vec<ImageUpload>,
+    analyses: Vec<ImageAnalysis>,
+    regenerated: Vec<RegeneratedImage>,
+    /// Parents before their children.
+    improved: Vec<ImprovedImage>,
+    refinements: Vec<Refinement>,
+    /// The improvement each chain continues from.
+    heads: Vec<Uuid>,
+}
+
+impl StagedImport {
+    async fn store(&self, storage: &dyn Storage) -> Result<(), SketchyError> {
+        for image in &self.images {
+            storage.store_image(image).await?;
+        }
+        for analysis in &self.analyses {
+            storage.store_analysis(analysis).await?;
+        }
+        for regenerated in &self.regenerated {
+            storage.store_regenerated(regenerated).await?;
+        }
+        for improved in &self.improved {
+            storage.store_improved(improved).await?;
+        }
+        for refinement in &self.refinements {
+            storage.store_refinement(refinement).await?;
+        }
+        // Storing a step makes it the head, so restore the real ones last
+        for head in &self.heads {
+            if let Some(improved) = self.improved.iter().find(|improved| improved.id == *head) {
+                storage.set_improvement_head(improved).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SessionImport {
+    /// Reads and checks every entity in the archive, moving it to its new
+    /// id in this session.
+    fn stage(
+        &mut self,
+        image_processor: &ImageProcessor,
+        archive: &mut ArchiveReader,
+        manifest: SessionManifest,
+    ) -> Result<StagedImport, SketchyError> {
+        let mut staged = StagedImport::default();
+
+        for lineage in manifest.images {
+            let mut image = lineage.image;
+            let data = archive.read_entity(&manifest.files, &image.id)?;
+            image_processor
+                .validate_image(&data)
+                .map_err(|e| invalid_image(&image.id, e))?;
+            image.data = image_processor
+                .resize_if_needed(&data, 2048)
+                .map_err(|e| invalid_image(&image.id, e))?;
+            image.size = image.data.len();
+            image.content_type = image::guess_format(&image.data)
+                .map(|format| format.to_mime_type().to_string())
+                .unwrap_or(image.content_type);
+            let exported_id = image.id;
+            image.id = self.remap(image.id);
+            image.session_id = self.session_id;
+
+            for analysis_lineage in lineage.analyses {
+                let mut analysis = analysis_lineage.analysis;
+                analysis.id = self.remap(analysis.id);
+                analysis.image_id = image.id;
+
+                for regeneration in analysis_lineage.regenerations {
+                    let mut regenerated = regeneration.regenerated;
+                    regenerated.data = archive.read_entity(&manifest.files, &regenerated.id)?;
+                    regenerated.format = checked_format(
+                        image_processor,
+                        &regenerated.id,
+                        &regenerated.data,
+                        regenerated.format,
+                    )?;
+                    let exported_id = regenerated.id;
+                    regenerated.id = self.remap(regenerated.id);
+                    regenerated.analysis_id = analysis.id;
+                    if let Some(step) = &mut regenerated.refinement {
+                        step.refinement_id = self.remap(step.refinement_id);
+                    }
+
+                    let chain = self.stage_improvements(
+                        image_processor,
+                        archive,
+                        &manifest.files,
+                        regeneration.improvements,
+                        ChainRoot::Regenerated(regenerated.id),
+                    )?;
+                    staged
+                        .heads
+                        .extend(self.chain_head(&chain, &manifest.heads, &exported_id));
+                    staged.improved.extend(chain);
+                    staged.regenerated.push(regenerated);
+                }
+
+                for mut refinement in analysis_lineage.refinements {
+                    refinement.id = self.remap(refinement.id);
+                    refinement.analysis_id = analysis.id;
+                    for iteration in &mut refinement.iterations {
+                        iteration.regenerated_image_id = self.remap(iteration.regenerated_image_id);
+                    }
+                    refinement.best_regenerated_image_id = refinement
+                        .best_regenerated_image_id
+                        .map(|id| self.remap(id));
+                    // Nothing resumes the job here
+                    if refinement.status == RefinementStatus::Running {
+                        refinement.status = RefinementStatus::Failed;
+                        refinement.error = Some("Exported before it finished".to_string());
+                    }
+                    staged.refinements.push(refinement);
+                }
+                staged.analyses.push(analysis);
+            }
+
+            // Improvements made to the upload itself
+            let chain = self.stage_improvements(
+                image_processor,
+                archive,
+                &manifest.files,
+                lineage.improvements,
+                ChainRoot::Upload(image.id),
+            )?;
+            staged
+                .heads
+                .extend(self.chain_head(&chain, &manifest.heads, &exported_id));
+            staged.improved.extend(chain);
+            staged.images.push(image);
+        }
+
+        Ok(staged)
+    }
+
+    /// Reads improvement trees and moves them under the upload or
+    /// regenerated image they now start from, parents before their children.
+    fn stage_improvements(
+        &mut self,
+        image_processor: &ImageProcessor,
+        archive: &mut ArchiveReader,
+        files: &BTreeMap<Uuid, String>,
+        improvements: Vec<ImprovementNode>,
+        root: ChainRoot,
+    ) -> Result<Vec<ImprovedImage>, SketchyError> {
+        let mut chain = Vec::new();
+        let mut pending: Vec<(ImprovementNode, Option<Uuid>)> =
+            improvements.into_iter().map(|node| (node, None)).collect();
+        while let Some((node, parent_id)) = pending.pop() {
+            let mut improved = node.improved;
+            improved.data = archive.read_entity(files, &improved.id)?;
+            image_processor
+                .validate_image(&improved.data)
+                .map_err(|e| invalid_image(&improved.id, e))?;
+            improved.id = self.remap(improved.id);
+            (improved.image_id, improved.regenerated_image_id) = match root {
+                ChainRoot::Upload(id) => (Some(id), None),
+                ChainRoot::Regenerated(id) => (None, Some(id)),
+            };
+            improved.parent_improved_id = parent_id;
+
+            pending.extend(
+                node.children
+                    .into_iter()
+                    .map(|child| (child, Some(improved.id))),
+            );
+            chain.push(improved);
+        }
+        Ok(chain)
+    }
+
+    /// The head to restore for an imported chain. Archives from before
+    /// heads were exported continue from the latest step, as an unset head
+    /// does.
+    fn chain_head(
+        &self,
+        chain: &[ImprovedImage],
+        heads: &BTreeMap<Uuid, Uuid>,
+        exported_root: &Uuid,
+    ) -> Option<Uuid> {
+        heads
+            .get(exported_root)
+            .and_then(|head| self.ids.get(head))
+            .copied()
+            .filter(|head| chain.iter().any(|improved| improved.id == *head))
+            .or_else(|| {
+                chain
+                    .iter()
+                    .max_by_key(|improved| improved.created_at)
+                    .map(|improved| improved.id)
+            })
+    }
+
+    /// The fresh id for an entity from the archive.
+    fn remap(&mut self, id: Uuid) -> Uuid {
+        *self.ids.entry(id).or_insert_with(Uuid::new_v4)
+    }
+}
+
+struct ArchiveWriter {
+    zip: ZipWriter<Cursor<Vec<u8>>>,
+}
+
+impl ArchiveWriter {
+    fn new() -> Self {
+        Self {
+            zip: ZipWriter::new(Cursor::new(Vec::new())),
+        }
+    }
+
+    /// Adds image bytes under `dir`, named after the id with an extension
+    /// matching the format, and returns the path used.
+    fn add_image(&mut self, dir: &str, id: &Uuid, data: &[u8]) -> Result<String, SketchyError> {
+        let path = format!("{}/{}.{}", dir, id, file_extension(data));
+        // Images are already compressed
+        self.add_file(&path, data, CompressionMethod::Stored)?;
+        Ok(path)
+    }
+
+    fn add_file(
+        &mut self,
+        path: &str,
+        data: &[u8],
+        compression: CompressionMethod,
+    ) -> Result<(), SketchyError> {
+        let options = SimpleFileOptions::default()
+            .compression_method(compression)
+            .large_file(data.len() as u64 >= u32::MAX as u64);
+        self.zip
+            .start_file(path, options)
+            .map_err(|e| SketchyError::Serialization(e.to_string()))?;
+        self.zip
+            .write_all(data)
+            .map_err(|e| SketchyError::Serialization(e.to_string()))
+    }
+
+    fn finish(self) -> Result<Vec<u8>, SketchyError> {
+        self.zip
+            .finish()
+            .map(Cursor::into_inner)
+            .map_err(|e| SketchyError::Serialization(e.to_string()))
+    }
+}
+
+struct ArchiveReader {
+    zip: ZipArchive<Cursor<Vec<u8>>>,
+    /// Uncompressed bytes that may still be read before the import is refused.
+    remaining: u64,
+}
+
+impl ArchiveReader {
+    fn new(data: Vec<u8>) -> Result<Self, SketchyError> {
+        let zip = ZipArchive::new(Cursor::new(data))
+            .map_err(|e| SketchyError::Validation(format!("Invalid archive: {}", e)))?;
+        Ok(Self {
+            zip,
+            remaining: MAX_IMPORT_BYTES,
+        })
+    }
+
+    fn read(&mut self, path: &str) -> Result<Vec<u8>, SketchyError> {
+        let file = self.zip.by_name(path).map_err(|e| {
+            SketchyError::Validation(format!("Archive entry '{}' unreadable: {}", path, e))
+        })?;
+        if file.size() > self.remaining {
+            return Err(SketchyError::Validation(format!(
+                "Archive exceeds the {} byte import limit",
+                MAX_IMPORT_BYTES
+            )));
+        }
+
+        // The declared size can't be trusted, so cap the read as well
+        let mut data = Vec::new();
+        file.take(self.remaining + 1)
+            .read_to_end(&mut data)
+            .map_err(|e| SketchyError::Validation(format!("Invalid archive: {}", e)))?;
+        if data.len() as u64 > self.remaining {
+            return Err(SketchyError::Validation(format!(
+                "Archive exceeds the {} byte import limit",
+                MAX_IMPORT_BYTES
+            )));
+        }
+        self.remaining -= data.len() as u64;
+        Ok(data)
+    }
+
+    /// Reads the bytes the manifest lists for an entity.
+    fn read_entity(
+        &mut self,
+        files: &BTreeMap<Uuid, String>,
+        id: &Uuid,
+    ) -> Result<Vec<u8>, SketchyError> {
+        let path = files.get(id).ok_or_else(|| {
+            SketchyError::Validation(format!("Manifest lists no file for '{}'", id))
+        })?;
+        self.read(path)
+    }
+}
+
+fn file_extension(data: &[u8]) -> &'static str {
+    match image::guess_format(data) {
+        Ok(format) => format.extensions_str().first().copied().unwrap_or("bin"),
+        Err(_) if data.trim_ascii_start().starts_with(b"<") => "svg",
+        Err(_) => "bin",
+    }
+}
+
+/// Works out the format of a regenerated image from its bytes, refusing
+/// anything that doesn't decode or doesn't match the declared kind.
+fn checked_format(
+    image_processor: &ImageProcessor,
+    id: &Uuid,
+    data: &[u8],
+    declared: ImageFormat,
+) -> Result<ImageFormat, SketchyError> {
+    match declared {
+        ImageFormat::Vector { format } => {
+            let is_svg = std::str::from_utf8(data)
+                .is_ok_and(|text| text.trim_start().starts_with('<') && text.contains("<svg"));
+            if !is_svg {
+                return Err(SketchyError::Validation(format!(
+                    "Image '{}' in archive is not an SVG",
+                    id
+                )));
+            }
+            Ok(ImageFormat::Vector { format })
+        }
+        ImageFormat::Raster { .. } => {
+            let dimensions = image_processor
+                .validate_image(data)
+                .map_err(|e| invalid_image(id, e))?;
+            Ok(ImageFormat::Raster {
+                format: file_extension(data).to_string(),
+                dimensions,
+            })
+        }
+    }
+}
+
+fn invalid_image(id: &Uuid, error: SketchyError) -> SketchyError {
+    SketchyError::Validation(format!("Image '{}' in archive is invalid: {}", id, error))
+}
+
+fn to_json_pretty<T: serde::Serialize>(value: &T) -> Result<Vec<u8>, SketchyError> {
+    serde_json::to_vec_pretty(value).map_err(|e| SketchyError::Serialization(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::storage::FilesystemStorage;
+    use crate::services::storage::tests::{TempDir, analysis, improvement, regenerated, upload};
+    use image::{DynamicImage, ImageOutputFormat, Rgb, RgbImage};
+    use std::path::Path;
+
+    fn png(rgb: [u8; 3]) -> Vec<u8> {
+        let mut data = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(16, 16, Rgb(rgb)))
+            .write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Png)
+            .unwrap();
+        data
+    }
+
+    /// Number of files under `dir`.
+    fn file_count(dir: &Path) -> usize {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                if path.is_dir() { file_count(&path) } else { 1 }
+            })
+            .sum()
+    }
+
+    /// Copies an archive, replacing each entry with what `change` returns
+    /// for it and leaving it out for `None`.
+    fn rewrite(archive: &[u8], change: impl Fn(&str, Vec<u8>) -> Option<Vec<u8>>) -> Vec<u8> {
+        let mut zip = ZipArchive::new(Cursor::new(archive)).unwrap();
+        let mut writer = ArchiveWriter::new();
+        for i in 0..zip.len() {
+            let mut file = zip.by_index(i).unwrap();
+            let name = file.name().to_string();
+            let mut data = Vec::new();
+            file.read_to_end(&mut data).unwrap();
+            if let Some(data) = change(&name, data) {
+                writer
+                    .add_file(&name, &data, CompressionMethod::Stored)
+                    .unwrap();
+            }
+        }
+        writer.finish().unwrap()
+    }
+
+    /// Exports a session holding one upload.
+    async fn exported_upload() -> (Vec<u8>, ImageUpload) {
+        let dir = TempDir::new();
+        let storage = FilesystemStorage::new(&dir.0).await.unwrap();
+        let mut image = upload();
+        image.data = png([200, 0, 0]);
+        storage.store_image(&image).await.unwrap();
+        let archive = export_session(&storage, &image.session_id).await.unwrap();
+        (archive, image)
+    }
+
+    /// Imports into an empty store, expecting a validation error, and
+    /// checks nothing was stored.
+    async fn import_rejected(archive: Vec<u8>) -> String {
+        let dir = TempDir::new();
+        let storage = FilesystemStorage::new(&dir.0).await.unwrap();
+        let result = import_session(&storage, &ImageProcessor::new(), archive).await;
+        assert_eq!(file_count(&dir.0), 0);
+        match result {
+            Err(SketchyError::Validation(message)) => message,
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn export_then_import_remaps_every_id() {
+        let dir = TempDir::new();
+        let storage = FilesystemStorage::new(&dir.0).await.unwrap();
+
+        let mut image = upload();
+        image.data = png([200, 0, 0]);
+        storage.store_image(&image).await.unwrap();
+        let analysis = analysis(image.id);
+        storage.store_analysis(&analysis).await.unwrap();
+        let mut regenerated = regenerated(analysis.id);
+        regenerated.data = png([0, 0, 200]);
+        storage.store_regenerated(&regenerated).await.unwrap();
+
+        // first -> second and first -> sibling on the regenerated image,
+        // continued from second although sibling is newer
+        let on_regenerated = |parent: Option<&ImprovedImage>, minutes| {
+            let mut improved = improvement(image.id, parent, minutes);
+            improved.image_id = None;
+            improved.regenerated_image_id = Some(regenerated.id);
+            improved.data = png([0, 200, 0]);
+            improved
+        };
+        let first = on_regenerated(None, 1);
+        let second = on_regenerated(Some(&first), 2);
+        let sibling = on_regenerated(Some(&first), 3);
+        // And one step on the upload itself
+        let mut own = improvement(image.id, None, 4);
+        own.data = png([0, 200, 200]);
+        for improved in [&first, &second, &sibling, &own] {
+            storage.store_improved(improved).await.unwrap();
+        }
+        storage.set_improvement_head(&second).await.unwrap();
+
+        let archive = export_session(&storage, &image.session_id).await.unwrap();
+        let import = import_session(&storage, &ImageProcessor::new(), archive)
+            .await
+            .unwrap();
+
+        assert_ne!(import.session_id, image.session_id);
+        assert_eq!(
+            (
+                import.images,
+                import.analyses,
+                import.regenerated,
+                import.improved
+            ),
+            (1, 1, 1, 4)
+        );
+        assert_eq!(import.ids.len(), 7);
+        assert!(import.ids.iter().all(|(old, new)| old != new));
+        let new = |id: &Uuid| import.ids[id];
+
+        assert_eq!(
+            storage
+                .members(&format!("session:{}:images", import.session_id))
+                .await
+                .unwrap(),
+            [new(&image.id).to_string()]
+        );
+        let image_copy = storage.get_image(&new(&image.id)).await.unwrap();
+        assert_eq!(image_copy.session_id, import.session_id);
+        let analysis_copy = storage.get_analysis(&new(&analysis.id)).await.unwrap();
+        assert_eq!(analysis_copy.image_id, new(&image.id));
+        let regenerated_copy = storage
+            .get_regenerated(&new(&regenerated.id))
+            .await
+            .unwrap();
+        assert_eq!(regenerated_copy.analysis_id, new(&analysis.id));
+        assert_eq!(regenerated_copy.data, regenerated.data);
+
+        for (step, parent) in [
+            (&first, None),
+            (&second, Some(&first)),
+            (&sibling, Some(&first)),
+        ] {
+            let copy = storage.get_improved(&new(&step.id)).await.unwrap();
+            assert_eq!(
+                copy.parent_improved_id,
+                parent.map(|parent| new(&parent.id))
+            );
+            assert_eq!(copy.regenerated_image_id, Some(new(&regenerated.id)));
+            assert_eq!(copy.image_id, None);
+        }
+        let own_copy = storage.get_improved(&new(&own.id)).await.unwrap();
+        assert_eq!(own_copy.image_id, Some(new(&image.id)));
+
+        // The chain continues from the same step as the original
+        let branches = storage
+            .improvement_branches(&ChainRoot::Regenerated(new(&regenerated.id)))
+            .await
+            .unwrap();
+        assert_eq!(branches.head, Some(new(&second.id)));
+        let branches = storage
+            .improvement_branches(&ChainRoot::Upload(new(&image.id)))
+            .await
+            .unwrap();
+        assert_eq!(branches.head, Some(new(&own.id)));
+    }
+
+    #[tokio::test]
+    async fn missing_files_are_rejected_before_storing() {
+        let (archive, image) = exported_upload().await;
+        let path = format!("images/{}.png", image.id);
+        let archive = rewrite(&archive, |name, data| (name != path).then_some(data));
+
+        let message = import_rejected(archive).await;
+        assert!(message.contains("unreadable"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn corrupt_images_are_rejected_before_storing() {
+        let (archive, image) = exported_upload().await;
+        let path = format!("images/{}.png", image.id);
+        let archive = rewrite(&archive, |name, data| {
+            Some(if name == path {
+                b"not an image".to_vec()
+            } else {
+                data
+            })
+        });
+
+        let message = import_rejected(archive).await;
+        assert!(message.contains(&image.id.to_string()), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn oversized_archives_are_refused() {
+        let (mut archive, _) = exported_upload().await;
+
+        // Claim the manifest is one byte over the limit in its central
+        // directory header, which is where sizes are read from
+        let header = (0..archive.len())
+            .find(|&i| {
+                archive[i..].starts_with(b"PK\x01\x02")
+                    && archive[i + 46..].starts_with(b"manifest.json")
+            })
+            .unwrap();
+        let size = (MAX_IMPORT_BYTES + 1) as u32;
+        archive[header + 24..header + 28].copy_from_slice(&size.to_le_bytes());
+
+        let message = import_rejected(archive).await;
+        assert!(message.contains("import limit"), "{}", message);
+    }
+}
@@ -1,9 +1,60 @@
 // src/services/image_processor.rs
 use crate::errors::SketchyError;
-use image::{GenericImageView, ImageFormat as ImgFormat};
+use crate::mcp::ImageGenerationProvider;
+use crate::models::{
+    BoundingBox, CanvasExtension, Color, ColorSource, ImageVariant, PixelRect, RawAnalysis,
+    SimilarityReport,
+};
+#[cfg(feature = "avif")]
+use image::codecs::avif::AvifEncoder;
+use image::codecs::webp::{WebPEncoder, WebPQuality};
+use image::imageops::FilterType;
+use image::{
+    ColorType, DynamicImage, GenericImageView, GrayImage, ImageEncoder, ImageFormat as ImgFormat,
+    Luma, Rgb, RgbImage, Rgba, RgbaImage,
+};
+
+/// Quality used for lossy WebP thumbnails, previews and conversions.
+const WEBP_QUALITY: u8 = 80;
+
+/// Side length images are scaled to before comparing them.
+const COMPARE_SIZE: u32 = 256;
+
+/// Number of colours measured for a whole image and for each region.
+const GLOBAL_PALETTE_SIZE: usize = 6;
+const REGION_PALETTE_SIZE: usize = 4;
+
+/// How far (CIE76 ΔE) a reported colour may be from a measured one and still
+/// count as the same. LLM colour estimates are rough, so this is generous.
+const COLOR_MATCH_DELTA_E: f64 = 20.0;
+
+/// Palette entries closer than this (CIE76 ΔE, about the smallest visible
+/// difference) are reported as one colour.
+const PALETTE_MERGE_DELTA_E: f64 = 2.3;
+
+/// Outline colours of regions in an overlay, cycled through in region order.
+const OVERLAY_COLORS: [(u8, u8, u8); 8] = [
+    (230, 25, 75),
+    (60, 180, 75),
+    (255, 225, 25),
+    (0, 130, 200),
+    (245, 130, 48),
+    (145, 30, 180),
+    (70, 240, 240),
+    (240, 50, 230),
+];
 
 pub struct ImageProcessor;
 
+/// Where the area to repaint in an inpaint comes from.
+pub enum MaskSource {
+    /// An uploaded image, white (or transparent) where the image should be
+    /// repainted and black (or opaque) elsewhere. Scaled to the image.
+    Upload(Vec<u8>),
+    /// A region of the analysis, placed on the image by its percentages.
+    Region(BoundingBox),
+}
+
 impl ImageProcessor {
     pub fn new() -> Self {
         Self
@@ -25,6 +76,15 @@ impl ImageProcessor {
         Ok((width, height))
     }
 
+    /// Reads the size of an image from its header without decoding it.
+    pub fn dimensions(&self, data: &[u8]) -> Result<(u32, u32), SketchyError> {
+        image::io::Reader::new(std::io::Cursor::new(data))
+            .with_guessed_format()
+            .map_err(|e| SketchyError::ImageProcessing(format!("Invalid image format: {}", e)))?
+            .into_dimensions()
+            .map_err(|e| SketchyError::ImageProcessing(format!("Invalid image format: {}", e)))
+    }
+
     pub fn resize_if_needed(&self, data: &[u8], max_size: u32) -> Result<Vec<u8>, SketchyError> {
         let img = image::load_from_memory(data)
             .map_err(|e| SketchyError::ImageProcessing(format!("Failed to load image: {}", e)))?;
@@ -81,4 +141,822 @@ impl ImageProcessor {
 
         Ok(output)
     }
+
+    /// Renders a thumbnail, preview or alternative encoding of an image.
+    /// Images are only ever scaled down, never up.
+    pub fn render_variant(
+        &self,
+        data: &[u8],
+        variant: ImageVariant,
+    ) -> Result<Vec<u8>, SketchyError> {
+        let img = image::load_from_memory(data)
+            .map_err(|e| SketchyError::ImageProcessing(format!("Failed to load image: {}", e)))?;
+
+        let img = match variant {
+            ImageVariant::Thumb => fit_within(img, 256),
+            ImageVariant::Preview => fit_within(img, 768),
+            ImageVariant::Webp | ImageVariant::Avif => img,
+        };
+        let rgba = img.to_rgba8();
+        let (width, height) = rgba.dimensions();
+
+        let mut output = Vec::new();
+        let result = match variant {
+            // Lossy WebP is deprecated in `image` in favour of a pure-Rust
+            // lossless encoder, but lossless output is several times larger
+            #[allow(deprecated)]
+            ImageVariant::Thumb | ImageVariant::Preview | ImageVariant::Webp => {
+                WebPEncoder::new_with_quality(&mut output, WebPQuality::lossy(WEBP_QUALITY))
+                    .write_image(&rgba, width, height, ColorType::Rgba8)
+            }
+            #[cfg(feature = "avif")]
+            ImageVariant::Avif => {
+                AvifEncoder::new(&mut output).write_image(&rgba, width, height, ColorType::Rgba8)
+            }
+            #[cfg(not(feature = "avif"))]
+            ImageVariant::Avif => {
+                return Err(SketchyError::Validation(
+                    "AVIF output is not available in this build".to_string(),
+                ));
+            }
+        };
+        result.map_err(|e| {
+            SketchyError::ImageProcessing(format!(
+                "Failed to encode {} variant: {}",
+                variant.as_str(),
+                e
+            ))
+        })?;
+
+        Ok(output)
+    }
+
+    /// Measures how closely `regenerated` matches `original`.
+    /// `reference_colors` are the dominant colours reported for the original.
+    pub fn compare(
+        &self,
+        original: &[u8],
+        regenerated: &[u8],
+        reference_colors: &[Color],
+    ) -> Result<SimilarityReport, SketchyError> {
+        let load = |data: &[u8]| {
+            image::load_from_memory(data)
+                .map(|img| img.resize_exact(COMPARE_SIZE, COMPARE_SIZE, FilterType::Triangle))
+                .map_err(|e| SketchyError::ImageProcessing(format!("Failed to load image: {}", e)))
+        };
+        let original = load(original)?;
+        let regenerated = load(regenerated)?;
+
+        let regenerated_histogram = ColorHistogram::new(&regenerated.to_rgb8());
+        let histogram_distance =
+            1.0 - ColorHistogram::new(&original.to_rgb8()).intersection(&regenerated_histogram);
+
+        Ok(SimilarityReport {
+            ssim: ssim(&original.to_luma8(), &regenerated.to_luma8()) as f32,
+            phash_distance: (phash(&original) ^ phash(&regenerated)).count_ones(),
+            histogram_distance: histogram_distance as f32,
+            dominant_color_delta: regenerated_histogram
+                .palette_delta(reference_colors)
+                .map(|delta| delta as f32),
+            computed_at: chrono::Utc::now(),
+        })
+    }
+
+    /// Checks the colours an LLM reported against the image itself: the
+    /// dominant colours of the whole image and of each region are replaced
+    /// by palettes measured from the pixels, marking the ones the LLM also
+    /// reported as confirmed.
+    pub fn ground_colors(
+        &self,
+        data: &[u8],
+        mut analysis: RawAnalysis,
+    ) -> Result<RawAnalysis, SketchyError> {
+        let img = image::load_from_memory(data)
+            .map_err(|e| SketchyError::ImageProcessing(format!("Failed to load image: {}", e)))?
+            .to_rgb8();
+
+        let global = &mut analysis.global_attributes;
+        global.dominant_colors =
+            reconcile_colors(&global.dominant_colors, &palette(&img, GLOBAL_PALETTE_SIZE));
+
+        for region in &mut analysis.regions {
+            let Some(rect) = region.coordinates.to_pixels(img.width(), img.height()) else {
+                continue;
+            };
+            let crop =
+                image::imageops::crop_imm(&img, rect.x, rect.y, rect.width, rect.height).to_image();
+            region.dominant_colors = reconcile_colors(
+                &region.dominant_colors,
+                &palette(&crop, REGION_PALETTE_SIZE),
+            );
+        }
+
+        Ok(analysis)
+    }
+
+    /// The colour a region's outline is drawn in by [`Self::render_overlay`].
+    pub fn overlay_color(&self, index: usize) -> (u8, u8, u8) {
+        OVERLAY_COLORS[index % OVERLAY_COLORS.len()]
+    }
+
+    /// Draws the outlines of `regions` and markers at `focal_points` (both
+    /// in pixels) over an image and encodes the result as PNG. `regions` is
+    /// indexed like the analysis' regions so each keeps its colour; `None`
+    /// entries are skipped.
+    pub fn render_overlay(
+        &self,
+        data: &[u8],
+        regions: &[Option<PixelRect>],
+        focal_points: &[(u32, u32)],
+    ) -> Result<Vec<u8>, SketchyError> {
+        let mut img = image::load_from_memory(data)
+            .map_err(|e| SketchyError::ImageProcessing(format!("Failed to load image: {}", e)))?
+            .to_rgb8();
+        let thickness = (img.width().min(img.height()) / 200).max(2);
+
+        for (index, rect) in regions.iter().enumerate() {
+            let Some(rect) = rect else {
+                continue;
+            };
+            let (r, g, b) = self.overlay_color(index);
+            draw_outline(&mut img, rect, thickness, Rgb([r, g, b]));
+        }
+
+        // White crosshairs with a black edge stand out on any background
+        let arm = (img.width().min(img.height()) / 40).max(6);
+        for &(x, y) in focal_points {
+            draw_crosshair(&mut img, x, y, arm, thickness + 2, Rgb([0, 0, 0]));
+            draw_crosshair(&mut img, x, y, arm - 1, thickness, Rgb([255, 255, 255]));
+        }
+
+        encode(&DynamicImage::ImageRgb8(img), ImgFormat::Png)
+    }
+
+    /// Cuts a region out of an image. JPEG uploads stay JPEG, everything
+    /// else is returned as PNG.
+    pub fn crop_region(&self, data: &[u8], rect: &PixelRect) -> Result<Vec<u8>, SketchyError> {
+        let img = image::load_from_memory(data)
+            .map_err(|e| SketchyError::ImageProcessing(format!("Failed to load image: {}", e)))?;
+        let format = match image::guess_format(data) {
+            Ok(ImgFormat::Jpeg) => ImgFormat::Jpeg,
+            _ => ImgFormat::Png,
+        };
+        encode(
+            &img.crop_imm(rect.x, rect.y, rect.width, rect.height),
+            format,
+        )
+    }
+
+    /// Prepares the image and mask for an inpaint in the form `provider`
+    /// expects: Stability AI takes a greyscale mask that is white where to
+    /// repaint, OpenAI a PNG pair whose mask is transparent there.
+    pub fn inpaint_inputs(
+        &self,
+        data: &[u8],
+        mask: &MaskSource,
+        provider: &ImageGenerationProvider,
+    ) -> Result<(Vec<u8>, Vec<u8>), SketchyError> {
+        let img = image::load_from_memory(data)
+            .map_err(|e| SketchyError::ImageProcessing(format!("Failed to load image: {}", e)))?;
+        let (width, height) = img.dimensions();
+
+        let mask = match mask {
+            MaskSource::Upload(mask) => uploaded_mask(mask, width, height)?,
+            MaskSource::Region(bbox) => {
+                let rect = bbox.to_pixels(width, height).ok_or_else(|| {
+                    SketchyError::Validation("The region covers less than a pixel".to_string())
+                })?;
+                let mut mask = GrayImage::new(width, height);
+                for y in rect.y..rect.y + rect.height {
+                    for x in rect.x..rect.x + rect.width {
+                        mask.put_pixel(x, y, Luma([255]));
+                    }
+                }
+                mask
+            }
+        };
+        if mask.pixels().all(|p| p.0[0] == 0) {
+            return Err(SketchyError::Validation(
+                "The mask doesn't cover any of the image".to_string(),
+            ));
+        }
+
+        match provider {
+            ImageGenerationProvider::StabilityAI => Ok((
+                data.to_vec(),
+                encode(&DynamicImage::ImageLuma8(mask), ImgFormat::Png)?,
+            )),
+            ImageGenerationProvider::OpenAI => {
+                let alpha_mask = RgbaImage::from_fn(width, height, |x, y| {
+                    let alpha = 255 - mask.get_pixel(x, y).0[0];
+                    Rgba([0, 0, 0, alpha])
+                });
+                Ok((
+                    encode(&DynamicImage::ImageRgba8(img.to_rgba8()), ImgFormat::Png)?,
+                    encode(&DynamicImage::ImageRgba8(alpha_mask), ImgFormat::Png)?,
+                ))
+            }
+        }
+    }
+
+    /// Prepares the image for an outpaint. Stability AI extends the canvas
+    /// itself; for OpenAI the canvas is extended here with a transparent
+    /// border, which its edit endpoint fills in.
+    pub fn outpaint_input(
+        &self,
+        data: &[u8],
+        extension: &CanvasExtension,
+        provider: &ImageGenerationProvider,
+    ) -> Result<Vec<u8>, SketchyError> {
+        match provider {
+            ImageGenerationProvider::StabilityAI => Ok(data.to_vec()),
+            ImageGenerationProvider::OpenAI => {
+                let img = image::load_from_memory(data)
+                    .map_err(|e| {
+                        SketchyError::ImageProcessing(format!("Failed to load image: {}", e))
+                    })?
+                    .to_rgba8();
+                let mut canvas = RgbaImage::new(
+                    img.width() + extension.left + extension.right,
+                    img.height() + extension.up + extension.down,
+                );
+                image::imageops::replace(
+                    &mut canvas,
+                    &img,
+                    extension.left as i64,
+                    extension.up as i64,
+                );
+                encode(&DynamicImage::ImageRgba8(canvas), ImgFormat::Png)
+            }
+        }
+    }
+}
+
+/// Reads an uploaded mask as greyscale, 255 where to repaint. Masks with
+/// transparency mark the area to repaint by it, as OpenAI masks do;
+/// otherwise light pixels mark it. The mask is stretched to the image.
+fn uploaded_mask(data: &[u8], width: u32, height: u32) -> Result<GrayImage, SketchyError> {
+    let mask = image::load_from_memory(data)
+        .map_err(|e| SketchyError::Validation(format!("Invalid mask image: {}", e)))?;
+    let mask = if mask.dimensions() == (width, height) {
+        mask
+    } else {
+        mask.resize_exact(width, height, FilterType::Nearest)
+    };
+
+    let rgba = mask.to_rgba8();
+    let mask = if rgba.pixels().any(|p| p.0[3] < 128) {
+        GrayImage::from_fn(width, height, |x, y| {
+            let transparent = rgba.get_pixel(x, y).0[3] < 128;
+            Luma([if transparent { 255 } else { 0 }])
+        })
+    } else {
+        let luma = mask.to_luma8();
+        GrayImage::from_fn(width, height, |x, y| {
+            let light = luma.get_pixel(x, y).0[0] >= 128;
+            Luma([if light { 255 } else { 0 }])
+        })
+    };
+    Ok(mask)
+}
+
+fn encode(img: &DynamicImage, format: ImgFormat) -> Result<Vec<u8>, SketchyError> {
+    let mut output = Vec::new();
+    img.write_to(&mut std::io::Cursor::new(&mut output), format)
+        .map_err(|e| SketchyError::ImageProcessing(format!("Failed to encode image: {}", e)))?;
+    Ok(output)
+}
+
+/// Fills every pixel of `img` inside the given rectangle, clipped to the image.
+fn fill_rect(img: &mut RgbImage, x: i64, y: i64, width: i64, height: i64, color: Rgb<u8>) {
+    let x_end = (x + width).min(img.width() as i64);
+    let y_end = (y + height).min(img.height() as i64);
+    for py in y.max(0)..y_end {
+        for px in x.max(0)..x_end {
+            img.put_pixel(px as u32, py as u32, color);
+        }
+    }
+}
+
+/// Draws the outline of `rect` inside its edges, so it stays visible for
+/// regions touching the border of the image.
+fn draw_outline(img: &mut RgbImage, rect: &PixelRect, thickness: u32, color: Rgb<u8>) {
+    let (x, y) = (rect.x as i64, rect.y as i64);
+    let (width, height) = (rect.width as i64, rect.height as i64);
+    let t = (thickness as i64).min(width).min(height);
+    fill_rect(img, x, y, width, t, color);
+    fill_rect(img, x, y + height - t, width, t, color);
+    fill_rect(img, x, y, t, height, color);
+    fill_rect(img, x + width - t, y, t, height, color);
+}
+
+fn draw_crosshair(img: &mut RgbImage, x: u32, y: u32, arm: u32, thickness: u32, color: Rgb<u8>) {
+    let (x, y, arm, t) = (x as i64, y as i64, arm as i64, thickness as i64);
+    fill_rect(img, x - arm, y - t / 2, 2 * arm + 1, t, color);
+    fill_rect(img, x - t / 2, y - arm, t, 2 * arm + 1, color);
+}
+
+/// Median-cut palette of up to `size` colours, most common first. Large
+/// images are scaled down first, which barely changes the result.
+fn palette(img: &RgbImage, size: usize) -> Vec<Color> {
+    const MAX_SIDE: u32 = 128;
+
+    let img = if img.width() > MAX_SIDE || img.height() > MAX_SIDE {
+        let scale = MAX_SIDE as f64 / img.width().max(img.height()) as f64;
+        image::imageops::thumbnail(
+            img,
+            ((img.width() as f64 * scale) as u32).max(1),
+            ((img.height() as f64 * scale) as u32).max(1),
+        )
+    } else {
+        img.clone()
+    };
+    let pixels: Vec<[u8; 3]> = img.pixels().map(|p| p.0).collect();
+    let total = pixels.len();
+    if total == 0 {
+        return Vec::new();
+    }
+
+    // Keep splitting the box whose widest channel spans the most, weighted
+    // by how many pixels it holds, near the median of that channel
+    let mut boxes = vec![pixels];
+    while boxes.len() < size {
+        let Some((index, channel, _)) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, pixels)| pixels.len() > 1)
+            .map(|(index, pixels)| {
+                let (channel, range) = widest_channel(pixels);
+                (index, channel, range as usize * pixels.len())
+            })
+            .filter(|(_, _, spread)| *spread > 0)
+            .max_by_key(|(_, _, spread)| *spread)
+        else {
+            break;
+        };
+
+        let mut pixels = boxes.swap_remove(index);
+        pixels.sort_unstable_by_key(|p| p[channel]);
+        // Split where the value changes closest to the median, so a flat
+        // colour is never cut across two boxes
+        let median = pixels.len() / 2;
+        let changes = |i: &usize| pixels[*i - 1][channel] != pixels[*i][channel];
+        let below = (1..=median).rev().find(changes);
+        let above = (median.max(1)..pixels.len()).find(changes);
+        let split = below
+            .into_iter()
+            .chain(above)
+            .min_by_key(|i| i.abs_diff(median))
+            .unwrap_or(median);
+        let upper = pixels.split_off(split);
+        boxes.push(pixels);
+        boxes.push(upper);
+    }
+
+    // Boxes can still end up with much the same mean, which is one colour
+    let mut merged: Vec<([u64; 3], usize)> = Vec::new();
+    for pixels in &boxes {
+        let mut sum = [0u64; 3];
+        for pixel in pixels {
+            for (sum, value) in sum.iter_mut().zip(pixel) {
+                *sum += *value as u64;
+            }
+        }
+        let lab = to_lab(mean_rgb(&sum, pixels.len()));
+        match merged.iter_mut().find(|(other, count)| {
+            delta_e(&lab, &to_lab(mean_rgb(other, *count))) < PALETTE_MERGE_DELTA_E
+        }) {
+            Some((other, count)) => {
+                for (other, sum) in other.iter_mut().zip(sum) {
+                    *other += sum;
+                }
+                *count += pixels.len();
+            }
+            None => merged.push((sum, pixels.len())),
+        }
+    }
+
+    let mut colors: Vec<Color> = merged
+        .iter()
+        .map(|(sum, count)| {
+            let mean = mean_rgb(sum, *count);
+            Color::from_rgb(
+                (mean[0], mean[1], mean[2]),
+                (*count as f64 / total as f64 * 100.0) as f32,
+                ColorSource::Measured,
+            )
+        })
+        .collect();
+    colors.sort_by(|a, b| b.percentage.total_cmp(&a.percentage));
+    colors
+}
+
+fn mean_rgb(sum: &[u64; 3], count: usize) -> [u8; 3] {
+    sum.map(|s| (s as f64 / count as f64).round() as u8)
+}
+
+/// The channel with the largest spread of values and that spread.
+fn widest_channel(pixels: &[[u8; 3]]) -> (usize, u8) {
+    (0..3)
+        .map(|channel| {
+            let (min, max) = pixels.iter().fold((u8::MAX, u8::MIN), |(min, max), p| {
+                (min.min(p[channel]), max.max(p[channel]))
+            });
+            (channel, max - min)
+        })
+        .max_by_key(|(_, range)| *range)
+        .unwrap_or((0, 0))
+}
+
+/// Uses the measured palette, marking colours the LLM also reported as
+/// confirmed. Reported colours with no measured counterpart are dropped.
+fn reconcile_colors(reported: &[Color], measured: &[Color]) -> Vec<Color> {
+    measured
+        .iter()
+        .map(|color| {
+            let lab = to_lab([color.rgb.0, color.rgb.1, color.rgb.2]);
+            let confirmed = reported.iter().any(|r| {
+                delta_e(&lab, &to_lab([r.rgb.0, r.rgb.1, r.rgb.2])) <= COLOR_MATCH_DELTA_E
+            });
+            Color {
+                source: if confirmed {
+                    ColorSource::Confirmed
+                } else {
+                    ColorSource::Measured
+                },
+                ..color.clone()
+            }
+        })
+        .collect()
+}
+
+/// Mean SSIM over 8x8 windows with a stride of 4.
+fn ssim(a: &GrayImage, b: &GrayImage) -> f64 {
+    const WINDOW: u32 = 8;
+    const STRIDE: usize = 4;
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let n = (WINDOW * WINDOW) as f64;
+    let mut total = 0.0;
+    let mut windows = 0;
+    for y in (0..=a.height() - WINDOW).step_by(STRIDE) {
+        for x in (0..=a.width() - WINDOW).step_by(STRIDE) {
+            let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) =
+                (0.0, 0.0, 0.0, 0.0, 0.0);
+            for dy in 0..WINDOW {
+                for dx in 0..WINDOW {
+                    let pa = a.get_pixel(x + dx, y + dy)[0] as f64;
+                    let pb = b.get_pixel(x + dx, y + dy)[0] as f64;
+                    sum_a += pa;
+                    sum_b += pb;
+                    sum_aa += pa * pa;
+                    sum_bb += pb * pb;
+                    sum_ab += pa * pb;
+                }
+            }
+
+            let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+            let var_a = sum_aa / n - mean_a * mean_a;
+            let var_b = sum_bb / n - mean_b * mean_b;
+            let covariance = sum_ab / n - mean_a * mean_b;
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            windows += 1;
+        }
+    }
+    total / windows as f64
+}
+
+/// DCT-based perceptual hash: the low 8x8 frequencies of a 32x32 greyscale
+/// version, one bit each for whether it is above the median.
+fn phash(img: &DynamicImage) -> u64 {
+    const SIZE: usize = 32;
+    const LOW: usize = 8;
+
+    let small = img
+        .resize_exact(SIZE as u32, SIZE as u32, FilterType::Triangle)
+        .to_luma8();
+    let pixels: Vec<f64> = small.pixels().map(|p| p[0] as f64).collect();
+
+    // Separable 2D DCT-II, rows first, then columns
+    let cosines: Vec<f64> = (0..LOW * SIZE)
+        .map(|i| {
+            let (k, n) = (i / SIZE, i % SIZE);
+            (std::f64::consts::PI / SIZE as f64 * (n as f64 + 0.5) * k as f64).cos()
+        })
+        .collect();
+    let mut rows = vec![0.0; SIZE * LOW];
+    for y in 0..SIZE {
+        for k in 0..LOW {
+            rows[y * LOW + k] = (0..SIZE)
+                .map(|x| pixels[y * SIZE + x] * cosines[k * SIZE + x])
+                .sum();
+        }
+    }
+    let mut coefficients = Vec::with_capacity(LOW * LOW);
+    for ky in 0..LOW {
+        for kx in 0..LOW {
+            coefficients.push(
+                (0..SIZE)
+                    .map(|y| rows[y * LOW + kx] * cosines[ky * SIZE + y])
+                    .sum::<f64>(),
+            );
+        }
+    }
+
+    // The DC term only reflects overall brightness, so leave it out of the median
+    let mut sorted = coefficients[1..].to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let median = sorted[sorted.len() / 2];
+
+    coefficients
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| **c > median)
+        .fold(0u64, |hash, (i, _)| hash | (1 << i))
+}
+
+/// RGB histogram with 8 bins per channel that also tracks the mean colour of
+/// each bin.
+struct ColorHistogram {
+    counts: Vec<u32>,
+    sums: Vec<[u64; 3]>,
+    total: u32,
+}
+
+impl ColorHistogram {
+    const BINS: usize = 8;
+
+    fn new(img: &RgbImage) -> Self {
+        let bins = Self::BINS * Self::BINS * Self::BINS;
+        let mut histogram = Self {
+            counts: vec![0; bins],
+            sums: vec![[0; 3]; bins],
+            total: 0,
+        };
+        for pixel in img.pixels() {
+            let [r, g, b] = pixel.0;
+            let bin =
+                Self::bin(r) * Self::BINS * Self::BINS + Self::bin(g) * Self::BINS + Self::bin(b);
+            histogram.counts[bin] += 1;
+            for (sum, value) in histogram.sums[bin].iter_mut().zip([r, g, b]) {
+                *sum += value as u64;
+            }
+            histogram.total += 1;
+        }
+        histogram
+    }
+
+    fn bin(value: u8) -> usize {
+        value as usize * Self::BINS / 256
+    }
+
+    /// Shared share of pixels per bin, from 0 to 1.
+    fn intersection(&self, other: &ColorHistogram) -> f64 {
+        self.counts
+            .iter()
+            .zip(&other.counts)
+            .map(|(a, b)| (*a as f64 / self.total as f64).min(*b as f64 / other.total as f64))
+            .sum()
+    }
+
+    /// Weighted mean ΔE from each reference colour to the closest bin
+    /// holding at least 1% of the pixels.
+    fn palette_delta(&self, reference: &[Color]) -> Option<f64> {
+        let palette: Vec<[f64; 3]> = self
+            .counts
+            .iter()
+            .zip(&self.sums)
+            .filter(|(count, _)| **count as f64 >= self.total as f64 * 0.01)
+            .map(|(count, sum)| {
+                let mean = sum.map(|s| (s as f64 / *count as f64).round() as u8);
+                to_lab(mean)
+            })
+            .collect();
+        if reference.is_empty() || palette.is_empty() {
+            return None;
+        }
+
+        // Fall back to equal weights when no shares were reported
+        let total_share: f64 = reference.iter().map(|c| c.percentage.max(0.0) as f64).sum();
+        let weight = |color: &Color| {
+            if total_share > 0.0 {
+                color.percentage.max(0.0) as f64 / total_share
+            } else {
+                1.0 / reference.len() as f64
+            }
+        };
+
+        Some(
+            reference
+                .iter()
+                .map(|color| {
+                    let lab = to_lab([color.rgb.0, color.rgb.1, color.rgb.2]);
+                    let closest = palette
+                        .iter()
+                        .map(|p| delta_e(&lab, p))
+                        .fold(f64::INFINITY, f64::min);
+                    closest * weight(color)
+                })
+                .sum(),
+        )
+    }
+}
+
+/// Converts sRGB to CIELAB under a D65 white point.
+pub(crate) fn to_lab(rgb: [u8; 3]) -> [f64; 3] {
+    let linear = rgb.map(|c| {
+        let c = c as f64 / 255.0;
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    });
+    let [r, g, b] = linear;
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+
+    let f = |t: f64| {
+        if t > 216.0 / 24389.0 {
+            t.cbrt()
+        } else {
+            (24389.0 / 27.0 * t + 16.0) / 116.0
+        }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+/// CIE76 colour difference.
+pub(crate) fn delta_e(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| (a - b) * (a - b))
+        .sum::<f64>()
+        .sqrt()
+}
+
+fn fit_within(img: DynamicImage, max_size: u32) -> DynamicImage {
+    if img.width() <= max_size && img.height() <= max_size {
+        img
+    } else {
+        img.thumbnail(max_size, max_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Luma, Rgb};
+
+    /// A PNG of an 8x8 checkerboard over a colour gradient, laid out
+    /// relative to the size so it looks the same at any scale. `inverted`
+    /// swaps the light and dark squares.
+    fn checkerboard(width: u32, height: u32, inverted: bool) -> Vec<u8> {
+        let img = RgbImage::from_fn(width, height, |x, y| {
+            let light = (x * 8 / width + y * 8 / height).is_multiple_of(2);
+            let value = if light != inverted { 230 } else { 20 };
+            Rgb([value, (y * 255 / height) as u8, value])
+        });
+        png(img)
+    }
+
+    fn png(img: RgbImage) -> Vec<u8> {
+        let mut data = Vec::new();
+        DynamicImage::ImageRgb8(img)
+            .write_to(&mut std::io::Cursor::new(&mut data), ImgFormat::Png)
+            .unwrap();
+        data
+    }
+
+    fn solid(width: u32, height: u32, rgb: [u8; 3]) -> Vec<u8> {
+        let img = RgbImage::from_pixel(width, height, Rgb(rgb));
+        png(img)
+    }
+
+    #[test]
+    fn identical_images_are_a_perfect_match() {
+        let image = checkerboard(300, 200, false);
+        let report = ImageProcessor::new().compare(&image, &image, &[]).unwrap();
+        assert!((report.ssim - 1.0).abs() < 1e-6, "{}", report.ssim);
+        assert_eq!(report.phash_distance, 0);
+        assert!(report.histogram_distance.abs() < 1e-6);
+        assert_eq!(report.dominant_color_delta, None);
+    }
+
+    #[test]
+    fn different_images_score_lower() {
+        let report = ImageProcessor::new()
+            .compare(
+                &checkerboard(300, 200, false),
+                &checkerboard(300, 200, true),
+                &[],
+            )
+            .unwrap();
+        assert!(report.ssim < 0.5, "{}", report.ssim);
+        assert!(report.phash_distance > 16, "{}", report.phash_distance);
+
+        let report = ImageProcessor::new()
+            .compare(
+                &checkerboard(300, 200, false),
+                &solid(300, 200, [0, 0, 255]),
+                &[],
+            )
+            .unwrap();
+        assert!(
+            report.histogram_distance > 0.5,
+            "{}",
+            report.histogram_distance
+        );
+    }
+
+    #[test]
+    fn compares_images_of_different_sizes() {
+        let report = ImageProcessor::new()
+            .compare(
+                &checkerboard(300, 200, false),
+                &checkerboard(600, 400, false),
+                &[],
+            )
+            .unwrap();
+        assert!(report.ssim > 0.9, "{}", report.ssim);
+    }
+
+    #[test]
+    fn ssim_of_flat_images() {
+        let a = GrayImage::from_pixel(16, 16, Luma([200]));
+        let b = GrayImage::from_pixel(16, 16, Luma([20]));
+        assert!((ssim(&a, &a) - 1.0).abs() < 1e-9);
+        assert!(ssim(&a, &b) < 0.2);
+    }
+
+    #[test]
+    fn single_colour_image_has_a_one_colour_palette() {
+        for size in [1, 50, 300] {
+            let img = RgbImage::from_pixel(size, size, Rgb([10, 20, 30]));
+            let colors = palette(&img, GLOBAL_PALETTE_SIZE);
+            assert_eq!(colors.len(), 1, "{}", size);
+            assert_eq!(colors[0].rgb, (10, 20, 30));
+            assert_eq!(colors[0].hex, "#0a141e");
+            assert_eq!(colors[0].percentage, 100.0);
+            assert_eq!(colors[0].source, ColorSource::Measured);
+        }
+    }
+
+    #[test]
+    fn palette_splits_at_the_median() {
+        let split = |red_columns: u32| {
+            RgbImage::from_fn(40, 10, move |x, _| {
+                if x < red_columns {
+                    Rgb([200, 0, 0])
+                } else {
+                    Rgb([0, 0, 200])
+                }
+            })
+        };
+
+        let colors = palette(&split(20), GLOBAL_PALETTE_SIZE);
+        let rgbs: Vec<_> = colors.iter().map(|c| (c.rgb, c.percentage)).collect();
+        assert_eq!(rgbs, [((200, 0, 0), 50.0), ((0, 0, 200), 50.0)]);
+
+        // Three quarters red: the median falls inside the red, which still
+        // stays one colour
+        let colors = palette(&split(30), GLOBAL_PALETTE_SIZE);
+        let rgbs: Vec<_> = colors.iter().map(|c| (c.rgb, c.percentage)).collect();
+        assert_eq!(rgbs, [((200, 0, 0), 75.0), ((0, 0, 200), 25.0)]);
+    }
+
+    #[test]
+    fn palette_merges_indistinguishable_colours() {
+        let img = RgbImage::from_fn(40, 10, |x, _| match x {
+            0..15 => Rgb([200, 0, 0]),
+            15..25 => Rgb([202, 0, 0]),
+            _ => Rgb([0, 0, 200]),
+        });
+        let colors = palette(&img, GLOBAL_PALETTE_SIZE);
+        let rgbs: Vec<_> = colors.iter().map(|c| (c.rgb, c.percentage)).collect();
+        assert_eq!(rgbs, [((201, 0, 0), 62.5), ((0, 0, 200), 37.5)]);
+    }
+
+    #[test]
+    fn reconcile_confirms_close_reported_colours() {
+        let measured = [
+            Color::from_rgb((200, 0, 0), 60.0, ColorSource::Measured),
+            Color::from_rgb((0, 0, 200), 40.0, ColorSource::Measured),
+        ];
+        let reported = [
+            Color::from_rgb((210, 10, 5), 50.0, ColorSource::Reported),
+            // Nothing like it was measured, so it is dropped
+            Color::from_rgb((0, 255, 0), 50.0, ColorSource::Reported),
+        ];
+        let colors = reconcile_colors(&reported, &measured);
+        assert_eq!(colors.len(), 2);
+        assert_eq!(colors[0].rgb, (200, 0, 0));
+        assert_eq!(colors[0].percentage, 60.0);
+        assert_eq!(colors[0].source, ColorSource::Confirmed);
+        assert_eq!(colors[1].source, ColorSource::Measured);
+    }
 }
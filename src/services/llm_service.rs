@@ -1,6 +1,6 @@
 // src/services/llm_service.rs
 use crate::errors::SketchyError;
-use crate::mcp::ImageGenerationProvider;
+use crate::mcp::{Capability, ImageGenerationProvider};
 use crate::models::*;
 use base64::{Engine as _, engine::general_purpose};
 use reqwest::{multipart, Client};
@@ -8,53 +8,20 @@ use serde_json::json;
 use std::time::Instant;
 use uuid::Uuid;
 
-pub struct LLMService {
-    openai_key: String,
-    anthropic_key: Option<String>,
-    stability_key: Option<String>,
-    client: Client,
-}
-
-impl LLMService {
-    pub fn new(
-        openai_key: String,
-        anthropic_key: Option<String>,
-        stability_key: Option<String>,
-    ) -> Self {
-        Self {
-            openai_key,
-            anthropic_key,
-            stability_key,
-            client: Client::new(),
-        }
-    }
-
-    pub async fn analyze_image(
-        &self,
-        image_data: &[u8],
-        provider: &str,
-    ) -> Result<ImageAnalysis, SketchyError> {
-        let start = Instant::now();
-
-        match provider {
-            "openai" => self.analyze_with_openai(image_data, start).await,
-            "anthropic" => self.analyze_with_anthropic(image_data, start).await,
-            _ => Err(SketchyError::InvalidProvider(provider.to_string())),
-        }
-    }
+/// Longest prompts the image generation APIs accept, in characters.
+const DALLE_PROMPT_LIMIT: usize = 4000;
+const STABILITY_PROMPT_LIMIT: usize = 10000;
 
-    async fn analyze_with_openai(
-        &self,
-        image_data: &[u8],
-        start: Instant,
-    ) -> Result<ImageAnalysis, SketchyError> {
-        let base64_image = general_purpose::STANDARD.encode(image_data);
+/// How many of an analysis' dominant colours a composed prompt names.
+const MAX_PROMPT_COLORS: usize = 5;
 
-        let analysis_prompt = r#"
+/// Instructions sent with every image analysis request.
+const ANALYSIS_PROMPT: &str = r#"
         Analyze this image in extreme detail for AI image generation. Provide:
 
         1. REGIONS: Identify all distinct regions/objects with:
-           - Exact bounding box coordinates (x, y, width, height as percentages)
+           - Exact bounding box coordinates (x, y of the top-left corner, width, height, all as
+             percentages of the image size from 0 to 100)
            - Dominant colors (hex codes with percentages)
            - Object description (what it is, texture, material)
            - Importance score (0-1)
@@ -68,7 +35,7 @@ impl LLMService {
 
         3. COMPOSITION:
            - Layout type (rule of thirds, centered, etc.)
-           - Focal points (x,y coordinates as percentages)
+           - Focal points (x,y coordinates as percentages of the image size from 0 to 100)
            - Visual balance
            - Depth layers (foreground, midground, background elements)
 
@@ -93,6 +60,154 @@ impl LLMService {
         }
         "#;
 
+pub struct LLMService {
+    openai_key: String,
+    anthropic_key: Option<String>,
+    stability_key: Option<String>,
+    client: Client,
+}
+
+impl LLMService {
+    pub fn new(
+        openai_key: String,
+        anthropic_key: Option<String>,
+        stability_key: Option<String>,
+    ) -> Self {
+        Self {
+            openai_key,
+            anthropic_key,
+            stability_key,
+            client: Client::new(),
+        }
+    }
+
+    /// Every image generation provider with what it can do.
+    pub fn providers(&self) -> Vec<ProviderInfo> {
+        ImageGenerationProvider::ALL
+            .into_iter()
+            .map(|provider| ProviderInfo {
+                capabilities: provider.capabilities().to_vec(),
+                configured: self.is_configured(&provider),
+                provider,
+            })
+            .collect()
+    }
+
+    /// Fails with a capability error unless `provider` supports
+    /// `capability` and has an API key.
+    pub fn check_capability(
+        &self,
+        provider: &ImageGenerationProvider,
+        capability: Capability,
+    ) -> Result<(), SketchyError> {
+        if !provider.supports(capability) {
+            return Err(SketchyError::Capability(format!(
+                "{} can't {}",
+                provider.as_str(),
+                capability.action()
+            )));
+        }
+        if !self.is_configured(provider) {
+            return Err(SketchyError::Capability(format!(
+                "{} can't {} because its API key is not configured",
+                provider.as_str(),
+                capability.action()
+            )));
+        }
+        Ok(())
+    }
+
+    /// The provider improvements use when none is chosen: Stability AI, as
+    /// before one could be chosen, unless it has no key.
+    pub fn default_improve_provider(&self) -> ImageGenerationProvider {
+        if self.is_configured(&ImageGenerationProvider::StabilityAI) {
+            ImageGenerationProvider::StabilityAI
+        } else {
+            ImageGenerationProvider::OpenAI
+        }
+    }
+
+    /// Checks that `provider` can analyse images and revise prompts before
+    /// work that depends on it starts.
+    pub fn check_analysis_provider(&self, provider: &str) -> Result<(), SketchyError> {
+        match provider {
+            "openai" => Ok(()),
+            "anthropic" if self.anthropic_key.is_some() => Ok(()),
+            "anthropic" => Err(SketchyError::LLM(
+                "Anthropic API key not configured".to_string(),
+            )),
+            _ => Err(SketchyError::InvalidProvider(provider.to_string())),
+        }
+    }
+
+    fn is_configured(&self, provider: &ImageGenerationProvider) -> bool {
+        match provider {
+            // Required at startup
+            ImageGenerationProvider::OpenAI => true,
+            ImageGenerationProvider::StabilityAI => self.stability_key.is_some(),
+        }
+    }
+
+    pub async fn analyze_image(
+        &self,
+        image_data: &[u8],
+        provider: &str,
+    ) -> Result<ImageAnalysis, SketchyError> {
+        let start = Instant::now();
+
+        match provider {
+            "openai" => {
+                self.analyze_with_openai(image_data, ANALYSIS_PROMPT, start)
+                    .await
+            }
+            "anthropic" => {
+                self.analyze_with_anthropic(image_data, ANALYSIS_PROMPT, start)
+                    .await
+            }
+            _ => Err(SketchyError::InvalidProvider(provider.to_string())),
+        }
+    }
+
+    /// Analyses a crop of one region of an image. The model is told what the
+    /// region was identified as and asked for finer detail than an analysis
+    /// of the whole image gives. Coordinates in the result are relative to
+    /// the crop.
+    pub async fn analyze_region(
+        &self,
+        crop_data: &[u8],
+        provider: &str,
+        region: &ImageRegion,
+    ) -> Result<ImageAnalysis, SketchyError> {
+        let start = Instant::now();
+        let prompt = format!(
+            r#"
+        This image is a crop of one region of a larger image, identified as:
+        {} ({}).
+
+        Focus only on what is visible in the crop and describe it in finer
+        detail than an analysis of the whole image would: its parts, materials,
+        fine texture, small details and exact colors. Coordinates are relative
+        to this crop. The generation prompt must describe only this region, so
+        it can refine the part of a prompt for the whole image that covers it.
+        {}"#,
+            region.object_description, region.texture_description, ANALYSIS_PROMPT
+        );
+
+        match provider {
+            "openai" => self.analyze_with_openai(crop_data, &prompt, start).await,
+            "anthropic" => self.analyze_with_anthropic(crop_data, &prompt, start).await,
+            _ => Err(SketchyError::InvalidProvider(provider.to_string())),
+        }
+    }
+
+    async fn analyze_with_openai(
+        &self,
+        image_data: &[u8],
+        prompt: &str,
+        start: Instant,
+    ) -> Result<ImageAnalysis, SketchyError> {
+        let base64_image = general_purpose::STANDARD.encode(image_data);
+
         let response = self
             .client
             .post("https://api.openai.com/v1/chat/completions")
@@ -104,7 +219,7 @@ impl LLMService {
                     "content": [
                         {
                             "type": "text",
-                            "text": analysis_prompt
+                            "text": prompt
                         },
                         {
                             "type": "image_url",
@@ -134,12 +249,10 @@ impl LLMService {
         let choice = &result["choices"][0];
 
         // Handle explicit content filtering finish reason
-        if let Some(reason) = choice["finish_reason"].as_str() {
-            if reason == "content_filter" {
-                return Err(SketchyError::LLM(
-                    "Image analysis failed due to OpenAI's content safety filter.".to_string(),
-                ));
-            }
+        if choice["finish_reason"].as_str() == Some("content_filter") {
+            return Err(SketchyError::LLM(
+                "Image analysis failed due to OpenAI's content safety filter.".to_string(),
+            ));
         }
 
         let message = &choice["message"];
@@ -190,6 +303,7 @@ impl LLMService {
     async fn analyze_with_anthropic(
         &self,
         image_data: &[u8],
+        prompt: &str,
         start: Instant,
     ) -> Result<ImageAnalysis, SketchyError> {
         let api_key = self
@@ -199,49 +313,6 @@ impl LLMService {
 
         let base64_image = general_purpose::STANDARD.encode(image_data);
 
-        let analysis_prompt = r#"
-        Analyze this image in extreme detail for AI image generation. Provide:
-
-        1. REGIONS: Identify all distinct regions/objects with:
-           - Exact bounding box coordinates (x, y, width, height as percentages)
-           - Dominant colors (hex codes with percentages)
-           - Object description (what it is, texture, material)
-           - Importance score (0-1)
-
-        2. GLOBAL ATTRIBUTES:
-           - Art style (photorealistic, cartoon, painting style, etc.)
-           - Mood/atmosphere
-           - Lighting (direction, quality, color temperature)
-           - Camera perspective/angle
-           - Overall dominant colors
-
-        3. COMPOSITION:
-           - Layout type (rule of thirds, centered, etc.)
-           - Focal points (x,y coordinates as percentages)
-           - Visual balance
-           - Depth layers (foreground, midground, background elements)
-
-        4. FORENSIC FACIAL RECONSTRUCTION:
-           - If applicable, provide detailed facial features and expressions
-           - Include attributes like age
-           - Skin tone, hair color, eye color, etc.
-           - Any visible scars, tattoos, or distinguishing marks
-           - Facial symmetry and proportions
-
-        5. GENERATION PROMPT:
-           Create a detailed prompt that would recreate this image as closely as possible.
-           Include all visual elements, style, composition, colors, and atmospheric details.
-           Be extremely specific and comprehensive.
-
-        Return as JSON matching this structure:
-        {
-            "regions": [...],
-            "global_attributes": {...},
-            "composition": {...},
-            "generation_prompt": "..."
-        }
-        "#;
-
         let response = self
             .client
             .post("https://api.anthropic.com/v1/messages")
@@ -256,7 +327,7 @@ impl LLMService {
                     "content": [
                         {
                             "type": "text",
-                            "text": analysis_prompt
+                            "text": prompt
                         },
                         {
                             "type": "image",
@@ -315,6 +386,370 @@ impl LLMService {
         })
     }
 
+    /// Builds a generation prompt from the structured fields of an analysis
+    /// instead of the prompt the LLM wrote. DALL-E gets plain sentences;
+    /// Stability gets comma-separated `(token:weight)` terms, with regions
+    /// weighted by importance and colours by their share of the image.
+    pub fn compose_prompt(
+        &self,
+        analysis: &RawAnalysis,
+        provider: &ImageGenerationProvider,
+        sections: &PromptSections,
+    ) -> Result<String, SketchyError> {
+        let global = &analysis.global_attributes;
+        let attribute = |enabled: bool, value: &str| {
+            Some(value.trim().to_string()).filter(|value| enabled && !value.is_empty())
+        };
+        let style = attribute(sections.style, &global.style);
+        let perspective = attribute(sections.perspective, &global.perspective);
+        let mood = attribute(sections.mood, &global.mood);
+        let lighting = attribute(sections.lighting, &global.lighting);
+
+        let mut regions: Vec<&ImageRegion> = if sections.regions {
+            analysis.regions.iter().collect()
+        } else {
+            Vec::new()
+        };
+        regions.sort_by(|a, b| b.importance_score.total_cmp(&a.importance_score));
+        regions.truncate(sections.max_regions);
+        let regions: Vec<(String, f32)> = regions
+            .into_iter()
+            .map(|region| (describe_region(region), region.importance_score))
+            .filter(|(description, _)| !description.is_empty())
+            .collect();
+
+        let mut colors: Vec<&Color> = if sections.palette {
+            global.dominant_colors.iter().collect()
+        } else {
+            Vec::new()
+        };
+        colors.sort_by(|a, b| b.percentage.total_cmp(&a.percentage));
+        // Several shades often share a name; keep the most common of each
+        let mut palette: Vec<(String, f32)> = Vec::new();
+        for color in colors.into_iter().take(MAX_PROMPT_COLORS) {
+            let name = color.name();
+            if !palette.iter().any(|(existing, _)| *existing == name) {
+                palette.push((name, color.percentage));
+            }
+        }
+
+        let (prompt, max_len) = match provider {
+            ImageGenerationProvider::OpenAI => {
+                let mut sentences = Vec::new();
+                if !regions.is_empty() {
+                    let subjects: Vec<String> = regions.into_iter().map(|(d, _)| d).collect();
+                    sentences.push(format!("The scene shows {}.", join_list(&subjects)));
+                }
+                if let Some(style) = style {
+                    sentences.push(format!("Rendered in a {} style.", style));
+                }
+                if let Some(perspective) = perspective {
+                    sentences.push(format!("Camera perspective: {}.", perspective));
+                }
+                if let Some(mood) = mood {
+                    sentences.push(format!("The mood is {}.", mood));
+                }
+                if let Some(lighting) = lighting {
+                    sentences.push(format!("Lighting: {}.", lighting));
+                }
+                if !palette.is_empty() {
+                    let names: Vec<String> = palette.into_iter().map(|(name, _)| name).collect();
+                    sentences.push(format!("Dominant colors: {}.", join_list(&names)));
+                }
+                (sentences.join(" "), DALLE_PROMPT_LIMIT)
+            }
+            ImageGenerationProvider::StabilityAI => {
+                let mut tokens = Vec::new();
+                for (description, importance) in regions {
+                    let weight = 1.0 + 0.5 * importance.clamp(0.0, 1.0);
+                    tokens.push(weighted(&description, weight));
+                }
+                tokens.extend(style.map(|style| format!("{} style", plain(&style))));
+                tokens.extend(perspective.map(|p| format!("{} perspective", plain(&p))));
+                tokens.extend(mood.map(|mood| format!("{} mood", plain(&mood))));
+                tokens.extend(lighting.map(|lighting| format!("{} lighting", plain(&lighting))));
+                for (name, percentage) in palette {
+                    let share = (percentage / 100.0).clamp(0.0, 1.0);
+                    tokens.push(weighted(&format!("{} tones", name), 1.0 + 0.3 * share));
+                }
+                (tokens.join(", "), STABILITY_PROMPT_LIMIT)
+            }
+        };
+
+        if prompt.is_empty() {
+            return Err(SketchyError::Validation(
+                "The selected sections of the analysis are empty".to_string(),
+            ));
+        }
+        Ok(truncate_chars(prompt, max_len))
+    }
+
+    /// Asks an analysis provider to review a user's prompt, against the
+    /// analysis of the image it is meant to recreate if one is given.
+    pub async fn critique_prompt(
+        &self,
+        prompt: &str,
+        analysis: Option<&ImageAnalysis>,
+        target: Option<&ImageGenerationProvider>,
+        provider: &str,
+    ) -> Result<PromptCritique, SketchyError> {
+        let start = Instant::now();
+
+        let reference = match analysis {
+            Some(analysis) => format!(
+                "The prompt is meant to recreate an image analysed as follows:\n{}\n\
+                 List every attribute of the image the prompt leaves out or gets wrong \
+                 in \"missing_attributes\".",
+                describe_analysis(analysis)
+            ),
+            None => {
+                "There is no reference image, so leave \"missing_attributes\" empty.".to_string()
+            }
+        };
+        let target = match target {
+            Some(ImageGenerationProvider::OpenAI) => format!(
+                "Write the suggested prompt for DALL-E: plain descriptive sentences, \
+                 at most {} characters.",
+                DALLE_PROMPT_LIMIT
+            ),
+            Some(ImageGenerationProvider::StabilityAI) => format!(
+                "Write the suggested prompt for Stable Diffusion: comma-separated terms, \
+                 optionally weighted as (term:1.3), at most {} characters.",
+                STABILITY_PROMPT_LIMIT
+            ),
+            None => "Write the suggested prompt as plain descriptive sentences.".to_string(),
+        };
+        let instructions = format!(
+            r#"
+        You are teaching prompt engineering for AI image generation. Review this prompt:
+
+        """
+        {}
+        """
+
+        {}
+
+        Point out words a generator could read more than one way ("ambiguities"),
+        and words that contradict each other, like two lighting setups or styles
+        ("conflicts"). Then rewrite the prompt to fix every issue you found. {}
+
+        Return as JSON matching this structure:
+        {{
+            "summary": "one or two sentences on the prompt overall",
+            "missing_attributes": [{{"attribute": "lighting", "expected": "warm golden hour light"}}],
+            "ambiguities": [{{"terms": ["nice"], "explanation": "..."}}],
+            "conflicts": [{{"terms": ["noon", "moonlit"], "explanation": "..."}}],
+            "suggested_prompt": "..."
+        }}
+        "#,
+            prompt, reference, target
+        );
+
+        let (data, model_used) = self.ask_json(&instructions, provider).await?;
+
+        let suggested_prompt = data["suggested_prompt"]
+            .as_str()
+            .map(str::trim)
+            .filter(|suggestion| !suggestion.is_empty())
+            .ok_or_else(|| SketchyError::LLM("Missing suggested_prompt in critique".to_string()))?
+            .to_string();
+        let issues = |key: &str| -> Vec<PromptIssue> {
+            data[key]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|issue| PromptIssue {
+                    terms: issue["terms"]
+                        .as_array()
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|term| term.as_str().map(str::to_string))
+                        .collect(),
+                    explanation: json_text(&issue["explanation"]),
+                })
+                .filter(|issue| !issue.explanation.is_empty())
+                .collect()
+        };
+        let missing_attributes = match analysis {
+            Some(_) => data["missing_attributes"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|missing| MissingAttribute {
+                    attribute: json_text(&missing["attribute"]),
+                    expected: json_text(&missing["expected"]),
+                })
+                .filter(|missing| !missing.attribute.is_empty())
+                .collect(),
+            None => Vec::new(),
+        };
+
+        Ok(PromptCritique {
+            prompt: prompt.to_string(),
+            analysis_id: analysis.map(|analysis| analysis.id),
+            llm_provider: provider.to_string(),
+            model_used: model_used.to_string(),
+            summary: json_text(&data["summary"]),
+            missing_attributes,
+            ambiguities: issues("ambiguities"),
+            conflicts: issues("conflicts"),
+            suggested_prompt,
+            processing_time_ms: start.elapsed().as_millis() as u64,
+        })
+    }
+
+    /// Rewrites a prompt after the image generated from it came out unlike
+    /// the original, going by what its round trip and pixel comparison
+    /// found.
+    pub async fn revise_prompt(
+        &self,
+        prompt: &str,
+        analysis: &ImageAnalysis,
+        round_trip: &RoundTrip,
+        similarity: &SimilarityReport,
+        target: &ImageGenerationProvider,
+        provider: &str,
+    ) -> Result<PromptRevision, SketchyError> {
+        let (style, max_len) = match target {
+            ImageGenerationProvider::OpenAI => {
+                ("plain descriptive sentences for DALL-E", DALLE_PROMPT_LIMIT)
+            }
+            ImageGenerationProvider::StabilityAI => (
+                "comma-separated terms for Stable Diffusion, optionally weighted as (term:1.3)",
+                STABILITY_PROMPT_LIMIT,
+            ),
+        };
+        let instructions = format!(
+            r#"
+        An image was generated from this prompt to recreate a reference image:
+
+        """
+        {}
+        """
+
+        The reference image was analysed as follows:
+        {}
+
+        Analysing the generated image and comparing it with the reference found:
+        {}
+
+        Rewrite the prompt so the next image comes closer to the reference. Keep
+        what already matches, fix what drifted and add what is missing. Write it
+        as {}, at most {} characters.
+
+        Return as JSON matching this structure:
+        {{
+            "prompt": "...",
+            "rationale": "one or two sentences on what you changed and why"
+        }}
+        "#,
+            prompt,
+            describe_analysis(analysis),
+            describe_drift(&round_trip.diff, similarity),
+            style,
+            max_len
+        );
+
+        let (data, _) = self.ask_json(&instructions, provider).await?;
+
+        let revised = json_text(&data["prompt"]);
+        if revised.is_empty() {
+            return Err(SketchyError::LLM("Missing prompt in revision".to_string()));
+        }
+        Ok(PromptRevision {
+            prompt: truncate_chars(revised, max_len),
+            rationale: json_text(&data["rationale"]),
+        })
+    }
+
+    /// Sends text-only instructions to an analysis provider and parses the
+    /// JSON object it answers with. Returns it with the model used.
+    async fn ask_json(
+        &self,
+        instructions: &str,
+        provider: &str,
+    ) -> Result<(serde_json::Value, &'static str), SketchyError> {
+        let (content, model) = match provider {
+            "openai" => {
+                const MODEL: &str = "gpt-4o";
+                let response = self
+                    .client
+                    .post("https://api.openai.com/v1/chat/completions")
+                    .header("Authorization", format!("Bearer {}", self.openai_key))
+                    .json(&json!({
+                        "model": MODEL,
+                        "messages": [{ "role": "user", "content": instructions }],
+                        "max_tokens": 4096,
+                        "response_format": { "type": "json_object" }
+                    }))
+                    .send()
+                    .await
+                    .map_err(|e| SketchyError::LLM(format!("OpenAI request failed: {}", e)))?;
+                if !response.status().is_success() {
+                    let error_text = response.text().await.unwrap_or_default();
+                    return Err(SketchyError::LLM(format!("OpenAI error: {}", error_text)));
+                }
+                let result: serde_json::Value = response.json().await.map_err(|e| {
+                    SketchyError::LLM(format!("Failed to parse OpenAI response: {}", e))
+                })?;
+                let message = &result["choices"][0]["message"];
+                if let Some(refusal_text) = message["refusal"].as_str() {
+                    return Err(SketchyError::LLM(format!(
+                        "Request refused by OpenAI: {}",
+                        refusal_text
+                    )));
+                }
+                (message["content"].as_str().map(str::to_string), MODEL)
+            }
+            "anthropic" => {
+                const MODEL: &str = "claude-3-5-sonnet-20241022";
+                let api_key = self.anthropic_key.as_ref().ok_or_else(|| {
+                    SketchyError::LLM("Anthropic API key not configured".to_string())
+                })?;
+                let response = self
+                    .client
+                    .post("https://api.anthropic.com/v1/messages")
+                    .header("x-api-key", api_key)
+                    .header("anthropic-version", "2023-06-01")
+                    .header("Content-Type", "application/json")
+                    .json(&json!({
+                        "model": MODEL,
+                        "max_tokens": 4096,
+                        "messages": [{ "role": "user", "content": instructions }]
+                    }))
+                    .send()
+                    .await
+                    .map_err(|e| SketchyError::LLM(format!("Anthropic request failed: {}", e)))?;
+                if !response.status().is_success() {
+                    let error_text = response.text().await.unwrap_or_default();
+                    return Err(SketchyError::LLM(format!(
+                        "Anthropic error: {}",
+                        error_text
+                    )));
+                }
+                let result: serde_json::Value = response.json().await.map_err(|e| {
+                    SketchyError::LLM(format!("Failed to parse Anthropic response: {}", e))
+                })?;
+                (
+                    result["content"][0]["text"].as_str().map(str::to_string),
+                    MODEL,
+                )
+            }
+            _ => return Err(SketchyError::InvalidProvider(provider.to_string())),
+        };
+
+        let content = content
+            .ok_or_else(|| SketchyError::LLM(format!("No content in {} response", provider)))?;
+        // Claude has no JSON mode and may wrap the object in prose
+        let json = match (content.find('{'), content.rfind('}')) {
+            (Some(start), Some(end)) if start < end => &content[start..=end],
+            _ => content.as_str(),
+        };
+        let data = serde_json::from_str(json)
+            .map_err(|e| SketchyError::LLM(format!("Failed to parse {} JSON: {}", provider, e)))?;
+        Ok((data, model))
+    }
+
     pub async fn generate_image(
         &self,
         prompt: &str,
@@ -386,6 +821,10 @@ impl LLMService {
                 cfg_scale: None,
                 seed: None,
             },
+            similarity: None,
+            round_trip: None,
+            refinement: None,
+            template: None,
             created_at: chrono::Utc::now(),
         })
     }
@@ -449,14 +888,39 @@ impl LLMService {
                 cfg_scale: None,
                 seed: None,
             },
+            similarity: None,
+            round_trip: None,
+            refinement: None,
+            template: None,
             created_at: chrono::Utc::now(),
         })
     }
 
+    /// Changes a whole image guided by `prompt`.
     pub async fn improve_image(
+        &self,
+        provider: &ImageGenerationProvider,
+        image_data: &[u8],
+        prompt: &str,
+        params: &ImproveParams,
+    ) -> Result<ImprovedImage, SketchyError> {
+        match provider {
+            ImageGenerationProvider::OpenAI => {
+                self.edit_with_openai(image_data, None, prompt, params)
+                    .await
+            }
+            ImageGenerationProvider::StabilityAI => {
+                self.improve_with_stabilityai(image_data, prompt, params)
+                    .await
+            }
+        }
+    }
+
+    async fn improve_with_stabilityai(
         &self,
         image_data: &[u8],
         prompt: &str,
+        params: &ImproveParams,
     ) -> Result<ImprovedImage, SketchyError> {
         let api_key = self.stability_key.as_ref().ok_or_else(|| {
             SketchyError::LLM("Stability AI API key not configured".to_string())
@@ -464,13 +928,37 @@ impl LLMService {
 
         let image_base64 = general_purpose::STANDARD.encode(image_data);
 
-        let form = multipart::Form::new()
+        let mut form = multipart::Form::new()
             .text("prompt", prompt.to_string())
-            .text("output_format", "png")
-            .text("init_image_mode", "IMAGE_STRENGTH")
-            .text("image_strength", "0.35") // Adjust this value to control the influence of the original image
+            .text("output_format", params.output_format.as_str())
+            .text("init_image_mode", params.init_image_mode.as_str())
             .text("init_image", image_base64);
 
+        form = match params.init_image_mode {
+            InitImageMode::ImageStrength => {
+                form.text("image_strength", params.image_strength.to_string())
+            }
+            InitImageMode::StepSchedule => {
+                let form = form.text(
+                    "step_schedule_start",
+                    params.step_schedule_start.to_string(),
+                );
+                match params.step_schedule_end {
+                    Some(end) => form.text("step_schedule_end", end.to_string()),
+                    None => form,
+                }
+            }
+        };
+        if let Some(negative_prompt) = &params.negative_prompt {
+            form = form.text("negative_prompt", negative_prompt.clone());
+        }
+        if let Some(seed) = params.seed {
+            form = form.text("seed", seed.to_string());
+        }
+        if let Some(style) = &params.style_preset {
+            form = form.text("style_preset", style.clone());
+        }
+
         let response = self
             .client
             .post("https://api.stability.ai/v2beta/stable-image/generate/core")
@@ -489,6 +977,16 @@ impl LLMService {
             )));
         }
 
+        // Record the seed Stability picked so the result can be reproduced
+        let mut params = params.clone();
+        if params.seed.is_none() {
+            params.seed = response
+                .headers()
+                .get("seed")
+                .and_then(|seed| seed.to_str().ok())
+                .and_then(|seed| seed.parse().ok());
+        }
+
         let image_data = response
             .bytes()
             .await
@@ -497,9 +995,210 @@ impl LLMService {
 
         Ok(ImprovedImage {
             id: Uuid::new_v4(),
-            regenerated_image_id: Uuid::new_v4(), // Will be set by handler
+            regenerated_image_id: None, // Set by the handler
+            image_id: None,
+            parent_improved_id: None,
+            step: 1,
+            data: image_data,
+            prompt_used: prompt.to_string(),
+            provider: ImageGenerationProvider::StabilityAI,
+            params,
+            edit: None,
+            created_at: chrono::Utc::now(),
+        })
+    }
+
+    /// Repaints the masked area of an image. `image` and `mask` must be in
+    /// the form `ImageProcessor::inpaint_inputs` prepares for `provider`.
+    pub async fn inpaint(
+        &self,
+        provider: &ImageGenerationProvider,
+        image: &[u8],
+        mask: &[u8],
+        prompt: &str,
+        params: &ImproveParams,
+    ) -> Result<ImprovedImage, SketchyError> {
+        match provider {
+            ImageGenerationProvider::OpenAI => {
+                self.edit_with_openai(image, Some(mask), prompt, params)
+                    .await
+            }
+            ImageGenerationProvider::StabilityAI => {
+                let mut form = multipart::Form::new()
+                    .part("image", file_part(image, "image")?)
+                    .part("mask", file_part(mask, "mask")?)
+                    .text("prompt", prompt.to_string());
+                if let Some(negative_prompt) = &params.negative_prompt {
+                    form = form.text("negative_prompt", negative_prompt.clone());
+                }
+                self.edit_with_stabilityai("inpaint", form, prompt, params)
+                    .await
+            }
+        }
+    }
+
+    /// Extends the canvas of an image and paints the new area. `image` must
+    /// be in the form `ImageProcessor::outpaint_input` prepares for `provider`.
+    pub async fn outpaint(
+        &self,
+        provider: &ImageGenerationProvider,
+        image: &[u8],
+        extension: &CanvasExtension,
+        prompt: &str,
+        params: &ImproveParams,
+    ) -> Result<ImprovedImage, SketchyError> {
+        match provider {
+            ImageGenerationProvider::OpenAI => {
+                self.edit_with_openai(image, None, prompt, params).await
+            }
+            ImageGenerationProvider::StabilityAI => {
+                let form = multipart::Form::new()
+                    .part("image", file_part(image, "image")?)
+                    .text("prompt", prompt.to_string())
+                    .text("left", extension.left.to_string())
+                    .text("right", extension.right.to_string())
+                    .text("up", extension.up.to_string())
+                    .text("down", extension.down.to_string());
+                self.edit_with_stabilityai("outpaint", form, prompt, params)
+                    .await
+            }
+        }
+    }
+
+    /// Sends `form` to one of Stability AI's edit endpoints, adding the
+    /// settings every edit accepts.
+    async fn edit_with_stabilityai(
+        &self,
+        operation: &str,
+        mut form: multipart::Form,
+        prompt: &str,
+        params: &ImproveParams,
+    ) -> Result<ImprovedImage, SketchyError> {
+        let api_key = self.stability_key.as_ref().ok_or_else(|| {
+            SketchyError::LLM("Stability AI API key not configured".to_string())
+        })?;
+
+        form = form.text("output_format", params.output_format.as_str());
+        if let Some(seed) = params.seed {
+            form = form.text("seed", seed.to_string());
+        }
+        if let Some(style) = &params.style_preset {
+            form = form.text("style_preset", style.clone());
+        }
+
+        let response = self
+            .client
+            .post(format!(
+                "https://api.stability.ai/v2beta/stable-image/edit/{}",
+                operation
+            ))
+            .header("Authorization", format!("Bearer {}", api_key))
+            .header("Accept", "image/*")
+            .multipart(form)
+            .send()
+            .await
+            .map_err(|e| {
+                SketchyError::LLM(format!("Stability AI {} request failed: {}", operation, e))
+            })?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await.unwrap_or_default();
+            return Err(SketchyError::LLM(format!(
+                "Stability AI {} error: {}",
+                operation, error_text
+            )));
+        }
+
+        let mut params = params.clone();
+        if params.seed.is_none() {
+            params.seed = response
+                .headers()
+                .get("seed")
+                .and_then(|seed| seed.to_str().ok())
+                .and_then(|seed| seed.parse().ok());
+        }
+
+        let image_data = response
+            .bytes()
+            .await
+            .map_err(|e| SketchyError::LLM(format!("Failed to read edited image data: {}", e)))?
+            .to_vec();
+
+        Ok(ImprovedImage {
+            id: Uuid::new_v4(),
+            regenerated_image_id: None, // Set by the handler
+            image_id: None,
+            parent_improved_id: None,
+            step: 1,
+            data: image_data,
+            prompt_used: prompt.to_string(),
+            provider: ImageGenerationProvider::StabilityAI,
+            params,
+            edit: None, // Will be set by handler
+            created_at: chrono::Utc::now(),
+        })
+    }
+
+    /// Edits an image with OpenAI. Without a mask, the whole image is
+    /// reworked, and any transparent areas of it are painted.
+    async fn edit_with_openai(
+        &self,
+        image: &[u8],
+        mask: Option<&[u8]>,
+        prompt: &str,
+        params: &ImproveParams,
+    ) -> Result<ImprovedImage, SketchyError> {
+        let mut form = multipart::Form::new()
+            .text("model", "gpt-image-1")
+            .part("image", file_part(image, "image")?)
+            .text("prompt", prompt.to_string())
+            .text("n", "1")
+            .text("size", "auto")
+            .text("output_format", params.output_format.as_str());
+        if let Some(mask) = mask {
+            form = form.part("mask", file_part(mask, "mask")?);
+        }
+
+        let response = self
+            .client
+            .post("https://api.openai.com/v1/images/edits")
+            .header("Authorization", format!("Bearer {}", self.openai_key))
+            .multipart(form)
+            .send()
+            .await
+            .map_err(|e| SketchyError::LLM(format!("OpenAI image edit request failed: {}", e)))?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await.unwrap_or_default();
+            return Err(SketchyError::LLM(format!(
+                "OpenAI image edit error: {}",
+                error_text
+            )));
+        }
+
+        let result: serde_json::Value = response.json().await.map_err(|e| {
+            SketchyError::LLM(format!("Failed to parse image edit response: {}", e))
+        })?;
+
+        let b64_json = result["data"][0]["b64_json"]
+            .as_str()
+            .ok_or_else(|| SketchyError::LLM("No image data in response".to_string()))?;
+
+        let image_data = general_purpose::STANDARD
+            .decode(b64_json)
+            .map_err(|e| SketchyError::LLM(format!("Failed to decode image: {}", e)))?;
+
+        Ok(ImprovedImage {
+            id: Uuid::new_v4(),
+            regenerated_image_id: None, // Set by the handler
+            image_id: None,
+            parent_improved_id: None,
+            step: 1,
             data: image_data,
             prompt_used: prompt.to_string(),
+            provider: ImageGenerationProvider::OpenAI,
+            params: params.clone(),
+            edit: None, // Will be set by handler
             created_at: chrono::Utc::now(),
         })
     }
@@ -513,12 +1212,12 @@ impl LLMService {
             .map(|r| {
                 Ok(ImageRegion {
                     id: Uuid::new_v4().to_string(),
-                    coordinates: BoundingBox {
-                        x: r["coordinates"]["x"].as_u64().unwrap_or(0) as u32,
-                        y: r["coordinates"]["y"].as_u64().unwrap_or(0) as u32,
-                        width: r["coordinates"]["width"].as_u64().unwrap_or(0) as u32,
-                        height: r["coordinates"]["height"].as_u64().unwrap_or(0) as u32,
-                    },
+                    coordinates: BoundingBox::from_reported(
+                        r["coordinates"]["x"].as_f64().unwrap_or(0.0) as f32,
+                        r["coordinates"]["y"].as_f64().unwrap_or(0.0) as f32,
+                        r["coordinates"]["width"].as_f64().unwrap_or(0.0) as f32,
+                        r["coordinates"]["height"].as_f64().unwrap_or(0.0) as f32,
+                    ),
                     dominant_colors: self.parse_colors(r["dominant_colors"].as_array()),
                     object_description: r["object_description"].as_str().unwrap_or("").to_string(),
                     texture_description: r["texture_description"]
@@ -526,6 +1225,7 @@ impl LLMService {
                         .unwrap_or("")
                         .to_string(),
                     importance_score: r["importance_score"].as_f64().unwrap_or(0.5) as f32,
+                    detail: None,
                 })
             })
             .collect::<Result<Vec<_>, _>>()?;
@@ -554,7 +1254,9 @@ impl LLMService {
                         .filter_map(|p| {
                             let x = p["x"].as_f64()? as f32;
                             let y = p["y"].as_f64()? as f32;
-                            Some((x, y))
+                            // Same units as region bounding boxes
+                            let scale = if x <= 1.0 && y <= 1.0 { 100.0 } else { 1.0 };
+                            Some(((x * scale).clamp(0.0, 100.0), (y * scale).clamp(0.0, 100.0)))
                         })
                         .collect()
                 })
@@ -577,26 +1279,233 @@ impl LLMService {
         })
     }
 
+    /// Reads colours given as `hex`, `rgb` or both, deriving whichever is
+    /// missing. Hex wins when both are present but disagree.
     fn parse_colors(&self, colors_array: Option<&Vec<serde_json::Value>>) -> Vec<Color> {
         colors_array
             .map(|arr| {
                 arr.iter()
                     .filter_map(|c| {
-                        let hex = c["hex"].as_str()?.to_string();
-                        let rgb = (
-                            c["rgb"][0].as_u64()? as u8,
-                            c["rgb"][1].as_u64()? as u8,
-                            c["rgb"][2].as_u64()? as u8,
-                        );
-                        let percentage = c["percentage"].as_f64()? as f32;
-                        Some(Color {
-                            hex,
-                            rgb,
+                        let from_hex = c["hex"].as_str().and_then(Color::parse_hex);
+                        let from_rgb = c["rgb"].as_array().and_then(|rgb| {
+                            let channel =
+                                |i: usize| rgb.get(i)?.as_u64().and_then(|v| u8::try_from(v).ok());
+                            Some((channel(0)?, channel(1)?, channel(2)?))
+                        });
+                        let percentage = c["percentage"].as_f64().unwrap_or(0.0) as f32;
+                        Some(Color::from_rgb(
+                            from_hex.or(from_rgb)?,
                             percentage,
-                        })
+                            ColorSource::Reported,
+                        ))
                     })
                     .collect()
             })
             .unwrap_or_default()
     }
-}
\ No newline at end of file
+}
+
+/// The subject of a region for a composed prompt, preferring the prompt from
+/// a closer analysis of it.
+fn describe_region(region: &ImageRegion) -> String {
+    if let Some(detail) = &region.detail {
+        let prompt = detail.prompt_description.trim().trim_end_matches('.');
+        if !prompt.is_empty() {
+            return prompt.to_string();
+        }
+    }
+
+    let object = region.object_description.trim().trim_end_matches('.');
+    let texture = region.texture_description.trim().trim_end_matches('.');
+    match (object.is_empty(), texture.is_empty()) {
+        (false, false) => format!("{} ({})", object, texture),
+        (false, true) => object.to_string(),
+        _ => String::new(),
+    }
+}
+
+/// Lists what an analysis found, one attribute per line, for instructions
+/// that compare against it.
+fn describe_analysis(analysis: &ImageAnalysis) -> String {
+    let raw = &analysis.raw_analysis;
+    let global = &raw.global_attributes;
+    let mut lines = vec![
+        format!("- Style: {}", global.style),
+        format!("- Mood: {}", global.mood),
+        format!("- Lighting: {}", global.lighting),
+        format!("- Perspective: {}", global.perspective),
+        format!("- Layout: {}", raw.composition.layout),
+    ];
+    if !global.dominant_colors.is_empty() {
+        let colors: Vec<String> = global
+            .dominant_colors
+            .iter()
+            .take(MAX_PROMPT_COLORS)
+            .map(|color| format!("{} {} ({:.0}%)", color.name(), color.hex, color.percentage))
+            .collect();
+        lines.push(format!("- Palette: {}", colors.join(", ")));
+    }
+
+    let mut regions: Vec<&ImageRegion> = raw.regions.iter().collect();
+    regions.sort_by(|a, b| b.importance_score.total_cmp(&a.importance_score));
+    for region in regions {
+        let description = describe_region(region);
+        if !description.is_empty() {
+            lines.push(format!(
+                "- Region {} (importance {:.1}): {}",
+                region.id, region.importance_score, description
+            ));
+        }
+    }
+    lines.push(format!(
+        "- Prompt written by the analysis: {}",
+        analysis.prompt_description
+    ));
+    lines.join("\n")
+}
+
+/// Lists where a regenerated image drifted from the original, for
+/// instructions that correct it. Attributes that match and colours that are
+/// close enough are left out.
+fn describe_drift(diff: &AnalysisDiff, similarity: &SimilarityReport) -> String {
+    /// Colours closer than this are not worth mentioning.
+    const NOTICEABLE_DELTA: f32 = 10.0;
+
+    let mut lines = Vec::new();
+    for (name, drift) in [
+        ("Style", &diff.style),
+        ("Mood", &diff.mood),
+        ("Lighting", &diff.lighting),
+        ("Perspective", &diff.perspective),
+        ("Layout", &diff.layout),
+    ] {
+        if drift.similarity < 1.0 {
+            lines.push(format!(
+                "- {}: should be \"{}\", came out as \"{}\"",
+                name, drift.original, drift.regenerated
+            ));
+        }
+    }
+    for region in &diff.missing_regions {
+        lines.push(format!(
+            "- Missing (importance {:.1}): {}",
+            region.importance_score, region.object_description
+        ));
+    }
+    for region in &diff.added_regions {
+        lines.push(format!(
+            "- Not in the reference: {}",
+            region.object_description
+        ));
+    }
+    for matched in &diff.matched_regions {
+        if matched.overlap < 0.5 {
+            lines.push(format!(
+                "- Misplaced: {} (reference at x {:.0}%, y {:.0}%; generated at x {:.0}%, y {:.0}%)",
+                matched.original.object_description,
+                matched.original.coordinates.x,
+                matched.original.coordinates.y,
+                matched.regenerated.coordinates.x,
+                matched.regenerated.coordinates.y
+            ));
+        }
+    }
+    for color in &diff.palette {
+        if color.delta_e >= NOTICEABLE_DELTA {
+            lines.push(format!(
+                "- Colour {} ({:.0}% of the reference) came out closest to {}",
+                color.original, color.percentage, color.closest
+            ));
+        }
+    }
+    lines.push(format!(
+        "- Pixel comparison: structural similarity {:.2} (1 is identical), colour histogram distance {:.2} (0 is identical)",
+        similarity.ssim, similarity.histogram_distance
+    ));
+    lines.join("\n")
+}
+
+/// The trimmed text of a JSON string, or an empty string for anything else.
+fn json_text(value: &serde_json::Value) -> String {
+    value.as_str().unwrap_or_default().trim().to_string()
+}
+
+/// Joins items as "a, b and c".
+fn join_list(items: &[String]) -> String {
+    match items {
+        [] => String::new(),
+        [only] => only.clone(),
+        [rest @ .., last] => format!("{} and {}", rest.join(", "), last),
+    }
+}
+
+/// Strips the characters Stability uses for weighting syntax.
+fn plain(text: &str) -> String {
+    text.replace(['(', ')', ':'], " ")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn weighted(text: &str, weight: f32) -> String {
+    format!("({}:{:.1})", plain(text), weight)
+}
+
+fn truncate_chars(text: String, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => text[..end].to_string(),
+        None => text,
+    }
+}
+
+/// A multipart file part named after `name`, with the image's MIME type.
+fn file_part(data: &[u8], name: &str) -> Result<multipart::Part, SketchyError> {
+    let format = image::guess_format(data)
+        .map_err(|e| SketchyError::ImageProcessing(format!("Unknown image format: {}", e)))?;
+    let extension = format.extensions_str().first().copied().unwrap_or("png");
+    multipart::Part::bytes(data.to_vec())
+        .file_name(format!("{}.{}", name, extension))
+        .mime_str(format.to_mime_type())
+        .map_err(|e| SketchyError::LLM(format!("Invalid image MIME type: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hex_colours() {
+        assert_eq!(Color::parse_hex("#ff8000"), Some((255, 128, 0)));
+        assert_eq!(Color::parse_hex(" FF8000 "), Some((255, 128, 0)));
+        assert_eq!(Color::parse_hex("#f80"), Some((255, 136, 0)));
+        assert_eq!(Color::parse_hex("#ff80"), None);
+        assert_eq!(Color::parse_hex("#gg0000"), None);
+        assert_eq!(Color::parse_hex("#ééé"), None);
+    }
+
+    #[test]
+    fn hex_wins_over_disagreeing_rgb() {
+        let service = LLMService::new("x".to_string(), None, None);
+        let colors = vec![
+            json!({"hex": "#ff0000", "rgb": [0, 0, 255], "percentage": 40.0}),
+            json!({"rgb": [0, 128, 0], "percentage": 30.0}),
+            json!({"hex": "not a colour", "rgb": [1, 2, 3]}),
+            json!({"hex": "#00f", "rgb": [0, 0, 256]}),
+            json!({"hex": "nope", "rgb": [0, 0]}),
+        ];
+        let parsed: Vec<_> = service
+            .parse_colors(Some(&colors))
+            .into_iter()
+            .map(|c| (c.hex, c.rgb, c.percentage))
+            .collect();
+        assert_eq!(
+            parsed,
+            [
+                ("#ff0000".to_string(), (255, 0, 0), 40.0),
+                ("#008000".to_string(), (0, 128, 0), 30.0),
+                ("#010203".to_string(), (1, 2, 3), 0.0),
+                ("#0000ff".to_string(), (0, 0, 255), 0.0),
+            ]
+        );
+    }
+}
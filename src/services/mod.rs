@@ -1,8 +1,13 @@
 // src/services/mod.rs
+pub mod analysis_diff;
+pub mod archive;
 pub mod image_processor;
 pub mod llm_service;
+pub mod prompt_template;
 pub mod redis_service;
+pub mod storage;
 
 pub use image_processor::ImageProcessor;
 pub use llm_service::LLMService;
 pub use redis_service::RedisService;
+pub use storage::Storage;
@@ -0,0 +1,429 @@
+// src/services/prompt_template.rs
+use std::collections::BTreeMap;
+
+use crate::errors::SketchyError;
+use crate::models::{ImageAnalysis, ImageRegion};
+
+/// How many regions `{subjects}` and colours `{palette}` list.
+const MAX_LISTED: usize = 5;
+
+/// A prompt with `{name}` placeholders. `{{` and `}}` stand for literal
+/// braces.
+#[derive(Debug, Clone)]
+pub struct PromptTemplate {
+    source: String,
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Text(String),
+    Variable(String),
+}
+
+impl PromptTemplate {
+    pub fn parse(template: &str) -> Result<Self, SketchyError> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => {
+                                return Err(SketchyError::Validation(format!(
+                                    "Unclosed placeholder '{{{}' in template",
+                                    name
+                                )));
+                            }
+                        }
+                    }
+                    let name = name.trim();
+                    if !is_variable_name(name) {
+                        return Err(SketchyError::Validation(format!(
+                            "Invalid placeholder '{{{}}}': use letters, digits and underscores, \
+                             and write a literal brace as {{{{ or }}}}",
+                            name
+                        )));
+                    }
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(Part::Variable(name.to_string()));
+                }
+                '}' => {
+                    return Err(SketchyError::Validation(
+                        "Unmatched '}' in template; write a literal brace as }}".to_string(),
+                    ));
+                }
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(Self {
+            source: template.to_string(),
+            parts,
+        })
+    }
+
+    /// The template as written.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Names of the placeholders, each once, in order of first use.
+    pub fn variables(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = Vec::new();
+        for part in &self.parts {
+            if let Part::Variable(name) = part
+                && !names.contains(&name.as_str())
+            {
+                names.push(name);
+            }
+        }
+        names
+    }
+
+    /// Fills every placeholder from `values`, failing with the names of
+    /// those that have no value.
+    pub fn render(&self, values: &BTreeMap<String, String>) -> Result<String, SketchyError> {
+        let missing: Vec<&str> = self
+            .variables()
+            .into_iter()
+            .filter(|name| !values.contains_key(*name))
+            .collect();
+        if !missing.is_empty() {
+            return Err(SketchyError::Validation(format!(
+                "No value for {}",
+                missing
+                    .iter()
+                    .map(|name| format!("{{{}}}", name))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )));
+        }
+
+        Ok(self
+            .parts
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.as_str(),
+                Part::Variable(name) => values[name].as_str(),
+            })
+            .collect())
+    }
+}
+
+pub fn is_variable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// The placeholders an analysis fills, leaving out any it found nothing
+/// for:
+///
+/// - `style`, `mood`, `lighting` and `perspective` from its global attributes
+/// - `layout` and `balance` from its composition
+/// - `subject`, the most important region, and `subjects`, the most
+///   important few
+/// - `palette`, the names of the dominant colours
+/// - `description`, the prompt the analysis wrote
+pub fn analysis_variables(analysis: &ImageAnalysis) -> BTreeMap<String, String> {
+    let raw = &analysis.raw_analysis;
+    let global = &raw.global_attributes;
+
+    let mut regions: Vec<&ImageRegion> = raw.regions.iter().collect();
+    regions.sort_by(|a, b| b.importance_score.total_cmp(&a.importance_score));
+    let subjects: Vec<&str> = regions
+        .iter()
+        .map(|region| region.object_description.trim())
+        .filter(|description| !description.is_empty())
+        .take(MAX_LISTED)
+        .collect();
+    let mut palette: Vec<String> = Vec::new();
+    for name in global.dominant_colors.iter().map(|color| color.name()) {
+        if !palette.contains(&name) {
+            palette.push(name);
+        }
+    }
+    palette.truncate(MAX_LISTED);
+
+    let values = [
+        ("style", global.style.clone()),
+        ("mood", global.mood.clone()),
+        ("lighting", global.lighting.clone()),
+        ("perspective", global.perspective.clone()),
+        ("layout", raw.composition.layout.clone()),
+        ("balance", raw.composition.balance.clone()),
+        (
+            "subject",
+            subjects.first().copied().unwrap_or_default().to_string(),
+        ),
+        ("subjects", subjects.join(", ")),
+        ("palette", palette.join(", ")),
+        ("description", analysis.prompt_description.clone()),
+    ];
+    values
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value.trim().to_string()))
+        .filter(|(_, value)| !value.is_empty())
+        .collect()
+}
+
+/// Every combination of one value per variable, the first variable by name
+/// changing slowest. An empty matrix has one, empty combination. Fails if
+/// there would be more than `max` combinations.
+pub fn expand(
+    matrix: &BTreeMap<String, Vec<String>>,
+    max: usize,
+) -> Result<Vec<BTreeMap<String, String>>, SketchyError> {
+    let count = matrix
+        .values()
+        .try_fold(1usize, |count, values| count.checked_mul(values.len()));
+    match count {
+        Some(0) => {
+            return Err(SketchyError::Validation(
+                "Every matrix variable needs at least one value".to_string(),
+            ));
+        }
+        Some(count) if count <= max => {}
+        _ => {
+            return Err(SketchyError::Validation(format!(
+                "The matrix expands to more than {} combinations",
+                max
+            )));
+        }
+    }
+
+    let mut combinations = vec![BTreeMap::new()];
+    for (name, values) in matrix {
+        combinations = combinations
+            .into_iter()
+            .flat_map(|combination| {
+                values.iter().map(move |value| {
+                    let mut combination = combination.clone();
+                    combination.insert(name.clone(), value.clone());
+                    combination
+                })
+            })
+            .collect();
+    }
+    Ok(combinations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Color;
+
+    fn values(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn matrix(pairs: &[(&str, &[&str])]) -> BTreeMap<String, Vec<String>> {
+        pairs
+            .iter()
+            .map(|(name, values)| {
+                (
+                    name.to_string(),
+                    values.iter().map(|value| value.to_string()).collect(),
+                )
+            })
+            .collect()
+    }
+
+    fn validation_message(result: Result<impl std::fmt::Debug, SketchyError>) -> String {
+        match result {
+            Err(SketchyError::Validation(message)) => message,
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn renders_placeholders_and_escaped_braces() {
+        let template =
+            PromptTemplate::parse("{{literal}} a { style } {subject}, {style}}}").unwrap();
+        assert_eq!(template.variables(), ["style", "subject"]);
+        let rendered = template
+            .render(&values(&[("style", "watercolor"), ("subject", "barn")]))
+            .unwrap();
+        assert_eq!(rendered, "{literal} a watercolor barn, watercolor}");
+    }
+
+    #[test]
+    fn rejects_unclosed_and_invalid_placeholders() {
+        assert!(validation_message(PromptTemplate::parse("a {style")).contains("Unclosed"));
+        assert!(validation_message(PromptTemplate::parse("a {")).contains("Unclosed"));
+        for template in ["{}", "{1st}", "{two words}", "{a-b}"] {
+            assert!(
+                validation_message(PromptTemplate::parse(template)).contains("Invalid"),
+                "{}",
+                template
+            );
+        }
+        assert!(validation_message(PromptTemplate::parse("a } b")).contains("Unmatched"));
+        assert!(validation_message(PromptTemplate::parse("{{a}")).contains("Unmatched"));
+    }
+
+    #[test]
+    fn render_names_every_missing_value() {
+        let template = PromptTemplate::parse("{style} {mood} {style} {subject}").unwrap();
+        let message = validation_message(template.render(&values(&[("mood", "calm")])));
+        assert_eq!(message, "No value for {style}, {subject}");
+    }
+
+    #[test]
+    fn renders_empty_values() {
+        let template = PromptTemplate::parse("a{style}b").unwrap();
+        assert_eq!(template.render(&values(&[("style", "")])).unwrap(), "ab");
+    }
+
+    #[test]
+    fn empty_matrix_has_one_empty_combination() {
+        let combinations = expand(&BTreeMap::new(), 1).unwrap();
+        assert_eq!(combinations, vec![BTreeMap::new()]);
+    }
+
+    #[test]
+    fn expands_in_name_order() {
+        let combinations = expand(&matrix(&[("b", &["1", "2"]), ("a", &["x", "y"])]), 4).unwrap();
+        assert_eq!(
+            combinations,
+            vec![
+                values(&[("a", "x"), ("b", "1")]),
+                values(&[("a", "x"), ("b", "2")]),
+                values(&[("a", "y"), ("b", "1")]),
+                values(&[("a", "y"), ("b", "2")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_variables_without_values() {
+        let message = validation_message(expand(&matrix(&[("a", &["x"]), ("b", &[])]), 16));
+        assert!(message.contains("at least one value"));
+    }
+
+    #[test]
+    fn keeps_zero_length_values() {
+        let combinations = expand(&matrix(&[("a", &["", "x"])]), 2).unwrap();
+        assert_eq!(
+            combinations,
+            vec![values(&[("a", "")]), values(&[("a", "x")])]
+        );
+    }
+
+    #[test]
+    fn limits_combinations() {
+        let two: &[&str] = &["1", "2"];
+        assert_eq!(
+            expand(&matrix(&[("a", two), ("b", two)]), 4).unwrap().len(),
+            4
+        );
+        let message = validation_message(expand(&matrix(&[("a", two), ("b", two)]), 3));
+        assert!(message.contains("more than 3"));
+    }
+
+    #[test]
+    fn overflowing_count_is_over_the_limit() {
+        // 2^64 combinations overflow the count rather than wrapping past the limit
+        let two: Vec<String> = vec!["1".to_string(), "2".to_string()];
+        let matrix: BTreeMap<String, Vec<String>> = (0..64)
+            .map(|i| (format!("v{:02}", i), two.clone()))
+            .collect();
+        let message = validation_message(expand(&matrix, usize::MAX));
+        assert!(message.contains("more than"));
+    }
+
+    #[test]
+    fn fills_variables_from_an_analysis() {
+        let analysis: ImageAnalysis = serde_json::from_value(serde_json::json!({
+            "id": uuid::Uuid::new_v4(),
+            "image_id": uuid::Uuid::new_v4(),
+            "llm_provider": "openai",
+            "raw_analysis": {
+                "regions": [
+                    region("a green field", 0.5),
+                    region("  ", 1.0),
+                    region("a red barn", 0.9),
+                ],
+                "global_attributes": {
+                    "style": " watercolor ",
+                    "mood": "",
+                    "lighting": "golden hour",
+                    "perspective": "eye level",
+                    "dominant_colors": [
+                        color("#000000"),
+                        color("#ffffff"),
+                        color("#010101"),
+                    ],
+                },
+                "composition": {
+                    "layout": "rule of thirds",
+                    "focal_points": [],
+                    "balance": "asymmetric",
+                    "depth_layers": [],
+                },
+            },
+            "prompt_description": "A watercolor barn",
+            "metadata": {
+                "processing_time_ms": 1,
+                "model_used": "gpt-4o",
+                "confidence_score": 0.9,
+            },
+            "created_at": "2026-01-01T00:00:00Z",
+        }))
+        .unwrap();
+
+        assert_eq!(
+            analysis_variables(&analysis),
+            values(&[
+                ("style", "watercolor"),
+                ("lighting", "golden hour"),
+                ("perspective", "eye level"),
+                ("layout", "rule of thirds"),
+                ("balance", "asymmetric"),
+                ("subject", "a red barn"),
+                ("subjects", "a red barn, a green field"),
+                ("palette", "black, white"),
+                ("description", "A watercolor barn"),
+            ])
+        );
+    }
+
+    fn region(description: &str, importance: f32) -> serde_json::Value {
+        serde_json::json!({
+            "id": description,
+            "coordinates": {"x": 0.0, "y": 0.0, "width": 10.0, "height": 10.0},
+            "dominant_colors": [],
+            "object_description": description,
+            "texture_description": "",
+            "importance_score": importance,
+        })
+    }
+
+    fn color(hex: &str) -> serde_json::Value {
+        let rgb = Color::parse_hex(hex).unwrap();
+        serde_json::json!({"hex": hex, "rgb": [rgb.0, rgb.1, rgb.2], "percentage": 10.0})
+    }
+}
@@ -1,210 +1,290 @@
 // src/services/redis_service.rs
 use crate::errors::SketchyError;
-use crate::models::*;
-use redis::{AsyncCommands, Client};
+use crate::services::storage::{Retention, Storage, WriteOp};
+use async_trait::async_trait;
+use log::warn;
+use redis::{AsyncCommands, Client, aio::ConnectionManager};
+use serde::Serialize;
 use serde_json;
-use uuid::Uuid;
+use std::time::Duration;
 
+/// How many times to try reaching Redis on startup before giving up.
+const CONNECT_ATTEMPTS: u32 = 10;
+
+/// Resets a key's expiry only if it already has one, leaving persistent keys alone.
+const TOUCH_SCRIPT: &str = r#"
+if redis.call('TTL', KEYS[1]) > 0 then
+    return redis.call('EXPIRE', KEYS[1], ARGV[1])
+end
+return 0
+"#;
+
+/// Redis-backed storage. All calls share one multiplexed connection that
+/// reconnects on its own after Redis drops it.
 pub struct RedisService {
-    client: Client,
+    conn: ConnectionManager,
+    retention: Retention,
+}
+
+/// Outcome of moving legacy JSON-encoded image bytes into separate binary keys.
+#[derive(Debug, Default, Serialize)]
+pub struct BlobMigrationReport {
+    pub keys_scanned: usize,
+    pub keys_migrated: usize,
+    /// Redis `MEMORY USAGE` of the migrated keys before the migration.
+    pub memory_before_bytes: u64,
+    /// Redis `MEMORY USAGE` of the metadata and data keys after the migration.
+    pub memory_after_bytes: u64,
 }
 
 impl RedisService {
-    pub async fn new(redis_url: &str) -> Result<Self, SketchyError> {
+    pub async fn new(redis_url: &str, retention: Retention) -> Result<Self, SketchyError> {
         let client = Client::open(redis_url).map_err(|e| SketchyError::Redis(e.to_string()))?;
 
-        // Test connection
-        let mut conn = client
-            .get_async_connection()
-            .await
-            .map_err(|e| SketchyError::Redis(e.to_string()))?;
+        // Redis may still be starting up, so back off and retry before failing
+        let mut delay = Duration::from_millis(500);
+        let mut attempt = 1;
+        let mut conn = loop {
+            match ConnectionManager::new(client.clone()).await {
+                Ok(conn) => break conn,
+                Err(e) if attempt < CONNECT_ATTEMPTS => {
+                    warn!(
+                        "Redis unavailable (attempt {}/{}): {}. Retrying in {:?}",
+                        attempt, CONNECT_ATTEMPTS, e, delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(Duration::from_secs(5));
+                    attempt += 1;
+                }
+                Err(e) => return Err(SketchyError::Redis(e.to_string())),
+            }
+        };
 
+        // Test connection
         redis::cmd("PING")
             .query_async::<_, String>(&mut conn)
             .await
             .map_err(|e| SketchyError::Redis(e.to_string()))?;
 
-        Ok(Self { client })
+        Ok(Self { conn, retention })
     }
 
-    pub async fn store_image(&self, image: &ImageUpload) -> Result<(), SketchyError> {
-        let mut conn = self
-            .client
-            .get_async_connection()
-            .await
-            .map_err(|e| SketchyError::Redis(e.to_string()))?;
-
-        let key = format!("image:{}", image.id);
-        let value =
-            serde_json::to_string(image).map_err(|e| SketchyError::Serialization(e.to_string()))?;
-
-        // Store with 24 hour expiration
-        conn.set_ex::<_, _, ()>(&key, value, 86400)
-            .await
-            .map_err(|e| SketchyError::Redis(e.to_string()))?;
+    /// Rewrites entries stored before image bytes were split out of the JSON
+    /// metadata, preserving their remaining TTL. Entries already in the new
+    /// layout are left untouched, so this is safe to run repeatedly.
+    pub async fn migrate_legacy_blobs(&self) -> Result<BlobMigrationReport, SketchyError> {
+        let mut conn = self.conn.clone();
+
+        let mut report = BlobMigrationReport::default();
+
+        for prefix in ["image", "regenerated", "improved"] {
+            let mut keys = Vec::new();
+            {
+                let mut iter = conn
+                    .scan_match::<_, String>(format!("{}:*", prefix))
+                    .await
+                    .map_err(|e| SketchyError::Redis(e.to_string()))?;
+                while let Some(key) = iter.next_item().await {
+                    // Skip indexes and data keys such as `image:{id}:analyses`
+                    if key.matches(':').count() == 1 {
+                        keys.push(key);
+                    }
+                }
+            }
 
-        // Add to session index
-        let session_key = format!("session:{}:images", image.session_id);
-        conn.sadd::<_, _, ()>(&session_key, image.id.to_string())
-            .await
-            .map_err(|e| SketchyError::Redis(e.to_string()))?;
+            for key in keys {
+                report.keys_scanned += 1;
+
+                let value: Option<String> = conn
+                    .get(&key)
+                    .await
+                    .map_err(|e| SketchyError::Redis(e.to_string()))?;
+                let Some(value) = value else { continue };
+
+                let mut metadata: serde_json::Value = serde_json::from_str(&value)
+                    .map_err(|e| SketchyError::Serialization(e.to_string()))?;
+                let Some(legacy_data) = metadata.as_object_mut().and_then(|m| m.remove("data"))
+                else {
+                    continue;
+                };
+                let data: Vec<u8> = serde_json::from_value(legacy_data)
+                    .map_err(|e| SketchyError::Serialization(e.to_string()))?;
+                let metadata = serde_json::to_string(&metadata)
+                    .map_err(|e| SketchyError::Serialization(e.to_string()))?;
+
+                report.memory_before_bytes += memory_usage(&mut conn, &key).await?;
+
+                let data_key = format!("{}:data", key);
+                let ttl_ms: i64 = conn
+                    .pttl(&key)
+                    .await
+                    .map_err(|e| SketchyError::Redis(e.to_string()))?;
+
+                let mut pipe = redis::pipe();
+                pipe.atomic()
+                    .set(&key, metadata)
+                    .ignore()
+                    .set(&data_key, data)
+                    .ignore();
+                if ttl_ms > 0 {
+                    pipe.pexpire(&key, ttl_ms as usize)
+                        .ignore()
+                        .pexpire(&data_key, ttl_ms as usize)
+                        .ignore();
+                }
+                pipe.query_async::<_, ()>(&mut conn)
+                    .await
+                    .map_err(|e| SketchyError::Redis(e.to_string()))?;
+
+                report.memory_after_bytes += memory_usage(&mut conn, &key).await?;
+                report.memory_after_bytes += memory_usage(&mut conn, &data_key).await?;
+                report.keys_migrated += 1;
+            }
+        }
 
-        Ok(())
+        Ok(report)
     }
+}
 
-    pub async fn get_image(&self, image_id: &Uuid) -> Result<ImageUpload, SketchyError> {
-        let mut conn = self
-            .client
-            .get_async_connection()
-            .await
-            .map_err(|e| SketchyError::Redis(e.to_string()))?;
+async fn memory_usage(conn: &mut ConnectionManager, key: &str) -> Result<u64, SketchyError> {
+    let usage: Option<u64> = redis::cmd("MEMORY")
+        .arg("USAGE")
+        .arg(key)
+        .query_async(conn)
+        .await
+        .map_err(|e| SketchyError::Redis(e.to_string()))?;
+    Ok(usage.unwrap_or(0))
+}
 
-        let key = format!("image:{}", image_id);
-        let value: Option<String> = conn
-            .get(&key)
-            .await
-            .map_err(|e| SketchyError::Redis(e.to_string()))?;
+#[async_trait]
+impl Storage for RedisService {
+    async fn put(&self, key: &str, value: &[u8], ttl: Option<u64>) -> Result<(), SketchyError> {
+        let mut conn = self.conn.clone();
 
-        match value {
-            Some(v) => {
-                serde_json::from_str(&v).map_err(|e| SketchyError::Serialization(e.to_string()))
-            }
-            None => Err(SketchyError::Redis(format!(
-                "Image with id '{}' not found.",
-                image_id
-            ))),
+        match ttl {
+            Some(ttl) => conn.set_ex::<_, _, ()>(key, value, ttl as usize).await,
+            None => conn.set::<_, _, ()>(key, value).await,
         }
+        .map_err(|e| SketchyError::Redis(e.to_string()))
     }
 
-    pub async fn store_analysis(&self, analysis: &ImageAnalysis) -> Result<(), SketchyError> {
-        let mut conn = self
-            .client
-            .get_async_connection()
-            .await
-            .map_err(|e| SketchyError::Redis(e.to_string()))?;
-
-        let key = format!("analysis:{}", analysis.id);
-        let value = serde_json::to_string(analysis)
-            .map_err(|e| SketchyError::Serialization(e.to_string()))?;
-
-        conn.set_ex::<_, _, ()>(&key, value, 86400)
-            .await
-            .map_err(|e| SketchyError::Redis(e.to_string()))?;
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, SketchyError> {
+        let mut conn = self.conn.clone();
 
-        // Index by image
-        let image_key = format!("image:{}:analyses", analysis.image_id);
-        conn.sadd::<_, _, ()>(&image_key, analysis.id.to_string())
+        conn.get(key)
             .await
-            .map_err(|e| SketchyError::Redis(e.to_string()))?;
-
-        Ok(())
+            .map_err(|e| SketchyError::Redis(e.to_string()))
     }
 
-    pub async fn get_analysis(&self, analysis_id: &Uuid) -> Result<ImageAnalysis, SketchyError> {
-        let mut conn = self
-            .client
-            .get_async_connection()
-            .await
-            .map_err(|e| SketchyError::Redis(e.to_string()))?;
+    async fn exists(&self, key: &str) -> Result<bool, SketchyError> {
+        let mut conn = self.conn.clone();
 
-        let key = format!("analysis:{}", analysis_id);
-        let value: Option<String> = conn
-            .get(&key)
+        conn.exists(key)
             .await
-            .map_err(|e| SketchyError::Redis(e.to_string()))?;
-
-        match value {
-            Some(v) => {
-                serde_json::from_str(&v).map_err(|e| SketchyError::Serialization(e.to_string()))
-            }
-            None => Err(SketchyError::Redis(format!(
-                "Analysis with id '{}' not found.",
-                analysis_id
-            ))),
-        }
+            .map_err(|e| SketchyError::Redis(e.to_string()))
     }
 
-    pub async fn store_regenerated(&self, image: &RegeneratedImage) -> Result<(), SketchyError> {
-        let mut conn = self
-            .client
-            .get_async_connection()
-            .await
-            .map_err(|e| SketchyError::Redis(e.to_string()))?;
+    async fn add_member(
+        &self,
+        set: &str,
+        member: &str,
+        ttl: Option<u64>,
+    ) -> Result<(), SketchyError> {
+        self.write_batch(&[WriteOp::AddMember {
+            set: set.to_string(),
+            member: member.to_string(),
+            ttl,
+        }])
+        .await
+    }
 
-        let key = format!("regenerated:{}", image.id);
-        let value =
-            serde_json::to_string(image).map_err(|e| SketchyError::Serialization(e.to_string()))?;
+    async fn remove_member(&self, set: &str, member: &str) -> Result<(), SketchyError> {
+        let mut conn = self.conn.clone();
 
-        conn.set_ex::<_, _, ()>(&key, value, 86400)
+        conn.srem::<_, _, ()>(set, member)
             .await
-            .map_err(|e| SketchyError::Redis(e.to_string()))?;
-
-        Ok(())
+            .map_err(|e| SketchyError::Redis(e.to_string()))
     }
 
-    pub async fn get_regenerated(&self, image_id: &Uuid) -> Result<RegeneratedImage, SketchyError> {
-        let mut conn = self
-            .client
-            .get_async_connection()
-            .await
-            .map_err(|e| SketchyError::Redis(e.to_string()))?;
+    async fn members(&self, set: &str) -> Result<Vec<String>, SketchyError> {
+        let mut conn = self.conn.clone();
 
-        let key = format!("regenerated:{}", image_id);
-        let value: Option<String> = conn
-            .get(&key)
+        conn.smembers(set)
             .await
-            .map_err(|e| SketchyError::Redis(e.to_string()))?;
-
-        match value {
-            Some(v) => {
-                serde_json::from_str(&v).map_err(|e| SketchyError::Serialization(e.to_string()))
-            }
-            None => Err(SketchyError::Redis(format!(
-                "Regenerated image with id '{}' not found.",
-                image_id
-            ))),
-        }
+            .map_err(|e| SketchyError::Redis(e.to_string()))
     }
 
-    pub async fn store_improved(&self, image: &ImprovedImage) -> Result<(), SketchyError> {
-        let mut conn = self
-            .client
-            .get_async_connection()
-            .await
-            .map_err(|e| SketchyError::Redis(e.to_string()))?;
-
-        let key = format!("improved:{}", image.id);
-        let value =
-            serde_json::to_string(image).map_err(|e| SketchyError::Serialization(e.to_string()))?;
+    async fn delete(&self, key: &str) -> Result<(), SketchyError> {
+        let mut conn = self.conn.clone();
 
-        conn.set_ex::<_, _, ()>(&key, value, 86400)
+        conn.del::<_, ()>(key)
             .await
-            .map_err(|e| SketchyError::Redis(e.to_string()))?;
+            .map_err(|e| SketchyError::Redis(e.to_string()))
+    }
 
-        Ok(())
+    async fn expire(&self, key: &str, ttl: Option<u64>) -> Result<(), SketchyError> {
+        self.write_batch(&[WriteOp::Expire {
+            key: key.to_string(),
+            ttl,
+        }])
+        .await
     }
 
-    pub async fn get_improved(&self, image_id: &Uuid) -> Result<ImprovedImage, SketchyError> {
-        let mut conn = self
-            .client
-            .get_async_connection()
-            .await
-            .map_err(|e| SketchyError::Redis(e.to_string()))?;
+    async fn touch(&self, key: &str, ttl: u64) -> Result<(), SketchyError> {
+        self.write_batch(&[WriteOp::Touch {
+            key: key.to_string(),
+            ttl,
+        }])
+        .await
+    }
+
+    async fn write_batch(&self, ops: &[WriteOp<'_>]) -> Result<(), SketchyError> {
+        let mut conn = self.conn.clone();
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for op in ops {
+            match op {
+                WriteOp::Put {
+                    key,
+                    value,
+                    ttl: Some(ttl),
+                } => pipe.set_ex(key, *value, *ttl as usize).ignore(),
+                WriteOp::Put {
+                    key,
+                    value,
+                    ttl: None,
+                } => pipe.set(key, *value).ignore(),
+                WriteOp::AddMember { set, member, ttl } => {
+                    pipe.sadd(set, member).ignore();
+                    if let Some(ttl) = ttl {
+                        pipe.expire(set, *ttl as usize).ignore();
+                    }
+                    &mut pipe
+                }
+                WriteOp::Expire {
+                    key,
+                    ttl: Some(ttl),
+                } => pipe.expire(key, *ttl as usize).ignore(),
+                WriteOp::Expire { key, ttl: None } => pipe.persist(key).ignore(),
+                WriteOp::Touch { key, ttl } => pipe
+                    .cmd("EVAL")
+                    .arg(TOUCH_SCRIPT)
+                    .arg(1)
+                    .arg(key)
+                    .arg(*ttl)
+                    .ignore(),
+                WriteOp::Delete { key } => pipe.del(key).ignore(),
+                WriteOp::RemoveMember { set, member } => pipe.srem(set, member).ignore(),
+            };
+        }
 
-        let key = format!("improved:{}", image_id);
-        let value: Option<String> = conn
-            .get(&key)
+        pipe.query_async::<_, ()>(&mut conn)
             .await
-            .map_err(|e| SketchyError::Redis(e.to_string()))?;
+            .map_err(|e| SketchyError::Redis(e.to_string()))
+    }
 
-        match value {
-            Some(v) => {
-                serde_json::from_str(&v).map_err(|e| SketchyError::Serialization(e.to_string()))
-            }
-            None => Err(SketchyError::Redis(format!(
-                "Improved image with id '{}' not found.",
-                image_id
-            ))),
-        }
+    fn retention(&self) -> &Retention {
+        &self.retention
     }
 }
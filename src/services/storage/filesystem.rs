@@ -0,0 +1,355 @@
+// src/services/storage/filesystem.rs
+use super::{Storage, decode_segment, encode_segment};
+use crate::errors::SketchyError;
+use async_trait::async_trait;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use uuid::Uuid;
+
+/// Stores everything under a local directory. Values live in
+/// `blobs/<key segments>.bin` and sets are directories under `sets/` holding
+/// one empty file per member. Nothing expires.
+pub struct FilesystemStorage {
+    root: PathBuf,
+}
+
+impl FilesystemStorage {
+    pub async fn new(root: impl AsRef<Path>) -> Result<Self, SketchyError> {
+        let root = root.as_ref().to_path_buf();
+        for dir in ["blobs", "sets"] {
+            fs::create_dir_all(root.join(dir))
+                .await
+                .map_err(|e| SketchyError::Storage(e.to_string()))?;
+        }
+        Ok(Self { root })
+    }
+
+    fn key_path(&self, area: &str, key: &str) -> PathBuf {
+        let mut path = self.root.join(area);
+        for segment in key.split(':') {
+            path.push(encode_segment(segment));
+        }
+        path
+    }
+
+    fn blob_path(&self, key: &str) -> PathBuf {
+        self.key_path("blobs", key).with_extension("bin")
+    }
+
+    fn set_path(&self, set: &str) -> PathBuf {
+        self.key_path("sets", set)
+    }
+}
+
+#[async_trait]
+impl Storage for FilesystemStorage {
+    async fn put(&self, key: &str, value: &[u8], _ttl: Option<u64>) -> Result<(), SketchyError> {
+        let path = self.blob_path(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .map_err(|e| SketchyError::Storage(e.to_string()))?;
+        }
+
+        // Write to a temporary file first so readers never see a partial value
+        let tmp_path = path.with_extension(format!("{}.tmp", Uuid::new_v4()));
+        fs::write(&tmp_path, value)
+            .await
+            .map_err(|e| SketchyError::Storage(e.to_string()))?;
+        fs::rename(&tmp_path, &path)
+            .await
+            .map_err(|e| SketchyError::Storage(e.to_string()))
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, SketchyError> {
+        match fs::read(self.blob_path(key)).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(SketchyError::Storage(e.to_string())),
+        }
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, SketchyError> {
+        if fs::try_exists(self.blob_path(key))
+            .await
+            .map_err(|e| SketchyError::Storage(e.to_string()))?
+        {
+            return Ok(true);
+        }
+        Ok(!self.members(key).await?.is_empty())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), SketchyError> {
+        match fs::remove_file(self.blob_path(key)).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(SketchyError::Storage(e.to_string())),
+        }
+
+        // Only remove the member files: subdirectories hold other sets whose
+        // keys extend this one, such as `image:{id}:analyses`
+        let set_path = self.set_path(key);
+        for member in self.members(key).await? {
+            fs::remove_file(set_path.join(encode_segment(&member)))
+                .await
+                .map_err(|e| SketchyError::Storage(e.to_string()))?;
+        }
+        let _ = fs::remove_dir(&set_path).await;
+        Ok(())
+    }
+
+    async fn add_member(
+        &self,
+        set: &str,
+        member: &str,
+        _ttl: Option<u64>,
+    ) -> Result<(), SketchyError> {
+        let path = self.set_path(set);
+        fs::create_dir_all(&path)
+            .await
+            .map_err(|e| SketchyError::Storage(e.to_string()))?;
+        fs::write(path.join(encode_segment(member)), [])
+            .await
+            .map_err(|e| SketchyError::Storage(e.to_string()))
+    }
+
+    async fn remove_member(&self, set: &str, member: &str) -> Result<(), SketchyError> {
+        match fs::remove_file(self.set_path(set).join(encode_segment(member))).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(SketchyError::Storage(e.to_string())),
+        }
+    }
+
+    async fn members(&self, set: &str) -> Result<Vec<String>, SketchyError> {
+        let mut entries = match fs::read_dir(self.set_path(set)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(SketchyError::Storage(e.to_string())),
+        };
+
+        let mut members = Vec::new();
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| SketchyError::Storage(e.to_string()))?
+        {
+            let is_file = entry
+                .file_type()
+                .await
+                .map_err(|e| SketchyError::Storage(e.to_string()))?
+                .is_file();
+            if is_file {
+                members.push(decode_segment(&entry.file_name().to_string_lossy()));
+            }
+        }
+        Ok(members)
+    }
+
+    async fn expire(&self, _key: &str, _ttl: Option<u64>) -> Result<(), SketchyError> {
+        Ok(())
+    }
+
+    async fn touch(&self, _key: &str, _ttl: u64) -> Result<(), SketchyError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::storage::WriteOp;
+
+    /// A directory under the system temp dir, removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    async fn storage() -> (TempDir, FilesystemStorage) {
+        let dir = TempDir(std::env::temp_dir().join(format!("sketchy-test-{}", Uuid::new_v4())));
+        let storage = FilesystemStorage::new(dir.0.join("store")).await.unwrap();
+        (dir, storage)
+    }
+
+    /// Every file under `dir`, relative to it.
+    fn files(dir: &Path) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        let mut pending = vec![dir.to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    pending.push(path);
+                } else {
+                    files.push(path);
+                }
+            }
+        }
+        files
+    }
+
+    #[tokio::test]
+    async fn put_get_and_delete() {
+        let (_dir, storage) = storage().await;
+        assert_eq!(storage.get("image:1").await.unwrap(), None);
+        assert!(!storage.exists("image:1").await.unwrap());
+
+        storage.put("image:1", b"first", None).await.unwrap();
+        storage.put("image:1", b"second", Some(60)).await.unwrap();
+        assert_eq!(storage.get("image:1").await.unwrap().unwrap(), b"second");
+        assert!(storage.exists("image:1").await.unwrap());
+
+        // Keys that extend another are separate values
+        storage.put("image:1:data", b"bytes", None).await.unwrap();
+        storage.delete("image:1").await.unwrap();
+        assert_eq!(storage.get("image:1").await.unwrap(), None);
+        assert_eq!(
+            storage.get("image:1:data").await.unwrap().unwrap(),
+            b"bytes"
+        );
+
+        // Deleting what isn't there is not an error
+        storage.delete("image:1").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn set_membership() {
+        let (_dir, storage) = storage().await;
+        assert!(
+            storage
+                .members("session:1:images")
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        for member in ["a", "b", "a"] {
+            storage
+                .add_member("session:1:images", member, None)
+                .await
+                .unwrap();
+        }
+        storage
+            .add_member("session:1:images:nested", "c", None)
+            .await
+            .unwrap();
+        let mut members = storage.members("session:1:images").await.unwrap();
+        members.sort();
+        assert_eq!(members, ["a", "b"]);
+        assert!(storage.exists("session:1:images").await.unwrap());
+
+        storage
+            .remove_member("session:1:images", "a")
+            .await
+            .unwrap();
+        storage
+            .remove_member("session:1:images", "missing")
+            .await
+            .unwrap();
+        assert_eq!(storage.members("session:1:images").await.unwrap(), ["b"]);
+
+        // Deleting a set leaves the sets whose keys extend it
+        storage.delete("session:1:images").await.unwrap();
+        assert!(
+            storage
+                .members("session:1:images")
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert!(!storage.exists("session:1:images").await.unwrap());
+        assert_eq!(
+            storage.members("session:1:images:nested").await.unwrap(),
+            ["c"]
+        );
+    }
+
+    #[tokio::test]
+    async fn write_batch_applies_every_op() {
+        let (_dir, storage) = storage().await;
+        storage.put("old", b"gone", None).await.unwrap();
+        storage
+            .write_batch(&[
+                WriteOp::Put {
+                    key: "new".to_string(),
+                    value: b"value",
+                    ttl: None,
+                },
+                WriteOp::AddMember {
+                    set: "index".to_string(),
+                    member: "new".to_string(),
+                    ttl: None,
+                },
+                WriteOp::Delete {
+                    key: "old".to_string(),
+                },
+            ])
+            .await
+            .unwrap();
+        assert_eq!(storage.get("new").await.unwrap().unwrap(), b"value");
+        assert_eq!(storage.members("index").await.unwrap(), ["new"]);
+        assert_eq!(storage.get("old").await.unwrap(), None);
+    }
+
+    #[test]
+    fn segments_cannot_traverse_paths() {
+        for segment in ["..", ".", "../etc", "a/../../b", "..\\b", "/abs"] {
+            let encoded = encode_segment(segment);
+            assert!(
+                encoded
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_%".contains(c)),
+                "{} -> {}",
+                segment,
+                encoded
+            );
+            assert_ne!(encoded, ".");
+            assert_ne!(encoded, "..");
+            assert_eq!(decode_segment(&encoded), segment);
+        }
+        assert_eq!(encode_segment("../x"), "%2E%2E%2Fx");
+    }
+
+    #[tokio::test]
+    async fn traversing_keys_stay_inside_the_root() {
+        let (dir, storage) = storage().await;
+        let root = dir.0.join("store");
+
+        storage
+            .put("image:../../escaped", b"value", None)
+            .await
+            .unwrap();
+        storage
+            .add_member("../sets", "../../member", None)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            storage.get("image:../../escaped").await.unwrap().unwrap(),
+            b"value"
+        );
+        assert_eq!(storage.members("../sets").await.unwrap(), ["../../member"]);
+        let written = files(&dir.0);
+        assert_eq!(written.len(), 2);
+        assert!(written.iter().all(|path| path.starts_with(&root)));
+    }
+
+    #[tokio::test]
+    async fn members_round_trip_through_encoding() {
+        let (_dir, storage) = storage().await;
+        let members = ["with space", "colon:in", "100%", "%41", "ünïcode"];
+        for member in members {
+            storage.add_member("set", member, None).await.unwrap();
+        }
+        let mut stored = storage.members("set").await.unwrap();
+        stored.sort();
+        let mut expected = members.to_vec();
+        expected.sort();
+        assert_eq!(stored, expected);
+    }
+}
@@ -0,0 +1,1867 @@
+// src/services/storage/mod.rs
+pub mod filesystem;
+pub mod retention;
+pub mod s3;
+
+use crate::errors::SketchyError;
+use crate::models::*;
+use crate::services::RedisService;
+use async_trait::async_trait;
+use log::info;
+use serde::{Serialize, de::DeserializeOwned};
+use std::fmt::Display;
+use std::sync::Arc;
+use uuid::Uuid;
+
+pub use filesystem::FilesystemStorage;
+pub use retention::{DEFAULT_RETENTION, Entity, Retention};
+pub use s3::S3Storage;
+
+/// A single write inside [`Storage::write_batch`].
+pub enum WriteOp<'a> {
+    Put {
+        key: String,
+        value: &'a [u8],
+        ttl: Option<u64>,
+    },
+    AddMember {
+        set: String,
+        member: String,
+        ttl: Option<u64>,
+    },
+    Expire {
+        key: String,
+        ttl: Option<u64>,
+    },
+    Touch {
+        key: String,
+        ttl: u64,
+    },
+    Delete {
+        key: String,
+    },
+    RemoveMember {
+        set: String,
+        member: String,
+    },
+}
+
+/// Persistence for everything Sketchy produces.
+///
+/// Backends only implement a handful of key/value and set primitives; the
+/// entity-level operations are provided on top of them so every backend lays
+/// out images, analyses, regenerations and improvements the same way.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Stores `value` under `key`. `ttl` is in seconds and only honoured by
+    /// backends with native expiry; the others keep data until deleted.
+    async fn put(&self, key: &str, value: &[u8], ttl: Option<u64>) -> Result<(), SketchyError>;
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, SketchyError>;
+
+    /// Whether `key` holds a value or a non-empty set.
+    async fn exists(&self, key: &str) -> Result<bool, SketchyError>;
+
+    /// Removes the value or set stored under `key`, if any.
+    async fn delete(&self, key: &str) -> Result<(), SketchyError>;
+
+    /// Adds `member` to `set`, resetting the expiry of the whole set to `ttl`.
+    async fn add_member(
+        &self,
+        set: &str,
+        member: &str,
+        ttl: Option<u64>,
+    ) -> Result<(), SketchyError>;
+
+    /// Removes `member` from `set`, if present.
+    async fn remove_member(&self, set: &str, member: &str) -> Result<(), SketchyError>;
+
+    async fn members(&self, set: &str) -> Result<Vec<String>, SketchyError>;
+
+    /// Sets the expiry of `key`, or removes it when `ttl` is `None`. A no-op
+    /// on backends without native expiry.
+    async fn expire(&self, key: &str, ttl: Option<u64>) -> Result<(), SketchyError>;
+
+    /// Pushes back the expiry of `key`, but only if it already has one, so
+    /// keys that were made persistent stay that way.
+    async fn touch(&self, key: &str, ttl: u64) -> Result<(), SketchyError>;
+
+    /// Applies several writes together. Backends that support transactions
+    /// apply them atomically; the default applies them one by one.
+    async fn write_batch(&self, ops: &[WriteOp<'_>]) -> Result<(), SketchyError> {
+        for op in ops {
+            match op {
+                WriteOp::Put { key, value, ttl } => self.put(key, value, *ttl).await?,
+                WriteOp::AddMember { set, member, ttl } => {
+                    self.add_member(set, member, *ttl).await?
+                }
+                WriteOp::Expire { key, ttl } => self.expire(key, *ttl).await?,
+                WriteOp::Touch { key, ttl } => self.touch(key, *ttl).await?,
+                WriteOp::Delete { key } => self.delete(key).await?,
+                WriteOp::RemoveMember { set, member } => self.remove_member(set, member).await?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Expiry periods for each kind of entity.
+    fn retention(&self) -> &Retention {
+        &DEFAULT_RETENTION
+    }
+
+    async fn store_image(&self, image: &ImageUpload) -> Result<(), SketchyError> {
+        let key = format!("image:{}", image.id);
+        let metadata = to_json(image)?;
+        let ttl = self.session_ttl(&image.session_id, Entity::Image).await?;
+
+        self.write_batch(&[
+            WriteOp::Put {
+                key: key.clone(),
+                value: &metadata,
+                ttl,
+            },
+            WriteOp::Put {
+                key: format!("{}:data", key),
+                value: &image.data,
+                ttl,
+            },
+            // Add to session index
+            WriteOp::AddMember {
+                set: format!("session:{}:images", image.session_id),
+                member: image.id.to_string(),
+                ttl,
+            },
+        ])
+        .await
+    }
+
+    async fn get_image(&self, image_id: &Uuid) -> Result<ImageUpload, SketchyError> {
+        let key = format!("image:{}", image_id);
+        let mut image: ImageUpload = get_json(self, &key).await?.ok_or_else(|| {
+            SketchyError::NotFound(format!("Image with id '{}' not found.", image_id))
+        })?;
+        // Legacy entries carry their bytes inside the metadata instead
+        if let Some(data) = self.get(&format!("{}:data", key)).await? {
+            image.data = data;
+        }
+
+        let session_key = format!("session:{}:images", image.session_id);
+        self.touch_keys(
+            Entity::Image,
+            vec![format!("{}:data", key), key, session_key],
+        )
+        .await?;
+        Ok(image)
+    }
+
+    async fn store_analysis(&self, analysis: &ImageAnalysis) -> Result<(), SketchyError> {
+        let value = to_json(analysis)?;
+        let ttl = match self.session_of_image(&analysis.image_id).await? {
+            Some(session_id) => self.session_ttl(&session_id, Entity::Analysis).await?,
+            None => self.retention().ttl(Entity::Analysis),
+        };
+
+        self.write_batch(&[
+            WriteOp::Put {
+                key: format!("analysis:{}", analysis.id),
+                value: &value,
+                ttl,
+            },
+            // Index by image
+            WriteOp::AddMember {
+                set: format!("image:{}:analyses", analysis.image_id),
+                member: analysis.id.to_string(),
+                ttl,
+            },
+        ])
+        .await
+    }
+
+    async fn get_analysis(&self, analysis_id: &Uuid) -> Result<ImageAnalysis, SketchyError> {
+        let analysis: ImageAnalysis = get_json(self, &format!("analysis:{}", analysis_id))
+            .await?
+            .ok_or_else(|| {
+                SketchyError::NotFound(format!("Analysis with id '{}' not found.", analysis_id))
+            })?;
+
+        self.touch_keys(
+            Entity::Analysis,
+            vec![
+                format!("analysis:{}", analysis_id),
+                format!("image:{}:analyses", analysis.image_id),
+            ],
+        )
+        .await?;
+        Ok(analysis)
+    }
+
+    async fn store_regenerated(&self, image: &RegeneratedImage) -> Result<(), SketchyError> {
+        let key = format!("regenerated:{}", image.id);
+        let metadata = to_json(image)?;
+        let ttl = match self.session_of_analysis(&image.analysis_id).await? {
+            Some(session_id) => self.session_ttl(&session_id, Entity::Regenerated).await?,
+            None => self.retention().ttl(Entity::Regenerated),
+        };
+
+        self.write_batch(&[
+            WriteOp::Put {
+                key: key.clone(),
+                value: &metadata,
+                ttl,
+            },
+            WriteOp::Put {
+                key: format!("{}:data", key),
+                value: &image.data,
+                ttl,
+            },
+            // Index by analysis
+            WriteOp::AddMember {
+                set: format!("analysis:{}:regenerations", image.analysis_id),
+                member: image.id.to_string(),
+                ttl,
+            },
+        ])
+        .await
+    }
+
+    async fn get_regenerated(&self, image_id: &Uuid) -> Result<RegeneratedImage, SketchyError> {
+        let key = format!("regenerated:{}", image_id);
+        let mut image: RegeneratedImage = get_json(self, &key).await?.ok_or_else(|| {
+            SketchyError::NotFound(format!(
+                "Regenerated image with id '{}' not found.",
+                image_id
+            ))
+        })?;
+        if let Some(data) = self.get(&format!("{}:data", key)).await? {
+            image.data = data;
+        }
+
+        self.touch_keys(Entity::Regenerated, vec![format!("{}:data", key), key])
+            .await?;
+        Ok(image)
+    }
+
+    /// Saves the current state of a refinement job, kept as long as the
+    /// images it regenerates.
+    async fn store_refinement(&self, refinement: &Refinement) -> Result<(), SketchyError> {
+        let value = to_json(refinement)?;
+        let ttl = match self.session_of_analysis(&refinement.analysis_id).await? {
+            Some(session_id) => self.session_ttl(&session_id, Entity::Regenerated).await?,
+            None => self.retention().ttl(Entity::Regenerated),
+        };
+
+        self.write_batch(&[
+            WriteOp::Put {
+                key: format!("refinement:{}", refinement.id),
+                value: &value,
+                ttl,
+            },
+            WriteOp::AddMember {
+                set: format!("analysis:{}:refinements", refinement.analysis_id),
+                member: refinement.id.to_string(),
+                ttl,
+            },
+        ])
+        .await
+    }
+
+    async fn get_refinement(&self, refinement_id: &Uuid) -> Result<Refinement, SketchyError> {
+        let key = format!("refinement:{}", refinement_id);
+        let refinement = get_json(self, &key).await?.ok_or_else(|| {
+            SketchyError::NotFound(format!("Refinement with id '{}' not found.", refinement_id))
+        })?;
+
+        self.touch_keys(Entity::Regenerated, vec![key]).await?;
+        Ok(refinement)
+    }
+
+    async fn store_improved(&self, image: &ImprovedImage) -> Result<(), SketchyError> {
+        let key = format!("improved:{}", image.id);
+        let metadata = to_json(image)?;
+        let root = image.root();
+        let ttl = match root {
+            Some(root) => self.chain_ttl(&root).await?,
+            None => self.retention().ttl(Entity::Improved),
+        };
+        let id = image.id.to_string();
+
+        let mut ops = vec![
+            WriteOp::Put {
+                key: key.clone(),
+                value: &metadata,
+                ttl,
+            },
+            WriteOp::Put {
+                key: format!("{}:data", key),
+                value: &image.data,
+                ttl,
+            },
+        ];
+        // Index the whole chain by its root image, and continue it from
+        // the new step
+        if let Some(root) = root {
+            ops.push(WriteOp::AddMember {
+                set: format!("{}:improvements", root_key(&root)),
+                member: id.clone(),
+                ttl,
+            });
+            ops.push(WriteOp::Put {
+                key: format!("{}:head", root_key(&root)),
+                value: id.as_bytes(),
+                ttl,
+            });
+        }
+        if let Some(parent_id) = image.parent_improved_id {
+            ops.push(WriteOp::AddMember {
+                set: format!("improved:{}:children", parent_id),
+                member: image.id.to_string(),
+                ttl,
+            });
+        }
+        self.write_batch(&ops).await
+    }
+
+    async fn get_improved(&self, image_id: &Uuid) -> Result<ImprovedImage, SketchyError> {
+        let key = format!("improved:{}", image_id);
+        let mut image: ImprovedImage = get_json(self, &key).await?.ok_or_else(|| {
+            SketchyError::NotFound(format!("Improved image with id '{}' not found.", image_id))
+        })?;
+        if let Some(data) = self.get(&format!("{}:data", key)).await? {
+            image.data = data;
+        }
+
+        self.touch_keys(Entity::Improved, vec![format!("{}:data", key), key])
+            .await?;
+        Ok(image)
+    }
+
+    /// Caches a derived rendition of an image entity next to its original,
+    /// with the same retention.
+    async fn store_variant(
+        &self,
+        entity: Entity,
+        id: &Uuid,
+        variant: ImageVariant,
+        data: &[u8],
+    ) -> Result<(), SketchyError> {
+        // The image may have been deleted while the rendition was generated
+        if !self
+            .exists(&format!("{}:{}", entity.key_prefix(), id))
+            .await?
+        {
+            return Ok(());
+        }
+
+        let session_id = match entity {
+            Entity::Image => self.session_of_image(id).await?,
+            Entity::Regenerated => self.session_of_regenerated(id).await?,
+            Entity::Improved => self.session_of_improved(id).await?,
+            Entity::Analysis => None,
+        };
+        let ttl = match session_id {
+            Some(session_id) => self.session_ttl(&session_id, entity).await?,
+            None => self.retention().ttl(entity),
+        };
+
+        self.put(&variant_key(entity, id, variant), data, ttl).await
+    }
+
+    /// A previously stored rendition, or `None` if it has not been generated yet.
+    async fn get_variant(
+        &self,
+        entity: Entity,
+        id: &Uuid,
+        variant: ImageVariant,
+    ) -> Result<Option<Vec<u8>>, SketchyError> {
+        let key = variant_key(entity, id, variant);
+        let data = self.get(&key).await?;
+        if data.is_some() {
+            self.touch_keys(entity, vec![key]).await?;
+        }
+        Ok(data)
+    }
+
+    async fn session_exists(&self, session_id: &Uuid) -> Result<bool, SketchyError> {
+        self.exists(&format!("session:{}:images", session_id)).await
+    }
+
+    async fn is_session_pinned(&self, session_id: &Uuid) -> Result<bool, SketchyError> {
+        self.exists(&format!("session:{}:pinned", session_id)).await
+    }
+
+    /// Expiry for a new entity of the given kind in a session: none at all
+    /// if the session is pinned, otherwise the configured retention.
+    async fn session_ttl(
+        &self,
+        session_id: &Uuid,
+        entity: Entity,
+    ) -> Result<Option<u64>, SketchyError> {
+        if self.is_session_pinned(session_id).await? {
+            return Ok(None);
+        }
+        Ok(self.retention().ttl(entity))
+    }
+
+    async fn session_of_image(&self, image_id: &Uuid) -> Result<Option<Uuid>, SketchyError> {
+        let image: Option<ImageUpload> = get_json(self, &format!("image:{}", image_id)).await?;
+        Ok(image.map(|image| image.session_id))
+    }
+
+    async fn session_of_analysis(&self, analysis_id: &Uuid) -> Result<Option<Uuid>, SketchyError> {
+        let analysis: Option<ImageAnalysis> =
+            get_json(self, &format!("analysis:{}", analysis_id)).await?;
+        match analysis {
+            Some(analysis) => self.session_of_image(&analysis.image_id).await,
+            None => Ok(None),
+        }
+    }
+
+    async fn session_of_regenerated(
+        &self,
+        regenerated_id: &Uuid,
+    ) -> Result<Option<Uuid>, SketchyError> {
+        let regenerated: Option<RegeneratedImage> =
+            get_json(self, &format!("regenerated:{}", regenerated_id)).await?;
+        match regenerated {
+            Some(regenerated) => self.session_of_analysis(&regenerated.analysis_id).await,
+            None => Ok(None),
+        }
+    }
+
+    async fn session_of_improved(&self, improved_id: &Uuid) -> Result<Option<Uuid>, SketchyError> {
+        let improved: Option<ImprovedImage> =
+            get_json(self, &format!("improved:{}", improved_id)).await?;
+        match improved.and_then(|improved| improved.root()) {
+            Some(root) => self.session_of_chain(&root).await,
+            None => Ok(None),
+        }
+    }
+
+    /// The session of the upload or regenerated image a chain starts from.
+    async fn session_of_chain(&self, root: &ChainRoot) -> Result<Option<Uuid>, SketchyError> {
+        match root {
+            ChainRoot::Upload(image_id) => self.session_of_image(image_id).await,
+            ChainRoot::Regenerated(regenerated_id) => {
+                self.session_of_regenerated(regenerated_id).await
+            }
+        }
+    }
+
+    /// Expiry of the keys of an improvement chain.
+    async fn chain_ttl(&self, root: &ChainRoot) -> Result<Option<u64>, SketchyError> {
+        match self.session_of_chain(root).await? {
+            Some(session_id) => self.session_ttl(&session_id, Entity::Improved).await,
+            None => Ok(self.retention().ttl(Entity::Improved)),
+        }
+    }
+
+    /// Every key belonging to a session, tagged with the entity whose
+    /// retention applies to it: the session index itself, each of its images,
+    /// their analyses, regenerations and improvements, and the indexes
+    /// linking them.
+    async fn session_keys(&self, session_id: &Uuid) -> Result<Vec<(String, Entity)>, SketchyError> {
+        let session_key = format!("session:{}:images", session_id);
+        let image_ids = self.members(&session_key).await?;
+
+        let mut keys = vec![(session_key, Entity::Image)];
+        for image_id in image_ids {
+            keys.extend(self.image_keys(&image_id).await?);
+        }
+        Ok(keys)
+    }
+
+    /// Keys of an upload and everything derived from it.
+    async fn image_keys(&self, image_id: &str) -> Result<Vec<(String, Entity)>, SketchyError> {
+        let analyses_key = format!("image:{}:analyses", image_id);
+        let analysis_ids = self.members(&analyses_key).await?;
+
+        let improvements_key = format!("image:{}:improvements", image_id);
+        let improved_ids = self.members(&improvements_key).await?;
+
+        let mut keys = vec![
+            (format!("image:{}", image_id), Entity::Image),
+            (format!("image:{}:data", image_id), Entity::Image),
+        ];
+        keys.extend(variant_keys(Entity::Image, image_id));
+        keys.push((analyses_key, Entity::Analysis));
+        keys.push((improvements_key, Entity::Improved));
+        keys.push((format!("image:{}:head", image_id), Entity::Improved));
+
+        for analysis_id in analysis_ids {
+            keys.extend(self.analysis_keys(&analysis_id).await?);
+        }
+        for improved_id in improved_ids {
+            keys.extend(improved_entity_keys(&improved_id));
+        }
+        Ok(keys)
+    }
+
+    /// Keys of an analysis and the images regenerated from it.
+    async fn analysis_keys(
+        &self,
+        analysis_id: &str,
+    ) -> Result<Vec<(String, Entity)>, SketchyError> {
+        let regenerations_key = format!("analysis:{}:regenerations", analysis_id);
+        let regenerated_ids = self.members(&regenerations_key).await?;
+        let refinements_key = format!("analysis:{}:refinements", analysis_id);
+        let refinement_ids = self.members(&refinements_key).await?;
+
+        let mut keys = vec![
+            (format!("analysis:{}", analysis_id), Entity::Analysis),
+            (regenerations_key, Entity::Regenerated),
+            (refinements_key, Entity::Regenerated),
+        ];
+        for regenerated_id in regenerated_ids {
+            keys.extend(self.regenerated_keys(&regenerated_id).await?);
+        }
+        keys.extend(
+            refinement_ids
+                .into_iter()
+                .map(|id| (format!("refinement:{}", id), Entity::Regenerated)),
+        );
+        Ok(keys)
+    }
+
+    /// Keys of a regenerated image and its whole improvement chain.
+    async fn regenerated_keys(
+        &self,
+        regenerated_id: &str,
+    ) -> Result<Vec<(String, Entity)>, SketchyError> {
+        let improvements_key = format!("regenerated:{}:improvements", regenerated_id);
+        let improved_ids = self.members(&improvements_key).await?;
+
+        let mut keys = vec![
+            (
+                format!("regenerated:{}", regenerated_id),
+                Entity::Regenerated,
+            ),
+            (
+                format!("regenerated:{}:data", regenerated_id),
+                Entity::Regenerated,
+            ),
+        ];
+        keys.extend(variant_keys(Entity::Regenerated, regenerated_id));
+        keys.push((
+            format!("regenerated:{}:prompts", regenerated_id),
+            Entity::Regenerated,
+        ));
+        keys.push((improvements_key, Entity::Improved));
+        keys.push((
+            format!("regenerated:{}:head", regenerated_id),
+            Entity::Improved,
+        ));
+
+        for improved_id in improved_ids {
+            keys.extend(improved_entity_keys(&improved_id));
+        }
+        Ok(keys)
+    }
+
+    /// Keys of an improvement and every improvement made from it, found by
+    /// following the children indexes.
+    async fn improved_keys(
+        &self,
+        improved_id: &str,
+    ) -> Result<Vec<(String, Entity)>, SketchyError> {
+        let mut keys = Vec::new();
+        let mut pending = vec![improved_id.to_string()];
+        while let Some(id) = pending.pop() {
+            pending.extend(self.members(&format!("improved:{}:children", id)).await?);
+            keys.extend(improved_entity_keys(&id));
+        }
+        Ok(keys)
+    }
+
+    /// Deletes a whole session: every upload in it, everything derived from
+    /// them and the pin marker.
+    async fn delete_session(
+        &self,
+        session_id: &Uuid,
+        reason: Option<String>,
+    ) -> Result<DeletionRecord, SketchyError> {
+        if !self.session_exists(session_id).await? {
+            return Err(SketchyError::NotFound(format!(
+                "Session with id '{}' not found.",
+                session_id
+            )));
+        }
+
+        let mut keys = self.session_keys(session_id).await?;
+        keys.push((format!("session:{}:pinned", session_id), Entity::Image));
+        self.erase(
+            DeletionTarget::Session,
+            session_id,
+            reason,
+            keys,
+            Vec::new(),
+        )
+        .await
+    }
+
+    async fn delete_image(
+        &self,
+        image_id: &Uuid,
+        reason: Option<String>,
+    ) -> Result<DeletionRecord, SketchyError> {
+        let image: ImageUpload = get_json(self, &format!("image:{}", image_id))
+            .await?
+            .ok_or_else(|| {
+                SketchyError::NotFound(format!("Image with id '{}' not found.", image_id))
+            })?;
+
+        let keys = self.image_keys(&image_id.to_string()).await?;
+        let unlinks = vec![(
+            format!("session:{}:images", image.session_id),
+            image_id.to_string(),
+        )];
+        self.erase(DeletionTarget::Image, image_id, reason, keys, unlinks)
+            .await
+    }
+
+    async fn delete_analysis(
+        &self,
+        analysis_id: &Uuid,
+        reason: Option<String>,
+    ) -> Result<DeletionRecord, SketchyError> {
+        let analysis: ImageAnalysis = get_json(self, &format!("analysis:{}", analysis_id))
+            .await?
+            .ok_or_else(|| {
+                SketchyError::NotFound(format!("Analysis with id '{}' not found.", analysis_id))
+            })?;
+
+        let keys = self.analysis_keys(&analysis_id.to_string()).await?;
+        let unlinks = vec![(
+            format!("image:{}:analyses", analysis.image_id),
+            analysis_id.to_string(),
+        )];
+        self.erase(DeletionTarget::Analysis, analysis_id, reason, keys, unlinks)
+            .await
+    }
+
+    async fn delete_regenerated(
+        &self,
+        regenerated_id: &Uuid,
+        reason: Option<String>,
+    ) -> Result<DeletionRecord, SketchyError> {
+        let regenerated: RegeneratedImage =
+            get_json(self, &format!("regenerated:{}", regenerated_id))
+                .await?
+                .ok_or_else(|| {
+                    SketchyError::NotFound(format!(
+                        "Regenerated image with id '{}' not found.",
+                        regenerated_id
+                    ))
+                })?;
+
+        let keys = self.regenerated_keys(&regenerated_id.to_string()).await?;
+        let unlinks = vec![(
+            format!("analysis:{}:regenerations", regenerated.analysis_id),
+            regenerated_id.to_string(),
+        )];
+        self.erase(
+            DeletionTarget::Regenerated,
+            regenerated_id,
+            reason,
+            keys,
+            unlinks,
+        )
+        .await
+    }
+
+    /// Deletes an improvement together with every improvement made from it.
+    async fn delete_improved(
+        &self,
+        improved_id: &Uuid,
+        reason: Option<String>,
+    ) -> Result<DeletionRecord, SketchyError> {
+        let improved: ImprovedImage = get_json(self, &format!("improved:{}", improved_id))
+            .await?
+            .ok_or_else(|| {
+                SketchyError::NotFound(format!(
+                    "Improved image with id '{}' not found.",
+                    improved_id
+                ))
+            })?;
+
+        let keys = self.improved_keys(&improved_id.to_string()).await?;
+
+        // Every removed step also leaves the index of the whole chain
+        let mut unlinks: Vec<(String, String)> = match improved.root() {
+            Some(root) => {
+                let chain_key = format!("{}:improvements", root_key(&root));
+                deleted_ids(&keys, Entity::Improved)
+                    .into_iter()
+                    .map(|id| (chain_key.clone(), id.to_string()))
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+        if let Some(parent_id) = improved.parent_improved_id {
+            unlinks.push((
+                format!("improved:{}:children", parent_id),
+                improved_id.to_string(),
+            ));
+        }
+        self.erase(DeletionTarget::Improved, improved_id, reason, keys, unlinks)
+            .await
+    }
+
+    /// Deletes `keys`, removes the deleted entities from the indexes of
+    /// their parents (`unlinks`, as set and member pairs) and writes an
+    /// audit record of it all in the same batch. Audit records never expire.
+    async fn erase(
+        &self,
+        target: DeletionTarget,
+        target_id: &Uuid,
+        reason: Option<String>,
+        mut keys: Vec<(String, Entity)>,
+        mut unlinks: Vec<(String, String)>,
+    ) -> Result<DeletionRecord, SketchyError> {
+        // Thumbnails kept in the prompt library go with their images
+        for regenerated_id in deleted_ids(&keys, Entity::Regenerated) {
+            for prompt_id in self
+                .members(&format!("regenerated:{}:prompts", regenerated_id))
+                .await?
+            {
+                keys.push((
+                    prompt_result_key(&prompt_id, regenerated_id),
+                    Entity::Regenerated,
+                ));
+                unlinks.push((
+                    format!("prompt:{}:results", prompt_id),
+                    regenerated_id.to_string(),
+                ));
+            }
+        }
+
+        let record = DeletionRecord {
+            id: Uuid::new_v4(),
+            target,
+            target_id: *target_id,
+            reason,
+            images: deleted_ids(&keys, Entity::Image),
+            analyses: deleted_ids(&keys, Entity::Analysis),
+            regenerated: deleted_ids(&keys, Entity::Regenerated),
+            improved: deleted_ids(&keys, Entity::Improved),
+            keys_deleted: keys.len(),
+            deleted_at: chrono::Utc::now(),
+        };
+        let value = to_json(&record)?;
+
+        let mut ops: Vec<WriteOp> = keys
+            .into_iter()
+            .map(|(key, _)| WriteOp::Delete { key })
+            .collect();
+        ops.extend(
+            unlinks
+                .into_iter()
+                .map(|(set, member)| WriteOp::RemoveMember { set, member }),
+        );
+        ops.push(WriteOp::Put {
+            key: format!("deletion:{}", record.id),
+            value: &value,
+            ttl: None,
+        });
+        ops.push(WriteOp::AddMember {
+            set: "deletions".to_string(),
+            member: record.id.to_string(),
+            ttl: None,
+        });
+        self.write_batch(&ops).await?;
+
+        Ok(record)
+    }
+
+    async fn get_deletion(&self, deletion_id: &Uuid) -> Result<DeletionRecord, SketchyError> {
+        get_json(self, &format!("deletion:{}", deletion_id))
+            .await?
+            .ok_or_else(|| {
+                SketchyError::NotFound(format!(
+                    "Deletion record with id '{}' not found.",
+                    deletion_id
+                ))
+            })
+    }
+
+    /// Saves a prompt to the library, moving it between tag indexes if its
+    /// tags changed. Library keys never expire.
+    async fn store_prompt(&self, prompt: &SavedPrompt) -> Result<(), SketchyError> {
+        let key = format!("prompt:{}", prompt.id);
+        let value = to_json(prompt)?;
+        let previous: Option<SavedPrompt> = get_json(self, &key).await?;
+        let id = prompt.id.to_string();
+
+        let mut ops = vec![
+            WriteOp::Put {
+                key,
+                value: &value,
+                ttl: None,
+            },
+            WriteOp::AddMember {
+                set: "prompts".to_string(),
+                member: id.clone(),
+                ttl: None,
+            },
+        ];
+        for tag in previous.iter().flat_map(|p| &p.tags) {
+            if !prompt.tags.contains(tag) {
+                ops.push(WriteOp::RemoveMember {
+                    set: prompt_tag_key(tag),
+                    member: id.clone(),
+                });
+            }
+        }
+        for tag in &prompt.tags {
+            ops.push(WriteOp::AddMember {
+                set: prompt_tag_key(tag),
+                member: id.clone(),
+                ttl: None,
+            });
+        }
+        self.write_batch(&ops).await
+    }
+
+    async fn get_prompt(&self, prompt_id: &Uuid) -> Result<SavedPrompt, SketchyError> {
+        let mut prompt: SavedPrompt = get_json(self, &format!("prompt:{}", prompt_id))
+            .await?
+            .ok_or_else(|| {
+                SketchyError::NotFound(format!("Prompt with id '{}' not found.", prompt_id))
+            })?;
+        prompt.results = self
+            .members(&format!("prompt:{}:results", prompt_id))
+            .await?
+            .iter()
+            .filter_map(|id| Uuid::parse_str(id).ok())
+            .collect();
+        prompt.results.sort();
+        Ok(prompt)
+    }
+
+    /// Prompts in the library carrying every one of `tags`, or all of them
+    /// if `tags` is empty.
+    async fn prompts_tagged(&self, tags: &[String]) -> Result<Vec<SavedPrompt>, SketchyError> {
+        let mut ids = match tags.split_first() {
+            Some((first, _)) => self.members(&prompt_tag_key(first)).await?,
+            None => self.members("prompts").await?,
+        };
+        for tag in tags.iter().skip(1) {
+            let tagged = self.members(&prompt_tag_key(tag)).await?;
+            ids.retain(|id| tagged.contains(id));
+        }
+
+        let mut prompts = Vec::new();
+        for id in ids {
+            let Ok(prompt_id) = Uuid::parse_str(&id) else {
+                continue;
+            };
+            match self.get_prompt(&prompt_id).await {
+                Ok(prompt) => prompts.push(prompt),
+                Err(SketchyError::NotFound(_)) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(prompts)
+    }
+
+    /// Keeps a thumbnail of a regenerated image made from a saved prompt.
+    /// The image links back to the prompt until it expires, so deleting it
+    /// deletes the thumbnail too.
+    async fn add_prompt_result(
+        &self,
+        prompt_id: &Uuid,
+        regenerated_id: &Uuid,
+        thumbnail: &[u8],
+    ) -> Result<(), SketchyError> {
+        let ttl = match self.session_of_regenerated(regenerated_id).await? {
+            Some(session_id) => self.session_ttl(&session_id, Entity::Regenerated).await?,
+            None => self.retention().ttl(Entity::Regenerated),
+        };
+
+        self.write_batch(&[
+            WriteOp::Put {
+                key: prompt_result_key(prompt_id, regenerated_id),
+                value: thumbnail,
+                ttl: None,
+            },
+            WriteOp::AddMember {
+                set: format!("prompt:{}:results", prompt_id),
+                member: regenerated_id.to_string(),
+                ttl: None,
+            },
+            WriteOp::AddMember {
+                set: format!("regenerated:{}:prompts", regenerated_id),
+                member: prompt_id.to_string(),
+                ttl,
+            },
+        ])
+        .await
+    }
+
+    async fn get_prompt_thumbnail(
+        &self,
+        prompt_id: &Uuid,
+        regenerated_id: &Uuid,
+    ) -> Result<Vec<u8>, SketchyError> {
+        self.get(&prompt_result_key(prompt_id, regenerated_id))
+            .await?
+            .ok_or_else(|| {
+                SketchyError::NotFound(format!(
+                    "Prompt '{}' has no result '{}'.",
+                    prompt_id, regenerated_id
+                ))
+            })
+    }
+
+    /// Removes a prompt, its thumbnails and its index entries from the
+    /// library. The images it links to are left alone.
+    async fn delete_prompt(&self, prompt_id: &Uuid) -> Result<(), SketchyError> {
+        let prompt = self.get_prompt(prompt_id).await?;
+        let id = prompt_id.to_string();
+
+        let mut ops = vec![
+            WriteOp::Delete {
+                key: format!("prompt:{}", prompt_id),
+            },
+            WriteOp::Delete {
+                key: format!("prompt:{}:results", prompt_id),
+            },
+            WriteOp::RemoveMember {
+                set: "prompts".to_string(),
+                member: id.clone(),
+            },
+        ];
+        for tag in &prompt.tags {
+            ops.push(WriteOp::RemoveMember {
+                set: prompt_tag_key(tag),
+                member: id.clone(),
+            });
+        }
+        for regenerated_id in &prompt.results {
+            ops.push(WriteOp::Delete {
+                key: prompt_result_key(prompt_id, regenerated_id),
+            });
+            ops.push(WriteOp::RemoveMember {
+                set: format!("regenerated:{}:prompts", regenerated_id),
+                member: id.clone(),
+            });
+        }
+        self.write_batch(&ops).await
+    }
+
+    /// Builds the lineage of an upload from the reverse indexes, skipping
+    /// anything that has already expired. Image bytes are not loaded.
+    async fn image_lineage(&self, image_id: &Uuid) -> Result<ImageLineage, SketchyError> {
+        let image: ImageUpload = get_json(self, &format!("image:{}", image_id))
+            .await?
+            .ok_or_else(|| {
+                SketchyError::NotFound(format!("Image with id '{}' not found.", image_id))
+            })?;
+
+        let mut analyses = Vec::new();
+        for analysis_id in self
+            .members(&format!("image:{}:analyses", image_id))
+            .await?
+        {
+            let Some(analysis) =
+                get_json::<_, ImageAnalysis>(self, &format!("analysis:{}", analysis_id)).await?
+            else {
+                continue;
+            };
+
+            let mut regenerations = Vec::new();
+            for regenerated_id in self
+                .members(&format!("analysis:{}:regenerations", analysis_id))
+                .await?
+            {
+                let Some(regenerated) = get_json::<_, RegeneratedImage>(
+                    self,
+                    &format!("regenerated:{}", regenerated_id),
+                )
+                .await?
+                else {
+                    continue;
+                };
+
+                let chain = self
+                    .improvement_chain(&ChainRoot::Regenerated(regenerated.id))
+                    .await?;
+                regenerations.push(RegenerationLineage {
+                    regenerated,
+                    improvements: improvement_tree(&chain, None),
+                });
+            }
+            regenerations.sort_by_key(|r| r.regenerated.created_at);
+
+            let mut refinements = Vec::new();
+            for refinement_id in self
+                .members(&format!("analysis:{}:refinements", analysis_id))
+                .await?
+            {
+                if let Some(refinement) =
+                    get_json::<_, Refinement>(self, &format!("refinement:{}", refinement_id))
+                        .await?
+                {
+                    refinements.push(refinement);
+                }
+            }
+            refinements.sort_by_key(|r| r.created_at);
+
+            analyses.push(AnalysisLineage {
+                analysis,
+                regenerations,
+                refinements,
+            });
+        }
+        analyses.sort_by_key(|a| a.analysis.created_at);
+
+        let chain = self.improvement_chain(&ChainRoot::Upload(image.id)).await?;
+
+        Ok(ImageLineage {
+            image,
+            analyses,
+            improvements: improvement_tree(&chain, None),
+        })
+    }
+
+    /// The improvements of a chain that have not expired yet.
+    async fn improvement_chain(
+        &self,
+        root: &ChainRoot,
+    ) -> Result<Vec<ImprovedImage>, SketchyError> {
+        let mut chain = Vec::new();
+        for improved_id in self
+            .members(&format!("{}:improvements", root_key(root)))
+            .await?
+        {
+            if let Some(improved) =
+                get_json::<_, ImprovedImage>(self, &format!("improved:{}", improved_id)).await?
+            {
+                chain.push(improved);
+            }
+        }
+        Ok(chain)
+    }
+
+    /// Lists the branches of a chain, one per step nothing was made from
+    /// yet. A head that has since been deleted or expired falls back to the
+    /// newest step.
+    async fn improvement_branches(
+        &self,
+        root: &ChainRoot,
+    ) -> Result<ImprovementBranches, SketchyError> {
+        if !self.exists(&root_key(root)).await? {
+            return Err(SketchyError::NotFound(match root {
+                ChainRoot::Upload(id) => format!("Image with id '{}' not found.", id),
+                ChainRoot::Regenerated(id) => {
+                    format!("Regenerated image with id '{}' not found.", id)
+                }
+            }));
+        }
+
+        let chain = self.improvement_chain(root).await?;
+        let stored_head = self
+            .get(&format!("{}:head", root_key(root)))
+            .await?
+            .and_then(|head| Uuid::parse_str(&String::from_utf8_lossy(&head)).ok());
+        let head = stored_head
+            .filter(|head| chain.iter().any(|improved| improved.id == *head))
+            .or_else(|| {
+                chain
+                    .iter()
+                    .max_by_key(|improved| improved.created_at)
+                    .map(|improved| improved.id)
+            });
+
+        let mut branches: Vec<ImprovementBranch> = chain
+            .iter()
+            .filter(|tip| {
+                !chain
+                    .iter()
+                    .any(|improved| improved.parent_improved_id == Some(tip.id))
+            })
+            .map(|tip| {
+                let mut steps = vec![tip.id];
+                let mut next = tip.parent_improved_id;
+                while let Some(parent) = next.and_then(|id| chain.iter().find(|i| i.id == id)) {
+                    steps.push(parent.id);
+                    next = parent.parent_improved_id;
+                }
+                steps.reverse();
+                ImprovementBranch {
+                    tip: tip.id,
+                    contains_head: head.is_some_and(|head| steps.contains(&head)),
+                    steps,
+                    updated_at: tip.created_at,
+                }
+            })
+            .collect();
+        branches.sort_by_key(|branch| branch.updated_at);
+
+        Ok(ImprovementBranches {
+            root: *root,
+            head,
+            branches,
+        })
+    }
+
+    /// Makes an improvement the head of its chain, the step to continue from.
+    async fn set_improvement_head(&self, improved: &ImprovedImage) -> Result<(), SketchyError> {
+        let Some(root) = improved.root() else {
+            return Err(SketchyError::Validation(format!(
+                "Improved image '{}' is not part of a chain",
+                improved.id
+            )));
+        };
+        let ttl = self.chain_ttl(&root).await?;
+        self.put(
+            &format!("{}:head", root_key(&root)),
+            improved.id.to_string().as_bytes(),
+            ttl,
+        )
+        .await
+    }
+
+    /// Walks parent pointers from an improvement back to the upload it
+    /// ultimately came from.
+    async fn improvement_ancestry(
+        &self,
+        improved_id: &Uuid,
+    ) -> Result<ImprovementAncestry, SketchyError> {
+        let mut steps = Vec::new();
+        let mut next = Some(*improved_id);
+        while let Some(id) = next {
+            let improved: ImprovedImage = get_json(self, &format!("improved:{}", id))
+                .await?
+                .ok_or_else(|| {
+                    SketchyError::NotFound(format!("Improved image with id '{}' not found.", id))
+                })?;
+            next = improved.parent_improved_id;
+            steps.push(improved);
+        }
+        steps.reverse();
+
+        let regenerated_image_id = steps[0].regenerated_image_id;
+        let regenerated: Option<RegeneratedImage> = match regenerated_image_id {
+            Some(regenerated_id) => {
+                get_json(self, &format!("regenerated:{}", regenerated_id)).await?
+            }
+            None => None,
+        };
+        let analysis_id = regenerated.map(|r| r.analysis_id);
+        let image_id = match analysis_id {
+            Some(analysis_id) => {
+                get_json::<_, ImageAnalysis>(self, &format!("analysis:{}", analysis_id))
+                    .await?
+                    .map(|a| a.image_id)
+            }
+            // Chains started from an upload name it directly
+            None => steps[0].image_id,
+        };
+
+        Ok(ImprovementAncestry {
+            image_id,
+            analysis_id,
+            regenerated_image_id,
+            steps,
+        })
+    }
+
+    /// Pushes back the expiry of every key belonging to a session. Keys of a
+    /// pinned session have no expiry and are left alone.
+    async fn refresh_session_ttl(&self, session_id: &Uuid) -> Result<(), SketchyError> {
+        let ops: Vec<WriteOp> = self
+            .session_keys(session_id)
+            .await?
+            .into_iter()
+            .filter_map(|(key, entity)| {
+                let ttl = self.retention().ttl(entity)?;
+                Some(WriteOp::Touch { key, ttl })
+            })
+            .collect();
+        self.write_batch(&ops).await
+    }
+
+    /// Keeps a session forever: removes the expiry of everything it holds so
+    /// far and makes later writes into it persistent too.
+    async fn pin_session(&self, session_id: &Uuid) -> Result<(), SketchyError> {
+        let marker = format!("session:{}:pinned", session_id);
+        let mut ops: Vec<WriteOp> = self
+            .session_keys(session_id)
+            .await?
+            .into_iter()
+            .map(|(key, _)| WriteOp::Expire { key, ttl: None })
+            .collect();
+        ops.push(WriteOp::Put {
+            key: marker,
+            value: b"1",
+            ttl: None,
+        });
+        self.write_batch(&ops).await
+    }
+
+    /// Reverses [`Storage::pin_session`], restarting the configured retention
+    /// period for everything in the session.
+    async fn unpin_session(&self, session_id: &Uuid) -> Result<(), SketchyError> {
+        self.delete(&format!("session:{}:pinned", session_id))
+            .await?;
+        let ops: Vec<WriteOp> = self
+            .session_keys(session_id)
+            .await?
+            .into_iter()
+            .map(|(key, entity)| WriteOp::Expire {
+                key,
+                ttl: self.retention().ttl(entity),
+            })
+            .collect();
+        self.write_batch(&ops).await
+    }
+
+    /// Refreshes the expiry of keys that were just read.
+    async fn touch_keys(&self, entity: Entity, keys: Vec<String>) -> Result<(), SketchyError> {
+        let Some(ttl) = self.retention().ttl(entity) else {
+            return Ok(());
+        };
+        let ops: Vec<WriteOp> = keys
+            .into_iter()
+            .map(|key| WriteOp::Touch { key, ttl })
+            .collect();
+        self.write_batch(&ops).await
+    }
+}
+
+/// Arranges the improvements of one chain into a tree by their parent
+/// pointers, oldest first at every level.
+fn improvement_tree(chain: &[ImprovedImage], parent: Option<Uuid>) -> Vec<ImprovementNode> {
+    let mut nodes: Vec<ImprovementNode> = chain
+        .iter()
+        .filter(|improved| improved.parent_improved_id == parent)
+        .map(|improved| ImprovementNode {
+            improved: improved.clone(),
+            children: improvement_tree(chain, Some(improved.id)),
+        })
+        .collect();
+    nodes.sort_by_key(|node| node.improved.created_at);
+    nodes
+}
+
+/// The key of the image a chain starts from. The chain is indexed under it
+/// with an `:improvements` suffix, and its head kept with `:head`.
+fn root_key(root: &ChainRoot) -> String {
+    match root {
+        ChainRoot::Upload(image_id) => format!("image:{}", image_id),
+        ChainRoot::Regenerated(regenerated_id) => format!("regenerated:{}", regenerated_id),
+    }
+}
+
+/// The index of library prompts carrying a tag.
+fn prompt_tag_key(tag: &str) -> String {
+    format!("prompts:tag:{}", tag)
+}
+
+/// The thumbnail a library prompt keeps of one of its results.
+fn prompt_result_key(prompt_id: impl Display, regenerated_id: impl Display) -> String {
+    format!("prompt:{}:result:{}", prompt_id, regenerated_id)
+}
+
+/// The keys stored directly under one improvement, not its children.
+fn improved_entity_keys(improved_id: &str) -> Vec<(String, Entity)> {
+    let mut keys = vec![
+        (format!("improved:{}", improved_id), Entity::Improved),
+        (format!("improved:{}:data", improved_id), Entity::Improved),
+    ];
+    keys.extend(variant_keys(Entity::Improved, improved_id));
+    keys.push((
+        format!("improved:{}:children", improved_id),
+        Entity::Improved,
+    ));
+    keys
+}
+
+/// Ids of the entities of one kind whose main key is among `keys`.
+fn deleted_ids(keys: &[(String, Entity)], entity: Entity) -> Vec<Uuid> {
+    keys.iter()
+        .filter(|(_, e)| *e == entity)
+        .filter_map(|(key, _)| key.strip_prefix(entity.key_prefix())?.strip_prefix(':'))
+        .filter_map(|id| Uuid::parse_str(id).ok())
+        .collect()
+}
+
+fn variant_key(entity: Entity, id: impl Display, variant: ImageVariant) -> String {
+    format!(
+        "{}:{}:variant:{}",
+        entity.key_prefix(),
+        id,
+        variant.as_str()
+    )
+}
+
+/// Keys of every rendition an image entity may have, generated or not.
+fn variant_keys(entity: Entity, id: &str) -> Vec<(String, Entity)> {
+    ImageVariant::ALL
+        .iter()
+        .map(|variant| (variant_key(entity, id, *variant), entity))
+        .collect()
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<Vec<u8>, SketchyError> {
+    serde_json::to_vec(value).map_err(|e| SketchyError::Serialization(e.to_string()))
+}
+
+async fn get_json<S, T>(storage: &S, key: &str) -> Result<Option<T>, SketchyError>
+where
+    S: Storage + ?Sized,
+    T: DeserializeOwned,
+{
+    match storage.get(key).await? {
+        Some(v) => serde_json::from_slice(&v)
+            .map(Some)
+            .map_err(|e| SketchyError::Serialization(e.to_string())),
+        None => Ok(None),
+    }
+}
+
+/// Escapes a key or member segment so it is safe to use as a path component
+/// or object name: anything but ASCII alphanumerics, `-` and `_` is
+/// percent-encoded, which also rules out `.`/`..` path traversal.
+pub(crate) fn encode_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn decode_segment(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            if let Some(byte) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Builds the storage backend selected by `SKETCHY_STORAGE` (`redis`,
+/// `filesystem` or `s3`; defaults to `redis`).
+pub async fn from_env() -> Result<Arc<dyn Storage>, SketchyError> {
+    let backend = std::env::var("SKETCHY_STORAGE").unwrap_or_else(|_| "redis".to_string());
+
+    match backend.as_str() {
+        "redis" => {
+            let redis_url =
+                std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+            let redis_service = RedisService::new(&redis_url, Retention::from_env()?).await?;
+
+            // One-off migration of entries written before image bytes were stored separately
+            if std::env::var("SKETCHY_MIGRATE_LEGACY_BLOBS").is_ok() {
+                match redis_service.migrate_legacy_blobs().await {
+                    Ok(report) => info!(
+                        "Migrated {} of {} image keys, memory {} -> {} bytes",
+                        report.keys_migrated,
+                        report.keys_scanned,
+                        report.memory_before_bytes,
+                        report.memory_after_bytes
+                    ),
+                    Err(e) => log::error!("Legacy blob migration failed: {}", e),
+                }
+            }
+
+            info!("Using Redis storage at {}", redis_url);
+            Ok(Arc::new(redis_service))
+        }
+        "filesystem" => {
+            let root =
+                std::env::var("SKETCHY_STORAGE_DIR").unwrap_or_else(|_| "./data".to_string());
+            let storage = FilesystemStorage::new(&root).await?;
+            info!("Using filesystem storage at {}", root);
+            Ok(Arc::new(storage))
+        }
+        "s3" => {
+            let bucket = std::env::var("S3_BUCKET")
+                .map_err(|_| SketchyError::Storage("S3_BUCKET must be set".to_string()))?;
+            let region = std::env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string());
+            let endpoint = std::env::var("S3_ENDPOINT").ok();
+            let storage = S3Storage::new(&bucket, &region, endpoint.as_deref())?;
+            info!("Using S3 storage in bucket {}", bucket);
+            Ok(Arc::new(storage))
+        }
+        other => Err(SketchyError::Storage(format!(
+            "Unknown storage backend '{}'",
+            other
+        ))),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::mcp::ImageGenerationProvider;
+    use chrono::{Duration, Utc};
+    use std::path::{Path, PathBuf};
+
+    /// A directory under the system temp dir, removed when dropped.
+    pub(crate) struct TempDir(pub(crate) PathBuf);
+
+    impl TempDir {
+        pub(crate) fn new() -> Self {
+            Self(std::env::temp_dir().join(format!("sketchy-test-{}", Uuid::new_v4())))
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    pub(crate) fn upload() -> ImageUpload {
+        ImageUpload {
+            id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            filename: "barn.png".to_string(),
+            content_type: "image/png".to_string(),
+            size: 3,
+            data: vec![1, 2, 3],
+            uploaded_at: Utc::now(),
+        }
+    }
+
+    pub(crate) fn improvement(
+        image_id: Uuid,
+        parent: Option<&ImprovedImage>,
+        minutes: i64,
+    ) -> ImprovedImage {
+        ImprovedImage {
+            id: Uuid::new_v4(),
+            regenerated_image_id: None,
+            image_id: Some(image_id),
+            parent_improved_id: parent.map(|parent| parent.id),
+            step: parent.map_or(1, |parent| parent.step + 1),
+            data: vec![4, 5, 6],
+            prompt_used: format!("step at {} minutes", minutes),
+            provider: ImageGenerationProvider::StabilityAI,
+            params: ImproveParams::default(),
+            edit: None,
+            created_at: Utc::now() + Duration::minutes(minutes),
+        }
+    }
+
+    pub(crate) fn analysis(image_id: Uuid) -> ImageAnalysis {
+        serde_json::from_value(serde_json::json!({
+            "id": Uuid::new_v4(),
+            "image_id": image_id,
+            "llm_provider": "openai",
+            "raw_analysis": {
+                "regions": [],
+                "global_attributes": {
+                    "style": "watercolor",
+                    "mood": "calm",
+                    "lighting": "golden hour",
+                    "perspective": "eye level",
+                    "dominant_colors": [],
+                },
+                "composition": {
+                    "layout": "centered",
+                    "focal_points": [],
+                    "balance": "symmetric",
+                    "depth_layers": [],
+                },
+            },
+            "prompt_description": "A watercolor barn",
+            "metadata": {
+                "processing_time_ms": 1,
+                "model_used": "gpt-4o",
+                "confidence_score": 0.9,
+            },
+            "created_at": Utc::now(),
+        }))
+        .unwrap()
+    }
+
+    pub(crate) fn regenerated(analysis_id: Uuid) -> RegeneratedImage {
+        RegeneratedImage {
+            id: Uuid::new_v4(),
+            analysis_id,
+            format: ImageFormat::Raster {
+                format: "png".to_string(),
+                dimensions: (1, 1),
+            },
+            data: vec![7, 8, 9],
+            prompt_used: "A watercolor barn".to_string(),
+            generation_params: GenerationParams {
+                model: "dall-e-3".to_string(),
+                steps: None,
+                cfg_scale: None,
+                seed: None,
+            },
+            similarity: None,
+            round_trip: None,
+            refinement: None,
+            template: None,
+            created_at: Utc::now(),
+        }
+    }
+
+    /// Each branch as its tip and whether it holds the head.
+    fn summary(branches: &ImprovementBranches) -> Vec<(Uuid, Vec<Uuid>, bool)> {
+        branches
+            .branches
+            .iter()
+            .map(|branch| (branch.tip, branch.steps.clone(), branch.contains_head))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn enumerates_branches_and_follows_the_head() {
+        let dir = TempDir::new();
+        let storage = FilesystemStorage::new(&dir.0).await.unwrap();
+        let image = upload();
+        storage.store_image(&image).await.unwrap();
+        let root = ChainRoot::Upload(image.id);
+
+        let empty = storage.improvement_branches(&root).await.unwrap();
+        assert_eq!(empty.head, None);
+        assert!(empty.branches.is_empty());
+
+        // first -> second, then a second branch from first made later
+        let first = improvement(image.id, None, 1);
+        let second = improvement(image.id, Some(&first), 2);
+        let sibling = improvement(image.id, Some(&first), 3);
+        for improved in [&first, &second, &sibling] {
+            storage.store_improved(improved).await.unwrap();
+        }
+
+        let branches = storage.improvement_branches(&root).await.unwrap();
+        assert_eq!(branches.head, Some(sibling.id));
+        assert_eq!(
+            summary(&branches),
+            [
+                (second.id, vec![first.id, second.id], false),
+                (sibling.id, vec![first.id, sibling.id], true),
+            ]
+        );
+
+        storage.set_improvement_head(&second).await.unwrap();
+        let branches = storage.improvement_branches(&root).await.unwrap();
+        assert_eq!(branches.head, Some(second.id));
+        assert_eq!(
+            summary(&branches)
+                .iter()
+                .map(|(_, _, contains_head)| *contains_head)
+                .collect::<Vec<_>>(),
+            [true, false]
+        );
+
+        // Reverting to the shared step puts the head on both branches
+        storage.set_improvement_head(&first).await.unwrap();
+        let branches = storage.improvement_branches(&root).await.unwrap();
+        assert_eq!(branches.head, Some(first.id));
+        assert!(branches.branches.iter().all(|branch| branch.contains_head));
+    }
+
+    #[tokio::test]
+    async fn unknown_head_falls_back_to_the_latest_step() {
+        let dir = TempDir::new();
+        let storage = FilesystemStorage::new(&dir.0).await.unwrap();
+        let image = upload();
+        storage.store_image(&image).await.unwrap();
+        let root = ChainRoot::Upload(image.id);
+
+        let latest = improvement(image.id, None, 5);
+        let earlier = improvement(image.id, Some(&latest), 1);
+        storage.store_improved(&latest).await.unwrap();
+        storage.store_improved(&earlier).await.unwrap();
+        storage
+            .put(
+                &format!("image:{}:head", image.id),
+                Uuid::new_v4().to_string().as_bytes(),
+                None,
+            )
+            .await
+            .unwrap();
+
+        let branches = storage.improvement_branches(&root).await.unwrap();
+        assert_eq!(branches.head, Some(latest.id));
+    }
+
+    #[tokio::test]
+    async fn branches_of_a_missing_root_are_not_found() {
+        let dir = TempDir::new();
+        let storage = FilesystemStorage::new(&dir.0).await.unwrap();
+        let result = storage
+            .improvement_branches(&ChainRoot::Upload(Uuid::new_v4()))
+            .await;
+        assert!(matches!(result, Err(SketchyError::NotFound(_))));
+    }
+
+    /// Every file under `dir`.
+    fn files(dir: &Path) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        let mut pending = vec![dir.to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    pending.push(path);
+                } else {
+                    files.push(path);
+                }
+            }
+        }
+        files
+    }
+
+    #[tokio::test]
+    async fn deleting_a_session_removes_everything_in_it() {
+        let dir = TempDir::new();
+        let storage = FilesystemStorage::new(&dir.0).await.unwrap();
+
+        let image = upload();
+        storage.store_image(&image).await.unwrap();
+        let analysis = analysis(image.id);
+        storage.store_analysis(&analysis).await.unwrap();
+        let regenerated = regenerated(analysis.id);
+        storage.store_regenerated(&regenerated).await.unwrap();
+        storage
+            .store_variant(
+                Entity::Regenerated,
+                &regenerated.id,
+                ImageVariant::Thumb,
+                b"thumb",
+            )
+            .await
+            .unwrap();
+        let mut first = improvement(image.id, None, 1);
+        first.image_id = None;
+        first.regenerated_image_id = Some(regenerated.id);
+        let mut second = improvement(image.id, Some(&first), 2);
+        second.image_id = None;
+        second.regenerated_image_id = Some(regenerated.id);
+        let own = improvement(image.id, None, 3);
+        for improved in [&first, &second, &own] {
+            storage.store_improved(improved).await.unwrap();
+        }
+        storage.pin_session(&image.session_id).await.unwrap();
+
+        let record = storage
+            .delete_session(&image.session_id, Some("Cleanup".to_string()))
+            .await
+            .unwrap();
+
+        assert_eq!(record.target_id, image.session_id);
+        assert_eq!(record.reason.as_deref(), Some("Cleanup"));
+        assert_eq!(record.images, [image.id]);
+        assert_eq!(record.analyses, [analysis.id]);
+        assert_eq!(record.regenerated, [regenerated.id]);
+        assert_eq!(
+            sorted(record.improved.clone()),
+            sorted(vec![first.id, second.id, own.id])
+        );
+        let stored = storage.get_deletion(&record.id).await.unwrap();
+        assert_eq!(stored.keys_deleted, record.keys_deleted);
+        assert_eq!(stored.improved, record.improved);
+
+        // Only the audit record and its index are left
+        assert_eq!(files(&dir.0).len(), 2);
+        assert_eq!(
+            storage.members("deletions").await.unwrap(),
+            [record.id.to_string()]
+        );
+        assert!(!storage.session_exists(&image.session_id).await.unwrap());
+        assert!(matches!(
+            storage.get_improved(&second.id).await,
+            Err(SketchyError::NotFound(_))
+        ));
+        assert!(matches!(
+            storage.delete_session(&image.session_id, None).await,
+            Err(SketchyError::NotFound(_))
+        ));
+    }
+
+    fn saved_prompt(tags: &[&str]) -> SavedPrompt {
+        SavedPrompt {
+            id: Uuid::new_v4(),
+            prompt: "A watercolor barn".to_string(),
+            is_template: false,
+            title: None,
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            analysis_id: None,
+            image_id: None,
+            results: Vec::new(),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        }
+    }
+
+    /// Ids of the prompts carrying every one of `tags`, sorted.
+    async fn tagged(storage: &dyn Storage, tags: &[&str]) -> Vec<Uuid> {
+        let tags: Vec<String> = tags.iter().map(|tag| tag.to_string()).collect();
+        let mut ids: Vec<Uuid> = storage
+            .prompts_tagged(&tags)
+            .await
+            .unwrap()
+            .iter()
+            .map(|prompt| prompt.id)
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    fn sorted(mut ids: Vec<Uuid>) -> Vec<Uuid> {
+        ids.sort();
+        ids
+    }
+
+    /// An upload with an analysis and a regenerated image of it.
+    async fn regenerated_upload(storage: &dyn Storage) -> RegeneratedImage {
+        let image = upload();
+        storage.store_image(&image).await.unwrap();
+        let analysis = analysis(image.id);
+        storage.store_analysis(&analysis).await.unwrap();
+        let regenerated = regenerated(analysis.id);
+        storage.store_regenerated(&regenerated).await.unwrap();
+        regenerated
+    }
+
+    #[tokio::test]
+    async fn prompts_are_found_by_every_tag() {
+        let dir = TempDir::new();
+        let storage = FilesystemStorage::new(&dir.0).await.unwrap();
+        let mut both = saved_prompt(&["barn", "watercolor"]);
+        let barn = saved_prompt(&["barn"]);
+        let untagged = saved_prompt(&[]);
+        for prompt in [&both, &barn, &untagged] {
+            storage.store_prompt(prompt).await.unwrap();
+        }
+
+        assert_eq!(
+            tagged(&storage, &[]).await,
+            sorted(vec![both.id, barn.id, untagged.id])
+        );
+        assert_eq!(
+            tagged(&storage, &["barn"]).await,
+            sorted(vec![both.id, barn.id])
+        );
+        assert_eq!(tagged(&storage, &["barn", "watercolor"]).await, [both.id]);
+        assert_eq!(tagged(&storage, &["watercolor", "barn"]).await, [both.id]);
+        assert!(tagged(&storage, &["barn", "ink"]).await.is_empty());
+
+        // Changing the tags moves the prompt between the indexes
+        both.tags = vec!["ink".to_string()];
+        storage.store_prompt(&both).await.unwrap();
+        assert_eq!(tagged(&storage, &["barn"]).await, [barn.id]);
+        assert!(tagged(&storage, &["watercolor"]).await.is_empty());
+        assert_eq!(tagged(&storage, &["ink"]).await, [both.id]);
+        assert_eq!(storage.get_prompt(&both.id).await.unwrap().tags, ["ink"]);
+    }
+
+    #[tokio::test]
+    async fn deleting_a_prompt_removes_its_indexes_and_thumbnails() {
+        let dir = TempDir::new();
+        let storage = FilesystemStorage::new(&dir.0).await.unwrap();
+        let regenerated = regenerated_upload(&storage).await;
+        let prompt = saved_prompt(&["barn"]);
+        storage.store_prompt(&prompt).await.unwrap();
+        storage
+            .add_prompt_result(&prompt.id, &regenerated.id, b"thumbnail")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            storage.get_prompt(&prompt.id).await.unwrap().results,
+            [regenerated.id]
+        );
+        assert_eq!(
+            storage
+                .get_prompt_thumbnail(&prompt.id, &regenerated.id)
+                .await
+                .unwrap(),
+            b"thumbnail"
+        );
+        assert_eq!(
+            storage
+                .members(&format!("regenerated:{}:prompts", regenerated.id))
+                .await
+                .unwrap(),
+            [prompt.id.to_string()]
+        );
+
+        storage.delete_prompt(&prompt.id).await.unwrap();
+        assert!(matches!(
+            storage.get_prompt(&prompt.id).await,
+            Err(SketchyError::NotFound(_))
+        ));
+        assert!(tagged(&storage, &[]).await.is_empty());
+        assert!(tagged(&storage, &["barn"]).await.is_empty());
+        assert!(
+            !storage
+                .exists(&format!("prompt:{}:result:{}", prompt.id, regenerated.id))
+                .await
+                .unwrap()
+        );
+        assert!(
+            storage
+                .members(&format!("regenerated:{}:prompts", regenerated.id))
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        // The image it was made from stays
+        storage.get_regenerated(&regenerated.id).await.unwrap();
+        assert!(matches!(
+            storage.delete_prompt(&prompt.id).await,
+            Err(SketchyError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn deleting_a_result_deletes_its_thumbnails() {
+        let dir = TempDir::new();
+        let storage = FilesystemStorage::new(&dir.0).await.unwrap();
+        let regenerated = regenerated_upload(&storage).await;
+        let kept = regenerated_upload(&storage).await;
+        let prompts = [saved_prompt(&[]), saved_prompt(&[])];
+        for prompt in &prompts {
+            storage.store_prompt(prompt).await.unwrap();
+            for result in [&regenerated, &kept] {
+                storage
+                    .add_prompt_result(&prompt.id, &result.id, b"thumbnail")
+                    .await
+                    .unwrap();
+            }
+        }
+
+        storage
+            .delete_regenerated(&regenerated.id, None)
+            .await
+            .unwrap();
+        for prompt in &prompts {
+            assert_eq!(
+                storage.get_prompt(&prompt.id).await.unwrap().results,
+                [kept.id]
+            );
+            assert!(matches!(
+                storage
+                    .get_prompt_thumbnail(&prompt.id, &regenerated.id)
+                    .await,
+                Err(SketchyError::NotFound(_))
+            ));
+            storage
+                .get_prompt_thumbnail(&prompt.id, &kept.id)
+                .await
+                .unwrap();
+        }
+    }
+}
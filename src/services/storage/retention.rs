@@ -0,0 +1,126 @@
+// src/services/storage/retention.rs
+use crate::errors::SketchyError;
+use std::env::VarError;
+
+/// How long session data is kept before it expires, unless configured otherwise.
+pub const SESSION_TTL_SECONDS: u64 = 86400;
+
+/// The kinds of stored entity that carry their own retention period.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Entity {
+    Image,
+    Analysis,
+    Regenerated,
+    Improved,
+}
+
+impl Entity {
+    /// The first segment of the keys this entity is stored under.
+    pub fn key_prefix(&self) -> &'static str {
+        match self {
+            Entity::Image => "image",
+            Entity::Analysis => "analysis",
+            Entity::Regenerated => "regenerated",
+            Entity::Improved => "improved",
+        }
+    }
+}
+
+/// Per-entity expiry in seconds, honoured by backends with native expiry.
+/// `None` keeps the entity until it is deleted. Index keys expire together
+/// with the entities they list.
+#[derive(Debug, Clone)]
+pub struct Retention {
+    pub images: Option<u64>,
+    pub analyses: Option<u64>,
+    pub regenerated: Option<u64>,
+    pub improved: Option<u64>,
+}
+
+pub static DEFAULT_RETENTION: Retention = Retention {
+    images: Some(SESSION_TTL_SECONDS),
+    analyses: Some(SESSION_TTL_SECONDS),
+    regenerated: Some(SESSION_TTL_SECONDS),
+    improved: Some(SESSION_TTL_SECONDS),
+};
+
+impl Default for Retention {
+    fn default() -> Self {
+        DEFAULT_RETENTION.clone()
+    }
+}
+
+impl Retention {
+    /// Reads `SKETCHY_TTL_IMAGES`, `SKETCHY_TTL_ANALYSES`,
+    /// `SKETCHY_TTL_REGENERATED` and `SKETCHY_TTL_IMPROVED` (seconds, `0` to
+    /// keep forever), falling back to 24 hours for any that are unset. A
+    /// value that isn't a number of seconds is an error rather than ignored.
+    pub fn from_env() -> Result<Self, SketchyError> {
+        fn ttl_var(name: &str) -> Result<Option<u64>, SketchyError> {
+            match std::env::var(name) {
+                Err(VarError::NotPresent) => Ok(Some(SESSION_TTL_SECONDS)),
+                Err(VarError::NotUnicode(value)) => {
+                    Err(invalid_ttl(name, &value.to_string_lossy()))
+                }
+                Ok(value) => parse_ttl(name, &value),
+            }
+        }
+
+        Ok(Self {
+            images: ttl_var("SKETCHY_TTL_IMAGES")?,
+            analyses: ttl_var("SKETCHY_TTL_ANALYSES")?,
+            regenerated: ttl_var("SKETCHY_TTL_REGENERATED")?,
+            improved: ttl_var("SKETCHY_TTL_IMPROVED")?,
+        })
+    }
+
+    pub fn ttl(&self, entity: Entity) -> Option<u64> {
+        match entity {
+            Entity::Image => self.images,
+            Entity::Analysis => self.analyses,
+            Entity::Regenerated => self.regenerated,
+            Entity::Improved => self.improved,
+        }
+    }
+}
+
+/// Parses the retention set in `name`, where `0` means forever.
+fn parse_ttl(name: &str, value: &str) -> Result<Option<u64>, SketchyError> {
+    match value.trim().parse::<u64>() {
+        Ok(0) => Ok(None),
+        Ok(ttl) => Ok(Some(ttl)),
+        Err(_) => Err(invalid_ttl(name, value)),
+    }
+}
+
+fn invalid_ttl(name: &str, value: &str) -> SketchyError {
+    SketchyError::Storage(format!(
+        "{} must be a whole number of seconds (0 to keep forever), got '{}'",
+        name, value
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_seconds_and_forever() {
+        assert_eq!(parse_ttl("SKETCHY_TTL_IMAGES", "3600").unwrap(), Some(3600));
+        assert_eq!(parse_ttl("SKETCHY_TTL_IMAGES", " 60 ").unwrap(), Some(60));
+        assert_eq!(parse_ttl("SKETCHY_TTL_IMAGES", "0").unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_values_that_are_not_seconds() {
+        for value in ["7d", "", "-1", "1.5", "24h"] {
+            match parse_ttl("SKETCHY_TTL_ANALYSES", value) {
+                Err(SketchyError::Storage(message)) => {
+                    assert!(message.contains("SKETCHY_TTL_ANALYSES"), "{}", message);
+                    assert!(message.contains(&format!("'{}'", value)), "{}", message);
+                }
+                other => panic!("{:?} was accepted as {:?}", value, other),
+            }
+        }
+    }
+}
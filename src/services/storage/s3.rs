@@ -0,0 +1,322 @@
+// src/services/storage/s3.rs
+use super::{Storage, decode_segment, encode_segment};
+use crate::errors::SketchyError;
+use ::s3::{Bucket, Region, creds::Credentials};
+use async_trait::async_trait;
+
+/// Stores everything in an S3-compatible bucket. Values live under `blobs/`
+/// and sets are emulated with one empty object per member under `sets/`.
+/// Nothing expires; use bucket lifecycle rules for retention.
+pub struct S3Storage {
+    bucket: Box<Bucket>,
+}
+
+impl S3Storage {
+    /// Connects to `bucket`. When `endpoint` is given (e.g. a local MinIO) it
+    /// is used with path-style addressing instead of the AWS endpoint for `region`.
+    /// Credentials come from `AWS_ACCESS_KEY_ID`/`AWS_SECRET_ACCESS_KEY` or the
+    /// default AWS profile.
+    pub fn new(bucket: &str, region: &str, endpoint: Option<&str>) -> Result<Self, SketchyError> {
+        let credentials =
+            Credentials::default().map_err(|e| SketchyError::Storage(e.to_string()))?;
+
+        let bucket = match endpoint {
+            Some(endpoint) => {
+                let region = Region::Custom {
+                    region: region.to_string(),
+                    endpoint: endpoint.to_string(),
+                };
+                Bucket::new(bucket, region, credentials)
+                    .map_err(|e| SketchyError::Storage(e.to_string()))?
+                    .with_path_style()
+            }
+            None => {
+                let region = region
+                    .parse()
+                    .map_err(|e: std::str::Utf8Error| SketchyError::Storage(e.to_string()))?;
+                Bucket::new(bucket, region, credentials)
+                    .map_err(|e| SketchyError::Storage(e.to_string()))?
+            }
+        };
+
+        Ok(Self { bucket })
+    }
+
+    fn blob_path(key: &str) -> String {
+        format!("blobs/{}", encode_segment(key))
+    }
+
+    fn set_prefix(set: &str) -> String {
+        format!("sets/{}/", encode_segment(set))
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, key: &str, value: &[u8], _ttl: Option<u64>) -> Result<(), SketchyError> {
+        let response = self
+            .bucket
+            .put_object(Self::blob_path(key), value)
+            .await
+            .map_err(|e| SketchyError::Storage(e.to_string()))?;
+        check_status(response.status_code(), key)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, SketchyError> {
+        let response = self
+            .bucket
+            .get_object(Self::blob_path(key))
+            .await
+            .map_err(|e| SketchyError::Storage(e.to_string()))?;
+        if response.status_code() == 404 {
+            return Ok(None);
+        }
+        check_status(response.status_code(), key)?;
+        Ok(Some(response.to_vec()))
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, SketchyError> {
+        let (_, status) = self
+            .bucket
+            .head_object(Self::blob_path(key))
+            .await
+            .map_err(|e| SketchyError::Storage(e.to_string()))?;
+        if status != 404 {
+            check_status(status, key)?;
+            return Ok(true);
+        }
+        Ok(!self.members(key).await?.is_empty())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), SketchyError> {
+        let mut paths = vec![Self::blob_path(key)];
+        let prefix = Self::set_prefix(key);
+        paths.extend(
+            self.members(key)
+                .await?
+                .iter()
+                .map(|member| format!("{}{}", prefix, encode_segment(member))),
+        );
+
+        for path in paths {
+            let response = self
+                .bucket
+                .delete_object(path)
+                .await
+                .map_err(|e| SketchyError::Storage(e.to_string()))?;
+            if response.status_code() != 404 {
+                check_status(response.status_code(), key)?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn add_member(
+        &self,
+        set: &str,
+        member: &str,
+        _ttl: Option<u64>,
+    ) -> Result<(), SketchyError> {
+        let path = format!("{}{}", Self::set_prefix(set), encode_segment(member));
+        let response = self
+            .bucket
+            .put_object(path, &[])
+            .await
+            .map_err(|e| SketchyError::Storage(e.to_string()))?;
+        check_status(response.status_code(), set)
+    }
+
+    async fn remove_member(&self, set: &str, member: &str) -> Result<(), SketchyError> {
+        let path = format!("{}{}", Self::set_prefix(set), encode_segment(member));
+        let response = self
+            .bucket
+            .delete_object(path)
+            .await
+            .map_err(|e| SketchyError::Storage(e.to_string()))?;
+        if response.status_code() != 404 {
+            check_status(response.status_code(), set)?;
+        }
+        Ok(())
+    }
+
+    async fn members(&self, set: &str) -> Result<Vec<String>, SketchyError> {
+        let prefix = Self::set_prefix(set);
+        let pages = self
+            .bucket
+            .list(prefix.clone(), None)
+            .await
+            .map_err(|e| SketchyError::Storage(e.to_string()))?;
+
+        Ok(pages
+            .iter()
+            .flat_map(|page| page.contents.iter())
+            .filter_map(|object| object.key.strip_prefix(&prefix))
+            .map(decode_segment)
+            .collect())
+    }
+
+    async fn expire(&self, _key: &str, _ttl: Option<u64>) -> Result<(), SketchyError> {
+        Ok(())
+    }
+
+    async fn touch(&self, _key: &str, _ttl: u64) -> Result<(), SketchyError> {
+        Ok(())
+    }
+}
+
+fn check_status(status: u16, key: &str) -> Result<(), SketchyError> {
+    if (200..300).contains(&status) {
+        Ok(())
+    } else {
+        Err(SketchyError::Storage(format!(
+            "S3 request for '{}' failed with status {}",
+            key, status
+        )))
+    }
+}
+
+/// These run against a real bucket, so they are ignored by default. Point
+/// them at a local MinIO (see the README) and run
+/// `S3_BUCKET=sketchy S3_ENDPOINT=http://localhost:9000 AWS_ACCESS_KEY_ID=minio
+/// AWS_SECRET_ACCESS_KEY=minio123 cargo test s3 -- --ignored`.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::storage::WriteOp;
+    use uuid::Uuid;
+
+    /// The bucket from the environment, and a prefix unique to this test so
+    /// runs never see each other's keys.
+    fn storage() -> (S3Storage, String) {
+        let bucket = std::env::var("S3_BUCKET").expect("S3_BUCKET must name a test bucket");
+        let region = std::env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string());
+        let endpoint = std::env::var("S3_ENDPOINT").ok();
+        let storage = S3Storage::new(&bucket, &region, endpoint.as_deref()).unwrap();
+        (storage, format!("test-{}:", Uuid::new_v4()))
+    }
+
+    #[tokio::test]
+    #[ignore = "needs an S3 bucket"]
+    async fn put_get_and_delete() {
+        let (storage, prefix) = storage();
+        let key = format!("{}image:1", prefix);
+        let data_key = format!("{}image:1:data", prefix);
+        assert_eq!(storage.get(&key).await.unwrap(), None);
+        assert!(!storage.exists(&key).await.unwrap());
+
+        storage.put(&key, b"first", None).await.unwrap();
+        storage.put(&key, b"second", Some(60)).await.unwrap();
+        assert_eq!(storage.get(&key).await.unwrap().unwrap(), b"second");
+        assert!(storage.exists(&key).await.unwrap());
+
+        // Keys that extend another are separate values
+        storage.put(&data_key, b"bytes", None).await.unwrap();
+        storage.delete(&key).await.unwrap();
+        assert_eq!(storage.get(&key).await.unwrap(), None);
+        assert_eq!(storage.get(&data_key).await.unwrap().unwrap(), b"bytes");
+
+        // Deleting what isn't there is not an error
+        storage.delete(&key).await.unwrap();
+        storage.delete(&data_key).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs an S3 bucket"]
+    async fn set_membership() {
+        let (storage, prefix) = storage();
+        let set = format!("{}session:1:images", prefix);
+        let nested = format!("{}session:1:images:nested", prefix);
+        assert!(storage.members(&set).await.unwrap().is_empty());
+
+        for member in ["a", "b", "a"] {
+            storage.add_member(&set, member, None).await.unwrap();
+        }
+        storage.add_member(&nested, "c", None).await.unwrap();
+        let mut members = storage.members(&set).await.unwrap();
+        members.sort();
+        assert_eq!(members, ["a", "b"]);
+        assert!(storage.exists(&set).await.unwrap());
+
+        storage.remove_member(&set, "a").await.unwrap();
+        storage.remove_member(&set, "missing").await.unwrap();
+        assert_eq!(storage.members(&set).await.unwrap(), ["b"]);
+
+        // Deleting a set leaves the sets whose keys extend it
+        storage.delete(&set).await.unwrap();
+        assert!(storage.members(&set).await.unwrap().is_empty());
+        assert!(!storage.exists(&set).await.unwrap());
+        assert_eq!(storage.members(&nested).await.unwrap(), ["c"]);
+        storage.delete(&nested).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs an S3 bucket"]
+    async fn write_batch_applies_every_op() {
+        let (storage, prefix) = storage();
+        let (old, new, index) = (
+            format!("{}old", prefix),
+            format!("{}new", prefix),
+            format!("{}index", prefix),
+        );
+        storage.put(&old, b"gone", None).await.unwrap();
+        storage
+            .write_batch(&[
+                WriteOp::Put {
+                    key: new.clone(),
+                    value: b"value",
+                    ttl: None,
+                },
+                WriteOp::AddMember {
+                    set: index.clone(),
+                    member: new.clone(),
+                    ttl: None,
+                },
+                WriteOp::Delete { key: old.clone() },
+            ])
+            .await
+            .unwrap();
+        assert_eq!(storage.get(&new).await.unwrap().unwrap(), b"value");
+        assert_eq!(storage.members(&index).await.unwrap(), [new.as_str()]);
+        assert_eq!(storage.get(&old).await.unwrap(), None);
+
+        storage.delete(&new).await.unwrap();
+        storage.delete(&index).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs an S3 bucket"]
+    async fn traversing_keys_round_trip() {
+        let (storage, prefix) = storage();
+        let key = format!("{}image:../../escaped", prefix);
+        let set = format!("{}../sets", prefix);
+        storage.put(&key, b"value", None).await.unwrap();
+        storage
+            .add_member(&set, "../../member", None)
+            .await
+            .unwrap();
+
+        assert_eq!(storage.get(&key).await.unwrap().unwrap(), b"value");
+        assert_eq!(storage.members(&set).await.unwrap(), ["../../member"]);
+
+        storage.delete(&key).await.unwrap();
+        storage.delete(&set).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs an S3 bucket"]
+    async fn members_round_trip_through_encoding() {
+        let (storage, prefix) = storage();
+        let set = format!("{}set", prefix);
+        let members = ["with space", "colon:in", "100%", "%41", "ünïcode"];
+        for member in members {
+            storage.add_member(&set, member, None).await.unwrap();
+        }
+        let mut stored = storage.members(&set).await.unwrap();
+        stored.sort();
+        let mut expected = members.to_vec();
+        expected.sort();
+        assert_eq!(stored, expected);
+
+        storage.delete(&set).await.unwrap();
+    }
+}